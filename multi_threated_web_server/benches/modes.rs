@@ -0,0 +1,128 @@
+//! Load test of the blocking server against the epoll event loop, each with
+//! the same small pool, with and without idle connections hogging it.
+//!
+//! Run with `cargo bench --bench modes` from the crate root, where `200.html` lives.
+
+use multi_threaded_web_server::{
+    config::Config,
+    server::{self, Services},
+    ThreadPool,
+};
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const WORKERS: usize = 4;
+const CLIENTS: usize = 16;
+const REQUESTS: usize = 100;
+/// Connections that never send a request, several per worker
+const IDLE: usize = 16;
+const HEADER_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Blocking,
+    #[cfg(target_os = "linux")]
+    Epoll,
+}
+
+fn start(mode: Mode) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Arc::new(Config {
+        header_timeout: Some(HEADER_TIMEOUT),
+        ..Config::default()
+    });
+    let pool = ThreadPool::build(WORKERS).unwrap();
+    let services = Services::default();
+    let access_log = Arc::new(None);
+    thread::spawn(move || match mode {
+        Mode::Blocking => {
+            server::serve(listener.incoming(), &pool, &config, &services, &access_log)
+        }
+        #[cfg(target_os = "linux")]
+        Mode::Epoll => multi_threaded_web_server::event_loop::serve(
+            &listener,
+            &pool,
+            &config,
+            &services,
+            &access_log,
+        )
+        .unwrap(),
+    });
+    addr
+}
+
+fn get(addr: SocketAddr) {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+}
+
+/// Has `CLIENTS` threads make `REQUESTS` requests each, one after another,
+/// returning the time taken and the slowest single request.
+fn load(addr: SocketAddr, idle: usize) -> (Duration, Duration) {
+    let hogs: Vec<TcpStream> = (0..idle)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+    // Let the server pick the idle connections up first
+    thread::sleep(Duration::from_millis(50));
+
+    let started = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            thread::spawn(move || {
+                (0..REQUESTS)
+                    .map(|_| {
+                        let started = Instant::now();
+                        get(addr);
+                        started.elapsed()
+                    })
+                    .max()
+                    .unwrap()
+            })
+        })
+        .collect();
+    let slowest = clients
+        .into_iter()
+        .map(|c| c.join().unwrap())
+        .max()
+        .unwrap();
+    let elapsed = started.elapsed();
+    drop(hogs);
+    (elapsed, slowest)
+}
+
+fn report(mode: &str, workload: &str, (elapsed, slowest): (Duration, Duration)) {
+    let rate = (CLIENTS * REQUESTS) as f64 / elapsed.as_secs_f64();
+    println!(
+        "{mode:<9} {workload:<9} {rate:>10.0} {:>12.1}",
+        slowest.as_secs_f64() * 1e3
+    );
+}
+
+fn main() {
+    let modes = [
+        ("blocking", Mode::Blocking),
+        #[cfg(target_os = "linux")]
+        ("epoll", Mode::Epoll),
+    ];
+
+    println!(
+        "{:<9} {:<9} {:>10} {:>12}",
+        "mode", "workload", "req/s", "slowest ms"
+    );
+    for (name, mode) in modes {
+        let addr = start(mode);
+        // Warm up the page cache and the pool
+        load(addr, 0);
+        report(name, "busy", load(addr, 0));
+        report(name, "idle", load(addr, IDLE));
+    }
+}
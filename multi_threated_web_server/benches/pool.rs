@@ -0,0 +1,169 @@
+//! Throughput of the work-stealing `ThreadPool` against the single
+//! `Mutex<Receiver>` design it replaced, on workloads of many tiny jobs.
+//!
+//! Run with `cargo bench --bench pool`.
+
+use multi_threaded_web_server::ThreadPoolBuilder;
+use std::{
+    hint::black_box,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const JOBS: usize = 200_000;
+const OUTER: usize = 2_000;
+const INNER: usize = 100;
+const RUNS: usize = 5;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The pool as it was before work stealing: every worker blocks on one shared receiver.
+struct ChannelPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+impl ChannelPool {
+    fn new(size: usize) -> ChannelPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let message = receiver.lock().unwrap().recv();
+                    match message {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        ChannelPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+trait Pool: Sync + 'static {
+    fn run(&'static self, job: Job);
+}
+
+impl Pool for ChannelPool {
+    fn run(&'static self, job: Job) {
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+}
+
+impl Pool for multi_threaded_web_server::ThreadPool {
+    fn run(&'static self, job: Job) {
+        self.execute(job).unwrap();
+    }
+}
+
+fn tiny_work(n: usize) -> usize {
+    (0..16).fold(n, |acc, i| black_box(acc.wrapping_mul(31).wrapping_add(i)))
+}
+
+fn wait_for(done: &AtomicUsize, count: usize) {
+    while done.load(Ordering::Acquire) < count {
+        thread::sleep(Duration::from_micros(50));
+    }
+}
+
+/// Every job is submitted from the main thread.
+fn flat(pool: &'static impl Pool) -> Duration {
+    let done: &'static AtomicUsize = Box::leak(Box::new(AtomicUsize::new(0)));
+    let started = Instant::now();
+    for n in 0..JOBS {
+        pool.run(Box::new(move || {
+            black_box(tiny_work(n));
+            done.fetch_add(1, Ordering::Release);
+        }));
+    }
+    wait_for(done, JOBS);
+    started.elapsed()
+}
+
+/// Jobs fan out into more jobs from inside the pool.
+fn nested(pool: &'static impl Pool) -> Duration {
+    let done: &'static AtomicUsize = Box::leak(Box::new(AtomicUsize::new(0)));
+    let started = Instant::now();
+    for _ in 0..OUTER {
+        pool.run(Box::new(move || {
+            for n in 0..INNER {
+                pool.run(Box::new(move || {
+                    black_box(tiny_work(n));
+                    done.fetch_add(1, Ordering::Release);
+                }));
+            }
+        }));
+    }
+    wait_for(done, OUTER * INNER);
+    started.elapsed()
+}
+
+fn best_of(mut f: impl FnMut() -> Duration) -> Duration {
+    (0..RUNS).map(|_| f()).min().unwrap()
+}
+
+fn report(workload: &str, threads: usize, jobs: usize, channel: Duration, stealing: Duration) {
+    let rate = |elapsed: Duration| jobs as f64 / elapsed.as_secs_f64() / 1e6;
+    println!(
+        "{workload:<8} {threads:>7} {:>14.2} {:>14.2} {:>8.2}x",
+        rate(channel),
+        rate(stealing),
+        channel.as_secs_f64() / stealing.as_secs_f64()
+    );
+}
+
+fn main() {
+    let max = thread::available_parallelism().map_or(4, |n| n.get());
+    let mut sizes = vec![1, 2, 4, max];
+    sizes.sort_unstable();
+    sizes.dedup();
+
+    println!(
+        "{:<8} {:>7} {:>14} {:>14} {:>9}",
+        "workload", "threads", "channel Mj/s", "stealing Mj/s", "speedup"
+    );
+    for threads in sizes {
+        // Pools are leaked so jobs can hold `&'static` references to them
+        let channel: &'static ChannelPool = Box::leak(Box::new(ChannelPool::new(threads)));
+        let stealing = Box::leak(Box::new(
+            ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap(),
+        ));
+
+        report(
+            "flat",
+            threads,
+            JOBS,
+            best_of(|| flat(channel)),
+            best_of(|| flat(&*stealing)),
+        );
+        report(
+            "nested",
+            threads,
+            OUTER * INNER,
+            best_of(|| nested(channel)),
+            best_of(|| nested(&*stealing)),
+        );
+    }
+}
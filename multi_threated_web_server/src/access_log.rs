@@ -0,0 +1,382 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `host - - [time] "request" status bytes`
+    Common,
+    /// Common plus the quoted `Referer` and `User-Agent` headers
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Everything we know about one request once its response has been written.
+#[derive(Debug, Clone)]
+pub struct AccessEntry {
+    pub time: SystemTime,
+    pub client: Option<SocketAddr>,
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub latency: Duration,
+    pub worker: Option<usize>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+/// Writes one line per request to stdout or a file.
+///
+/// Lines are written under a lock so entries from different workers never interleave.
+pub struct AccessLog {
+    format: LogFormat,
+    sink: Mutex<Sink>,
+}
+
+impl AccessLog {
+    pub fn stdout(format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            sink: Mutex::new(Sink::Stdout),
+        }
+    }
+
+    /// Appends to the file at `path`.
+    ///
+    /// Once the file would grow past `max_bytes` it is renamed to `path.1`
+    /// (shifting older files up to `path.{keep}`) and a fresh file is started.
+    /// A `max_bytes` of 0 never rotates.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the file can't be opened for appending.
+    pub fn file(
+        path: impl Into<PathBuf>,
+        format: LogFormat,
+        max_bytes: u64,
+        keep: usize,
+    ) -> io::Result<AccessLog> {
+        Ok(AccessLog {
+            format,
+            sink: Mutex::new(Sink::File(RotatingFile::open(
+                path.into(),
+                max_bytes,
+                keep,
+            )?)),
+        })
+    }
+
+    /// Formats and writes the entry, reporting failures on stderr since there
+    /// is nobody else to hand them to.
+    pub fn log(&self, entry: &AccessEntry) {
+        let line = format_entry(self.format, entry);
+        // A worker that panicked mid-write can't leave a sink in a state worth refusing to use
+        let mut sink = self
+            .sink
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let result = match &mut *sink {
+            Sink::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Sink::File(file) => file.write_line(&line),
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to write access log: {err}");
+        }
+    }
+
+    /// Starts a fresh file the same way outgrowing `max_bytes` does, for rotating
+    /// on a schedule. Does nothing for stdout or a file with nothing in it yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the files can't be renamed or the new one opened.
+    pub fn rotate(&self) -> io::Result<()> {
+        let mut sink = self
+            .sink
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match &mut *sink {
+            Sink::File(file) if file.written > 0 => file.rotate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            written,
+            max_bytes,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64;
+        if self.max_bytes > 0 && self.written > 0 && self.written + len > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.written += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            // Nothing to keep, just start over
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+fn format_entry(format: LogFormat, entry: &AccessEntry) -> String {
+    let client = entry
+        .client
+        .map_or_else(|| "-".to_string(), |addr| addr.ip().to_string());
+    let request = format!("{} {} {}", entry.method, entry.path, entry.version);
+
+    let mut line = String::new();
+    match format {
+        LogFormat::Common | LogFormat::Combined => {
+            let _ = write!(
+                line,
+                "{client} - - [{}] \"{}\" {} {}",
+                clf_time(entry.time),
+                request.escape_default(),
+                entry.status,
+                entry.bytes
+            );
+            if format == LogFormat::Combined {
+                for header in [&entry.referer, &entry.user_agent] {
+                    let value = header.as_deref().unwrap_or("-");
+                    let _ = write!(line, " \"{}\"", value.escape_default());
+                }
+            }
+        }
+        LogFormat::Json => {
+            let worker = entry
+                .worker
+                .map_or_else(|| "null".to_string(), |id| id.to_string());
+            let _ = write!(
+                line,
+                "{{\"time\":{},\"client\":{},\"method\":{},\"path\":{},\"version\":{},\
+                 \"status\":{},\"bytes\":{},\"latency_ms\":{:.3},\"worker\":{worker},\
+                 \"referer\":{},\"user_agent\":{}}}",
+                json_string(Some(&rfc3339_time(entry.time))),
+                json_string(Some(&client)),
+                json_string(Some(&entry.method)),
+                json_string(Some(&entry.path)),
+                json_string(Some(&entry.version)),
+                entry.status,
+                entry.bytes,
+                entry.latency.as_secs_f64() * 1000.0,
+                json_string(entry.referer.as_deref()),
+                json_string(entry.user_agent.as_deref()),
+            );
+        }
+    }
+    line.push('\n');
+    line
+}
+
+fn json_string(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "null".to_string();
+    };
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub(crate) const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, min, sec) = utc_parts(time);
+    format!(
+        "{day:02}/{}/{year}:{hour:02}:{min:02}:{sec:02} +0000",
+        MONTHS[month as usize - 1]
+    )
+}
+
+/// `2000-10-10T13:55:36Z`
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, min, sec) = utc_parts(time);
+    format!("{year}-{month:02}-{day:02}T{hour:02}:{min:02}:{sec:02}Z")
+}
+
+/// Splits a time into UTC `(year, month, day, hour, minute, second)`.
+pub(crate) fn utc_parts(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem % 3600 / 60) as u32,
+        (rem % 60) as u32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AccessEntry {
+        AccessEntry {
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            client: Some("127.0.0.1:54321".parse().unwrap()),
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            bytes: 930,
+            latency: Duration::from_micros(1500),
+            worker: Some(2),
+            referer: None,
+            user_agent: Some("curl/8.0 \"quoted\"".to_string()),
+        }
+    }
+
+    #[test]
+    fn common_and_combined() {
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET / HTTP/1.1\" 200 930\n",
+            format_entry(LogFormat::Common, &entry())
+        );
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET / HTTP/1.1\" 200 930 \"-\" \"curl/8.0 \\\"quoted\\\"\"\n",
+            format_entry(LogFormat::Combined, &entry())
+        );
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(
+            "{\"time\":\"2000-10-10T13:55:36Z\",\"client\":\"127.0.0.1\",\"method\":\"GET\",\
+             \"path\":\"/\",\"version\":\"HTTP/1.1\",\"status\":200,\"bytes\":930,\
+             \"latency_ms\":1.500,\"worker\":2,\"referer\":null,\
+             \"user_agent\":\"curl/8.0 \\\"quoted\\\"\"}\n",
+            format_entry(LogFormat::Json, &entry())
+        );
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("access_log_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let line_len = format_entry(LogFormat::Common, &entry()).len() as u64;
+        let log = AccessLog::file(&path, LogFormat::Common, line_len * 2, 2).unwrap();
+        for _ in 0..7 {
+            log.log(&entry());
+        }
+
+        let size = |p: PathBuf| fs::metadata(p).unwrap().len();
+        assert_eq!(line_len, size(path.clone()));
+        assert_eq!(line_len * 2, size(rotated_path(&path, 1)));
+
+        // On demand rotation only moves files that have something in them
+        log.rotate().unwrap();
+        log.rotate().unwrap();
+        assert_eq!(0, size(path.clone()));
+        assert_eq!(line_len, size(rotated_path(&path, 1)));
+        assert_eq!(line_len * 2, size(rotated_path(&path, 2)));
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
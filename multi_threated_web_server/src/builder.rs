@@ -0,0 +1,303 @@
+use crate::{
+    access_log::AccessLog,
+    cache::FileCache,
+    config::{AccessLogTarget, Config, LogLevel},
+    middleware::{Middleware, RateLimit},
+    proxy::Proxy,
+    server::{Server, Services},
+    sse::Channel,
+    websocket::{self, WebSocket},
+    PoolCreationError, ThreadPoolBuilder,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs, io,
+    net::TcpListener,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+/// How often rate limit buckets that have filled up again are dropped.
+const RATE_LIMIT_SWEEP: Duration = Duration::from_secs(60);
+
+/// Sets up a [`Server`] from its [`Config`] and the middleware to run around
+/// every request.
+///
+/// ```no_run
+/// use multi_threaded_web_server::{
+///     config::Config,
+///     middleware::{Logger, RequestId},
+///     server::Server,
+/// };
+///
+/// let server = Server::builder(Config::default())
+///     .middleware(RequestId::new())
+///     .middleware(Logger)
+///     .build()
+///     .unwrap();
+/// server.run();
+/// ```
+///
+/// [`Server::spawn`] runs it in the background instead, which with port 0
+/// is how the integration tests start one.
+pub struct ServerBuilder {
+    config: Config,
+    middleware: Vec<Box<dyn Middleware>>,
+    websockets: HashMap<String, websocket::Handler>,
+    event_streams: HashMap<String, Channel>,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config,
+            middleware: Vec::new(),
+            websockets: HashMap::new(),
+            event_streams: HashMap::new(),
+        }
+    }
+
+    /// Adds a layer inside those added before it, so the first one added
+    /// sees each request first and its response last.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> ServerBuilder {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Accepts WebSocket connections at `path`, running `handler` for each
+    /// one on a thread of its own. Other requests for the path get `426`.
+    pub fn websocket<F>(mut self, path: &str, handler: F) -> ServerBuilder
+    where
+        F: Fn(WebSocket) + Send + Sync + 'static,
+    {
+        self.websockets.insert(path.to_string(), Arc::new(handler));
+        self
+    }
+
+    /// Subscribes clients that `GET` `path` to `channel`, whose events they
+    /// are then sent as a `text/event-stream`.
+    pub fn events(mut self, path: &str, channel: &Channel) -> ServerBuilder {
+        self.event_streams.insert(path.to_string(), channel.clone());
+        self
+    }
+
+    /// Opens the access log, starts the pool and binds the listeners.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if any of those fail.
+    pub fn build(self) -> Result<Server, BuildError> {
+        let config = Arc::new(self.config);
+
+        let access_log = match &config.access_log {
+            AccessLogTarget::Off => None,
+            AccessLogTarget::Stdout => Some(AccessLog::stdout(config.access_log_format)),
+            AccessLogTarget::File(path) => Some(
+                AccessLog::file(
+                    path,
+                    config.access_log_format,
+                    config.access_log_max_size,
+                    config.access_log_keep,
+                )
+                .map_err(|source| BuildError::AccessLog {
+                    path: path.clone(),
+                    source,
+                })?,
+            ),
+        };
+        let access_log = Arc::new(access_log);
+
+        let log_level = config.log_level;
+        let mut builder = ThreadPoolBuilder::new()
+            .thread_name(|id| format!("http-worker-{id}"))
+            .when_full(config.when_full)
+            .panic_handler(move |worker, message| {
+                if log_level >= LogLevel::Error {
+                    match worker {
+                        Some(worker) => eprintln!(
+                            "Worker {worker} panicked while handling a connection: {message}"
+                        ),
+                        None => eprintln!("Panicked while handling a connection: {message}"),
+                    }
+                }
+            });
+        if let Some(workers) = config.workers {
+            builder = builder.num_threads(workers);
+        }
+        if let Some(capacity) = config.queue_capacity {
+            builder = builder.queue_capacity(capacity);
+        }
+        let pool = builder.build().map_err(BuildError::Pool)?;
+
+        // These run on a worker like any other job, for as long as the pool is around
+        if let Some(interval) = config.access_log_rotate_every {
+            let access_log = Arc::clone(&access_log);
+            pool.schedule_every(interval, move || {
+                if let Some(access_log) = access_log.as_ref() {
+                    if let Err(err) = access_log.rotate() {
+                        eprintln!("Failed to rotate access log: {err}");
+                    }
+                }
+            });
+        }
+
+        let proxy = (!config.proxy.is_empty())
+            .then(|| Arc::new(Proxy::new(&config.proxy, config.upstream_timeout)));
+        if let (Some(proxy), Some(interval)) = (&proxy, config.health_check_interval) {
+            let proxy = Arc::clone(proxy);
+            pool.schedule_every(interval, move || {
+                for (upstream, up) in proxy.check_health() {
+                    if up && log_level >= LogLevel::Info {
+                        println!("Upstream {upstream} is back up");
+                    } else if !up && log_level >= LogLevel::Warn {
+                        eprintln!("Upstream {upstream} is down");
+                    }
+                }
+            });
+        }
+
+        let cache = config
+            .cache_size
+            .map(|size| Arc::new(FileCache::new(size, config.cache_ttl)));
+        // Expired pages would be read again anyway, this frees their memory
+        if let (Some(cache), Some(ttl)) = (&cache, config.cache_ttl) {
+            let cache = Arc::clone(cache);
+            pool.schedule_every(ttl, move || {
+                cache.evict_expired();
+            });
+        }
+
+        if let Some(interval) = config.sse_heartbeat {
+            let channels: Vec<Channel> = self.event_streams.values().cloned().collect();
+            if !channels.is_empty() {
+                pool.schedule_every(interval, move || {
+                    for channel in &channels {
+                        channel.heartbeat();
+                    }
+                });
+            }
+        }
+
+        // Outside any other middleware, so turning a client away costs as little as possible
+        let mut middleware = self.middleware;
+        if config.rate_limit.is_some() || !config.rate_limit_routes.is_empty() {
+            let mut limit = match config.rate_limit {
+                Some(rate) => RateLimit::new(config.rate_limit_burst, rate),
+                None => RateLimit::routes_only(),
+            };
+            for route in &config.rate_limit_routes {
+                limit = limit.route(&route.prefix, route.burst, route.per_second);
+            }
+            let sweeper = limit.clone();
+            pool.schedule_every(RATE_LIMIT_SWEEP, move || {
+                sweeper.sweep();
+            });
+            middleware.insert(0, Box::new(limit));
+        }
+
+        let services = Services {
+            pool: Some(pool.monitor()),
+            proxy,
+            cache,
+            middleware: middleware.into(),
+            websockets: Arc::new(self.websockets),
+            event_streams: Arc::new(self.event_streams),
+        };
+
+        let mut listeners = Vec::new();
+        for addr in config.addrs() {
+            let listener = TcpListener::bind(&addr).map_err(|source| BuildError::Bind {
+                addr: addr.clone(),
+                source,
+            })?;
+            if config.log_level >= LogLevel::Info {
+                println!("Listening on http://{addr}");
+            }
+            listeners.push(listener);
+        }
+
+        #[cfg(unix)]
+        let unix_listener = match &config.unix {
+            Some(path) => {
+                let listener = remove_stale_socket(path)
+                    .and_then(|()| UnixListener::bind(path))
+                    .map_err(|source| BuildError::Bind {
+                        addr: format!("unix:{}", path.display()),
+                        source,
+                    })?;
+                if config.log_level >= LogLevel::Info {
+                    println!("Listening on unix:{}", path.display());
+                }
+                Some(listener)
+            }
+            None => None,
+        };
+
+        Ok(Server {
+            config,
+            pool,
+            services,
+            access_log,
+            listeners,
+            #[cfg(unix)]
+            unix_listener,
+            stopping: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+/// Removes a socket file left behind by a previous run, which would make
+/// bind fail. Anything else at `path`, including a socket a server is still
+/// listening on, is left alone and reported.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another server is listening on the socket",
+            )),
+            // Nobody listening, it's left over
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+            Err(err) => Err(err),
+        },
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        )),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    AccessLog { path: PathBuf, source: io::Error },
+    Pool(PoolCreationError),
+    Bind { addr: String, source: io::Error },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::AccessLog { path, source } => {
+                write!(f, "Problem opening access log {}: {source}", path.display())
+            }
+            BuildError::Pool(err) => write!(f, "Problem creating thread pool: {err}"),
+            BuildError::Bind { addr, source } => write!(f, "Problem binding {addr}: {source}"),
+        }
+    }
+}
+
+impl Error for BuildError {}
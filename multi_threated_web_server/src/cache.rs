@@ -0,0 +1,306 @@
+use crate::http::Request;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// What a request's `Cache-Control` lets the cache answer it with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup {
+    /// Whatever is cached, if it's still fresh
+    Cached,
+    /// Reads the file again and caches that (`no-cache`, `max-age=0`)
+    Reload,
+    /// Reads the file without touching the cache (`no-store`)
+    Bypass,
+}
+
+impl Lookup {
+    pub fn for_request(request: &Request) -> Lookup {
+        let mut lookup = Lookup::Cached;
+        if request
+            .header("Pragma")
+            .is_some_and(|pragma| pragma.eq_ignore_ascii_case("no-cache"))
+        {
+            lookup = Lookup::Reload;
+        }
+        if let Some(directives) = request.header("Cache-Control") {
+            for directive in directives.split(',').map(str::trim) {
+                let directive = directive.to_ascii_lowercase();
+                match directive.as_str() {
+                    "no-store" => return Lookup::Bypass,
+                    "no-cache" | "max-age=0" => lookup = Lookup::Reload,
+                    _ => {}
+                }
+            }
+        }
+        lookup
+    }
+}
+
+struct Entry {
+    contents: Arc<[u8]>,
+    modified: SystemTime,
+    loaded: Instant,
+    /// Key in `Entries::by_use`
+    used: u64,
+}
+
+struct Entries {
+    files: HashMap<PathBuf, Entry>,
+    /// Least recently used first
+    by_use: BTreeMap<u64, PathBuf>,
+    next_use: u64,
+    bytes: usize,
+}
+
+impl Entries {
+    fn touch(&mut self, path: &Path) {
+        let used = self.next_use;
+        self.next_use += 1;
+        let entry = self.files.get_mut(path).unwrap();
+        let path = self.by_use.remove(&entry.used).unwrap();
+        entry.used = used;
+        self.by_use.insert(used, path);
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.files.remove(path) {
+            self.by_use.remove(&entry.used);
+            self.bytes -= entry.contents.len();
+        }
+    }
+}
+
+/// File contents shared by all workers, so pages are read from disk once
+/// rather than on every request.
+///
+/// Holds at most `max_bytes` of contents, evicting the least recently used
+/// files to make room. A cached file is read again once it's older than the
+/// TTL or its modification time has changed.
+pub struct FileCache {
+    entries: Mutex<Entries>,
+    max_bytes: usize,
+    ttl: Option<Duration>,
+}
+
+impl FileCache {
+    /// A `ttl` of `None` keeps files until they're modified or evicted.
+    pub fn new(max_bytes: usize, ttl: Option<Duration>) -> FileCache {
+        FileCache {
+            entries: Mutex::new(Entries {
+                files: HashMap::new(),
+                by_use: BTreeMap::new(),
+                next_use: 0,
+                bytes: 0,
+            }),
+            max_bytes,
+            ttl,
+        }
+    }
+
+    /// The contents of the file at `path`, cached as `lookup` allows, and the
+    /// modification time of the version they were read from.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the file can't be read.
+    pub fn get(&self, path: &Path, lookup: Lookup) -> io::Result<(Arc<[u8]>, SystemTime)> {
+        if lookup == Lookup::Cached {
+            let modified = fs::metadata(path)?.modified()?;
+            let mut entries = self.entries();
+            if let Some(entry) = entries.files.get(path) {
+                if entry.modified == modified && !self.expired(entry) {
+                    let contents = Arc::clone(&entry.contents);
+                    entries.touch(path);
+                    return Ok((contents, modified));
+                }
+            }
+        }
+
+        // Read without holding the lock, a second worker missing on the same
+        // file just reads it too. The time comes from the file read, in case
+        // it's replaced in the meantime.
+        let mut file = File::open(path)?;
+        let modified = file.metadata()?.modified()?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let contents: Arc<[u8]> = contents.into();
+        if lookup != Lookup::Bypass {
+            self.insert(path, Arc::clone(&contents), modified);
+        }
+        Ok((contents, modified))
+    }
+
+    fn insert(&self, path: &Path, contents: Arc<[u8]>, modified: SystemTime) {
+        let mut entries = self.entries();
+        entries.remove(path);
+        if contents.len() > self.max_bytes {
+            return;
+        }
+        while entries.bytes + contents.len() > self.max_bytes {
+            let (_, oldest) = entries.by_use.pop_first().unwrap();
+            entries.remove(&oldest);
+        }
+        let used = entries.next_use;
+        entries.next_use += 1;
+        entries.by_use.insert(used, path.to_path_buf());
+        entries.bytes += contents.len();
+        entries.files.insert(
+            path.to_path_buf(),
+            Entry {
+                contents,
+                modified,
+                loaded: Instant::now(),
+                used,
+            },
+        );
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Entries> {
+        // Nothing panics while holding the lock, and a handler panicking elsewhere
+        // mustn't take every later static request down with it
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn expired(&self, entry: &Entry) -> bool {
+        self.ttl.is_some_and(|ttl| entry.loaded.elapsed() >= ttl)
+    }
+
+    /// Drops files older than the TTL, returning how many there were.
+    pub fn evict_expired(&self) -> usize {
+        let mut entries = self.entries();
+        let expired: Vec<PathBuf> = entries
+            .files
+            .iter()
+            .filter(|(_, entry)| self.expired(entry))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &expired {
+            entries.remove(path);
+        }
+        expired.len()
+    }
+
+    /// The `Cache-Control` value for responses served from the cache, letting
+    /// clients keep them as long as the server does.
+    pub fn cache_control(&self) -> String {
+        match self.ttl {
+            Some(ttl) => format!("max-age={}", ttl.as_secs()),
+            None => "no-cache".to_string(),
+        }
+    }
+
+    /// The number of files and bytes cached.
+    pub fn size(&self) -> (usize, usize) {
+        let entries = self.entries();
+        (entries.files.len(), entries.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache_test_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request::new("GET", "/", headers)
+    }
+
+    #[test]
+    fn lookup_follows_cache_control() {
+        assert_eq!(Lookup::Cached, Lookup::for_request(&request(&[])));
+        assert_eq!(
+            Lookup::Reload,
+            Lookup::for_request(&request(&[("Cache-Control", "max-age=0")]))
+        );
+        assert_eq!(
+            Lookup::Reload,
+            Lookup::for_request(&request(&[("Pragma", "no-cache")]))
+        );
+        assert_eq!(
+            Lookup::Bypass,
+            Lookup::for_request(&request(&[("Cache-Control", "No-Cache, no-store")]))
+        );
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = scratch("lru");
+        let [a, b, c] = ["a", "b", "c"].map(|name| dir.join(name));
+        for path in [&a, &b, &c] {
+            fs::write(path, "0123456789").unwrap();
+        }
+        let cache = FileCache::new(25, None);
+
+        cache.get(&a, Lookup::Cached).unwrap();
+        cache.get(&b, Lookup::Cached).unwrap();
+        cache.get(&a, Lookup::Cached).unwrap();
+        cache.get(&c, Lookup::Cached).unwrap();
+        let cached = |path: &PathBuf| cache.entries().files.contains_key(path);
+        assert_eq!((2, 20), cache.size());
+        assert!(cached(&a) && !cached(&b) && cached(&c));
+
+        // Too big to cache at all, and no longer cached as it was
+        fs::write(&c, "x".repeat(30)).unwrap();
+        assert_eq!(30, cache.get(&c, Lookup::Cached).unwrap().0.len());
+        assert_eq!((1, 10), cache.size());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_modified_and_expired_files() {
+        let dir = scratch("fresh");
+        let path = dir.join("page.html");
+        fs::write(&path, "one").unwrap();
+        let cache = FileCache::new(1024, Some(Duration::from_millis(50)));
+        assert_eq!(&b"one"[..], &*cache.get(&path, Lookup::Cached).unwrap().0);
+
+        // Same modification time, so only a reload or expiry picks this up
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, "two").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_eq!(&b"one"[..], &*cache.get(&path, Lookup::Cached).unwrap().0);
+        assert_eq!(&b"two"[..], &*cache.get(&path, Lookup::Bypass).unwrap().0);
+        assert_eq!(&b"one"[..], &*cache.get(&path, Lookup::Cached).unwrap().0);
+        assert_eq!(&b"two"[..], &*cache.get(&path, Lookup::Reload).unwrap().0);
+
+        // A new modification time invalidates it straight away
+        fs::write(&path, "three").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        let (contents, read) = cache.get(&path, Lookup::Cached).unwrap();
+        assert_eq!(
+            (&b"three"[..], modified + Duration::from_secs(1)),
+            (&*contents, read)
+        );
+        assert_eq!(read, cache.get(&path, Lookup::Cached).unwrap().1);
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(1, cache.evict_expired());
+        assert_eq!((0, 0), cache.size());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+/// A minimal HTTP/1.1 client making one request per connection, enough to
+/// exercise the server with in tests.
+///
+/// ```no_run
+/// use multi_threaded_web_server::client::Client;
+///
+/// let response = Client::new("127.0.0.1:7878".parse().unwrap()).get("/").unwrap();
+/// assert_eq!(200, response.status);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+}
+
+/// A whole response, its body decoded from any chunked framing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl ClientResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The body as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+impl Client {
+    /// Requests time out after 10 seconds.
+    pub fn new(addr: SocketAddr) -> Client {
+        Client {
+            addr,
+            timeout: Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// Bounds connecting, and each read and write, `None` waits forever.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    /// # Errors
+    ///
+    /// Returns Err() if the request fails or the response is malformed.
+    pub fn get(&self, path: &str) -> io::Result<ClientResponse> {
+        self.request("GET", path, &[], &[])
+    }
+
+    /// Sends a request with `headers` and `body`, adding `Host`,
+    /// `Connection: close` and, for a body, `Content-Length`.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the request fails or the response is malformed.
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<ClientResponse> {
+        let mut stream = match self.timeout {
+            Some(timeout) => TcpStream::connect_timeout(&self.addr, timeout)?,
+            None => TcpStream::connect(self.addr)?,
+        };
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+
+        let mut head = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            self.addr
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        read_response(BufReader::new(stream), method == "HEAD")
+    }
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed response {what}"),
+    )
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn read_response(mut reader: impl BufRead, head_only: bool) -> io::Result<ClientResponse> {
+    let status_line = read_line(&mut reader)?;
+    let status = match status_line.split_whitespace().nth(1) {
+        Some(status) if status_line.starts_with("HTTP/") => {
+            status.parse().map_err(|_| malformed("status"))?
+        }
+        _ => return Err(malformed("status line")),
+    };
+    let mut response = ClientResponse {
+        status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        match line.split_once(':') {
+            Some((name, value)) => response
+                .headers
+                .push((name.to_string(), value.trim().to_string())),
+            None => return Err(malformed("header")),
+        }
+    }
+
+    if head_only || status < 200 || status == 204 || status == 304 {
+        return Ok(response);
+    }
+    if response
+        .header("Transfer-Encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"))
+    {
+        loop {
+            let line = read_line(&mut reader)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| malformed("chunk size"))?;
+            if size == 0 {
+                // Skip any trailers
+                while !read_line(&mut reader)?.is_empty() {}
+                break;
+            }
+            let start = response.body.len();
+            response.body.resize(start + size, 0);
+            reader.read_exact(&mut response.body[start..])?;
+            if !read_line(&mut reader)?.is_empty() {
+                return Err(malformed("chunk"));
+            }
+        }
+    } else if let Some(len) = response.header("Content-Length") {
+        let len = len.parse().map_err(|_| malformed("Content-Length"))?;
+        response.body = vec![0; len];
+        reader.read_exact(&mut response.body)?;
+    } else {
+        reader.read_to_end(&mut response.body)?;
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_framed_bodies() {
+        let read = |raw: &str| read_response(raw.as_bytes(), false).unwrap();
+
+        let response = read("HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Id: 7\r\n\r\nhello, extra");
+        assert_eq!((200, "hello"), (response.status, response.text().as_str()));
+        assert_eq!(Some("7"), response.header("x-id"));
+
+        let response = read(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n",
+        );
+        assert_eq!("hello, world", response.text());
+
+        let response = read("HTTP/1.0 404 Not Found\r\n\r\nuntil close");
+        assert_eq!(
+            (404, "until close"),
+            (response.status, response.text().as_str())
+        );
+
+        assert!(
+            read_response(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n"[..], true)
+                .unwrap()
+                .body
+                .is_empty()
+        );
+        assert!(read_response(&b"SSH-2.0\r\n\r\n"[..], false).is_err());
+    }
+}
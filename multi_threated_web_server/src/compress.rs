@@ -0,0 +1,720 @@
+use crate::http::{Body, Chunks, Request, Response};
+use std::io::{self, Write};
+
+/// How far back a match may reach, the most DEFLATE allows.
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+/// Candidates tried per match, trading ratio for speed.
+const MAX_CHAIN: usize = 64;
+/// Bytes an [`EncodingWriter`] collects before compressing them as one block.
+const WRITER_BUFFER: usize = 16 * 1024;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// A content coding this server can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    /// The zlib format, which is what HTTP calls deflate
+    Deflate,
+}
+
+impl Coding {
+    /// The name used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the coding to answer with given the request's `Accept-Encoding`,
+/// gzip on a tie. `None` means sending the body as it is.
+pub fn negotiate(accept_encoding: &str) -> Option<Coding> {
+    let (mut gzip, mut deflate, mut any) = (None, None, None);
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(1.0, |q| q.trim().parse::<f32>().unwrap_or(0.0));
+        match name.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(q),
+            "deflate" => deflate = Some(q),
+            "*" => any = Some(q),
+            _ => {}
+        }
+    }
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let deflate = deflate.or(any).unwrap_or(0.0);
+    if gzip <= 0.0 && deflate <= 0.0 {
+        None
+    } else if gzip >= deflate {
+        Some(Coding::Gzip)
+    } else {
+        Some(Coding::Deflate)
+    }
+}
+
+/// Whether bodies of this `Content-Type` are worth compressing. Images,
+/// video and archives mostly come compressed already.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+/// Compresses the response body for the client if its type is compressible
+/// and, when the size is known up front, it's at least `min_size` bytes.
+///
+/// Such responses get `Vary: Accept-Encoding` whether or not the client
+/// accepted a coding, since caches must not hand one client's version to
+/// another. Buffered bodies stay buffered, and keep their `Content-Length`;
+/// streamed ones are compressed as they go.
+pub fn compress_response(request: &Request, mut response: Response, min_size: usize) -> Response {
+    // A range is of the unencoded body, so it can't be encoded on its own
+    let eligible = response.status != 206
+        && response.header("Content-Encoding").is_none()
+        && response.header("Content-Type").is_some_and(is_compressible)
+        && match &response.body {
+            Body::Empty => false,
+            Body::Full(bytes) => bytes.len() >= min_size,
+            Body::Stream(_) | Body::Writer(_) => true,
+        };
+    if !eligible {
+        return response;
+    }
+    response = response.with_header("Vary", "Accept-Encoding");
+    let Some(coding) = request.header("Accept-Encoding").and_then(negotiate) else {
+        return response;
+    };
+
+    response.body = match response.body {
+        Body::Full(bytes) => {
+            let compressed = compress(coding, &bytes);
+            // Already compressed data only grows
+            if compressed.len() >= bytes.len() {
+                response.body = Body::Full(bytes);
+                return response;
+            }
+            Body::Full(compressed)
+        }
+        Body::Stream(chunks) => Body::Stream(Box::new(CompressedChunks {
+            chunks,
+            encoder: Some(Encoder::new(coding)),
+        })),
+        Body::Writer(f) => Body::Writer(Box::new(move |writer: &mut dyn Write| {
+            let mut writer = EncodingWriter::new(writer, coding);
+            f(&mut writer)?;
+            writer.finish()
+        })),
+        Body::Empty => Body::Empty,
+    };
+    // The encoded body isn't byte for byte the one the tag was made for
+    for (name, value) in &mut response.headers {
+        if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+            value.insert_str(0, "W/");
+        }
+    }
+    response.with_header("Content-Encoding", coding.name())
+}
+
+/// Compresses `data` in one go.
+pub fn compress(coding: Coding, data: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new(coding);
+    let mut out = encoder.write(data);
+    out.extend(encoder.finish());
+    out
+}
+
+/// Packs bits least significant first, as DEFLATE wants them.
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, count: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes go most significant bit first.
+    fn put_code(&mut self, code: u32, len: u32) {
+        self.put(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.put(0, 8 - self.count);
+        }
+    }
+
+    /// Takes the bytes completed so far.
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+
+    fn literal(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.put_code(0x30 + symbol, 8),
+            144..=255 => self.put_code(0x190 + symbol - 144, 9),
+            256..=279 => self.put_code(symbol - 256, 7),
+            _ => self.put_code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn copy(&mut self, len: usize, dist: usize) {
+        let code = LENGTH_BASE
+            .iter()
+            .rposition(|&base| base as usize <= len)
+            .unwrap();
+        self.literal(257 + code as u16);
+        self.put(
+            (len - LENGTH_BASE[code] as usize) as u32,
+            LENGTH_EXTRA[code].into(),
+        );
+        let code = DIST_BASE
+            .iter()
+            .rposition(|&base| base as usize <= dist)
+            .unwrap();
+        self.put_code(code as u32, 5);
+        self.put(
+            (dist - DIST_BASE[code] as usize) as u32,
+            DIST_EXTRA[code].into(),
+        );
+    }
+}
+
+/// A streaming gzip or zlib compressor using fixed Huffman codes.
+///
+/// Each write is compressed as one block, with matches reaching back into
+/// earlier writes, and the whole bytes finished so far handed back.
+pub struct Encoder {
+    coding: Coding,
+    out: BitWriter,
+    /// The last `WINDOW` bytes written, for matches to refer to
+    history: Vec<u8>,
+    crc: u32,
+    adler: (u32, u32),
+    size: u32,
+}
+
+impl Encoder {
+    pub fn new(coding: Coding) -> Encoder {
+        let mut out = BitWriter {
+            bytes: Vec::new(),
+            bits: 0,
+            count: 0,
+        };
+        match coding {
+            // No name or modification time, unknown OS
+            Coding::Gzip => out.bytes.extend([0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 0xFF]),
+            Coding::Deflate => out.bytes.extend([0x78, 0x01]),
+        }
+        Encoder {
+            coding,
+            out,
+            history: Vec::new(),
+            crc: !0,
+            adler: (1, 0),
+            size: 0,
+        }
+    }
+
+    /// Compresses `data`, returning the output that's ready.
+    pub fn write(&mut self, data: &[u8]) -> Vec<u8> {
+        if !data.is_empty() {
+            self.checksum(data);
+            self.block(data);
+        }
+        self.out.take()
+    }
+
+    /// Returns everything written so far in decodable form, at the cost of
+    /// a few bytes, for bodies whose parts are meant to reach the client promptly.
+    pub fn flush(&mut self) -> Vec<u8> {
+        // An empty stored block ends on a byte boundary
+        self.out.put(0, 3);
+        self.out.align();
+        self.out.put(0xFFFF_0000, 32);
+        self.out.take()
+    }
+
+    /// Ends the stream, returning the rest of the output.
+    pub fn finish(mut self) -> Vec<u8> {
+        // An empty final block
+        self.out.put(1, 1);
+        self.out.put(1, 2);
+        self.out.literal(256);
+        self.out.align();
+        let mut out = self.out.take();
+        match self.coding {
+            Coding::Gzip => {
+                out.extend((!self.crc).to_le_bytes());
+                out.extend(self.size.to_le_bytes());
+            }
+            Coding::Deflate => {
+                let (a, b) = self.adler;
+                out.extend(((b << 16) | a).to_be_bytes());
+            }
+        }
+        out
+    }
+
+    fn checksum(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc = CRC_TABLE[((self.crc ^ u32::from(byte)) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+        let (mut a, mut b) = self.adler;
+        // Deferring the modulo this long can't overflow
+        for chunk in data.chunks(5552) {
+            for &byte in chunk {
+                a += u32::from(byte);
+                b += a;
+            }
+            a %= 65521;
+            b %= 65521;
+        }
+        self.adler = (a, b);
+        self.size = self.size.wrapping_add(data.len() as u32);
+    }
+
+    /// Writes one non-final fixed Huffman block holding `data`.
+    fn block(&mut self, data: &[u8]) {
+        let start = self.history.len();
+        let mut buf = std::mem::take(&mut self.history);
+        buf.extend_from_slice(data);
+
+        let mut chains = Chains {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; buf.len()],
+        };
+        for i in 0..start {
+            chains.insert(&buf, i);
+        }
+
+        self.out.put(0, 1);
+        self.out.put(1, 2);
+        let mut i = start;
+        while i < buf.len() {
+            let (len, dist) = chains.longest_match(&buf, i);
+            if len >= MIN_MATCH {
+                self.out.copy(len, dist);
+                for j in i..i + len {
+                    chains.insert(&buf, j);
+                }
+                i += len;
+            } else {
+                self.out.literal(buf[i].into());
+                chains.insert(&buf, i);
+                i += 1;
+            }
+        }
+        self.out.literal(256);
+
+        let keep = buf.len().saturating_sub(WINDOW);
+        buf.drain(..keep);
+        self.history = buf;
+    }
+}
+
+/// Earlier positions in a buffer, chained by the hash of the three bytes there.
+struct Chains {
+    /// The latest position for each hash
+    head: Vec<usize>,
+    /// The position before each one with the same hash
+    prev: Vec<usize>,
+}
+
+impl Chains {
+    fn hash(buf: &[u8], i: usize) -> usize {
+        let h = (u32::from(buf[i]) << 10) ^ (u32::from(buf[i + 1]) << 5) ^ u32::from(buf[i + 2]);
+        (h & ((1 << HASH_BITS) - 1)) as usize
+    }
+
+    fn insert(&mut self, buf: &[u8], i: usize) {
+        if i + MIN_MATCH <= buf.len() {
+            let h = Chains::hash(buf, i);
+            self.prev[i] = self.head[h];
+            self.head[h] = i;
+        }
+    }
+
+    /// The longest earlier match for the bytes at `i`, as a length and distance.
+    fn longest_match(&self, buf: &[u8], i: usize) -> (usize, usize) {
+        if i + MIN_MATCH > buf.len() {
+            return (0, 0);
+        }
+        let max_len = (buf.len() - i).min(MAX_MATCH);
+        let (mut best, mut dist) = (0, 0);
+        let mut candidate = self.head[Chains::hash(buf, i)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || i - candidate > WINDOW {
+                break;
+            }
+            let len = buf[candidate..]
+                .iter()
+                .zip(&buf[i..i + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best {
+                (best, dist) = (len, i - candidate);
+                if len == max_len {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+        }
+        (best, dist)
+    }
+}
+
+/// Compresses a streamed body chunk by chunk.
+struct CompressedChunks {
+    chunks: Chunks,
+    encoder: Option<Encoder>,
+}
+
+impl Iterator for CompressedChunks {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let encoder = self.encoder.as_mut()?;
+        loop {
+            match self.chunks.next() {
+                Some(Ok(chunk)) => {
+                    let out = encoder.write(&chunk);
+                    if !out.is_empty() {
+                        return Some(Ok(out));
+                    }
+                }
+                Some(Err(err)) => {
+                    self.encoder = None;
+                    return Some(Err(err));
+                }
+                None => return self.encoder.take().map(|encoder| Ok(encoder.finish())),
+            }
+        }
+    }
+}
+
+/// Compresses what's written to it before passing it on.
+///
+/// Writes are collected into blocks, [`flush`](Write::flush) sends what there
+/// is straight away. [`finish`](EncodingWriter::finish) must be called to end
+/// the compressed stream.
+pub struct EncodingWriter<W: Write> {
+    inner: W,
+    encoder: Encoder,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncodingWriter<W> {
+    pub fn new(inner: W, coding: Coding) -> EncodingWriter<W> {
+        EncodingWriter {
+            inner,
+            encoder: Encoder::new(coding),
+            buf: Vec::new(),
+        }
+    }
+
+    fn compress_buffered(&mut self) -> io::Result<()> {
+        let out = self.encoder.write(&self.buf);
+        self.buf.clear();
+        self.inner.write_all(&out)
+    }
+
+    /// Ends the compressed stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if writing to the inner writer fails.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.compress_buffered()?;
+        self.inner.write_all(&self.encoder.finish())?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for EncodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= WRITER_BUFFER {
+            self.compress_buffered()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.compress_buffered()?;
+        let out = self.encoder.flush();
+        self.inner.write_all(&out)?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, n: u32) -> u32 {
+            let mut value = 0;
+            for i in 0..n {
+                value |= u32::from(self.data[self.pos / 8] >> (self.pos % 8) & 1) << i;
+                self.pos += 1;
+            }
+            value
+        }
+
+        /// Huffman codes come most significant bit first.
+        fn code(&mut self, code: u32, n: u32) -> u32 {
+            (0..n).fold(code, |code, _| (code << 1) | self.bits(1))
+        }
+    }
+
+    /// Just enough of an inflater to read back what the encoder writes:
+    /// fixed Huffman and stored blocks.
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut input = BitReader { data, pos: 0 };
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let last = input.bits(1) == 1;
+            match input.bits(2) {
+                0 => {
+                    input.pos = input.pos.div_ceil(8) * 8;
+                    let len = input.bits(16);
+                    assert_eq!(!len & 0xFFFF, input.bits(16));
+                    for _ in 0..len {
+                        out.push(input.bits(8) as u8);
+                    }
+                }
+                1 => loop {
+                    let code = input.code(0, 7);
+                    let symbol = match code {
+                        0..=23 => 256 + code,
+                        _ => match input.code(code, 1) {
+                            code @ 0x30..=0xBF => code - 0x30,
+                            code @ 0xC0..=0xC7 => 280 + code - 0xC0,
+                            code => 144 + input.code(code, 1) - 0x190,
+                        },
+                    };
+                    match symbol {
+                        0..=255 => out.push(symbol as u8),
+                        256 => break,
+                        _ => {
+                            let i = (symbol - 257) as usize;
+                            let len = LENGTH_BASE[i] as usize
+                                + input.bits(LENGTH_EXTRA[i].into()) as usize;
+                            let i = input.code(0, 5) as usize;
+                            let dist =
+                                DIST_BASE[i] as usize + input.bits(DIST_EXTRA[i].into()) as usize;
+                            for _ in 0..len {
+                                out.push(out[out.len() - dist]);
+                            }
+                        }
+                    }
+                },
+                kind => panic!("unexpected block type {kind}"),
+            }
+            if last {
+                return out;
+            }
+        }
+    }
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        assert_eq!([0x1F, 0x8B, 8], data[..3]);
+        let out = inflate(&data[10..data.len() - 8]);
+        let trailer = &data[data.len() - 8..];
+        let mut crc = Encoder::new(Coding::Gzip);
+        crc.checksum(&out);
+        assert_eq!((!crc.crc).to_le_bytes(), trailer[..4]);
+        assert_eq!((out.len() as u32).to_le_bytes(), trailer[4..]);
+        out
+    }
+
+    fn request(accept_encoding: &str) -> Request {
+        Request::new("GET", "/", &[("Accept-Encoding", accept_encoding)])
+    }
+
+    #[test]
+    fn checksums() {
+        let mut encoder = Encoder::new(Coding::Deflate);
+        encoder.checksum(b"123456789");
+        assert_eq!(0xCBF4_3926, !encoder.crc);
+        let (a, b) = encoder.adler;
+        assert_eq!(0x091E_01DE, (b << 16) | a);
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = "It was the best of times, it was the worst of times. ".repeat(200);
+        let mut noise = Vec::new();
+        let mut seed = 1u32;
+        for _ in 0..70_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            noise.push((seed >> 16) as u8);
+        }
+        for data in [
+            &b""[..],
+            b"a",
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            text.as_bytes(),
+            &noise,
+        ] {
+            let gzip = compress(Coding::Gzip, data);
+            assert_eq!(data, &gunzip(&gzip)[..]);
+
+            let zlib = compress(Coding::Deflate, data);
+            assert_eq!(0, u16::from_be_bytes([zlib[0], zlib[1]]) % 31);
+            assert_eq!(data, &inflate(&zlib[2..zlib.len() - 4])[..]);
+        }
+        assert!(compress(Coding::Gzip, text.as_bytes()).len() < text.len() / 10);
+    }
+
+    #[test]
+    fn streams_across_writes() {
+        let mut out = Vec::new();
+        let mut writer = EncodingWriter::new(&mut out, Coding::Gzip);
+        for i in 0..2000 {
+            writeln!(writer, "line {i}").unwrap();
+            if i == 1000 {
+                writer.flush().unwrap();
+            }
+        }
+        writer.finish().unwrap();
+        let expected: String = (0..2000).map(|i| format!("line {i}\n")).collect();
+        assert_eq!(expected.as_bytes(), &gunzip(&out)[..]);
+    }
+
+    #[test]
+    fn negotiates() {
+        assert_eq!(Some(Coding::Gzip), negotiate("gzip, deflate, br"));
+        assert_eq!(Some(Coding::Deflate), negotiate("gzip;q=0.5, deflate"));
+        assert_eq!(Some(Coding::Deflate), negotiate("deflate, gzip;q=0"));
+        assert_eq!(Some(Coding::Gzip), negotiate("*"));
+        assert_eq!(None, negotiate("br, identity"));
+        assert_eq!(None, negotiate("*;q=0"));
+    }
+
+    #[test]
+    fn compresses_eligible_responses() {
+        let page = "<p>hello</p>".repeat(100);
+        let html = || {
+            Response::new(200)
+                .with_header("Content-Type", "text/html; charset=utf-8")
+                .with_body(page.clone())
+        };
+
+        let response =
+            compress_response(&request("gzip"), html().with_header("ETag", "\"v1\""), 1024);
+        assert_eq!(Some("gzip"), response.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+        assert_eq!(Some("W/\"v1\""), response.header("ETag"));
+        match response.body {
+            Body::Full(bytes) => assert_eq!(page.as_bytes(), &gunzip(&bytes)[..]),
+            body => panic!("expected a full body, got {body:?}"),
+        }
+
+        // Varies even when the client takes it as it is
+        let response = compress_response(&request("identity"), html(), 1024);
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+
+        // Too small, not worth it, or only part of the body
+        let response = compress_response(&request("gzip"), html(), 10_000);
+        assert_eq!(None, response.header("Vary"));
+        let mut partial = html();
+        partial.status = 206;
+        let response = compress_response(&request("gzip"), partial, 1024);
+        assert_eq!(None, response.header("Content-Encoding"));
+        let png = Response::new(200)
+            .with_header("Content-Type", "image/png")
+            .with_body(page.clone());
+        assert_eq!(
+            None,
+            compress_response(&request("gzip"), png, 0).header("Vary")
+        );
+
+        let streamed = Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_stream(
+                page.as_bytes()
+                    .chunks(100)
+                    .map(|c| Ok(c.to_vec()))
+                    .collect::<Vec<_>>(),
+            );
+        let mut response = compress_response(&request("gzip"), streamed, 1024);
+        let mut out = Vec::new();
+        response.write_to(&mut out, false).unwrap();
+        let split = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        assert_eq!(page.as_bytes(), &gunzip(&out[split..])[..]);
+    }
+}
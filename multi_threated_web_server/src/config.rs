@@ -0,0 +1,708 @@
+use crate::{
+    access_log::LogFormat, http::Limits, middleware::RateRoute, pool::FullPolicy, proxy::ProxyRoute,
+};
+use std::{
+    error::Error,
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+pub const USAGE: &str = "\
+Usage: multi_threaded_web_server [OPTIONS]
+
+Options:
+  -c, --config <PATH>         Read settings from a `key = value` config file
+  -b, --bind <ADDR>           Address to listen on, may be repeated [default: 127.0.0.1]
+  -p, --port <PORT>           Port to listen on [default: 7878]
+  -u, --unix <PATH>           Also listen on a Unix domain socket at this path
+  -w, --workers <N>           Number of worker threads [default: available parallelism]
+      --io <MODE>             blocking to give each connection a worker for its whole life,
+                              epoll to read and write on event loop threads and only use
+                              workers for handlers (Linux only) [default: blocking]
+      --event-loops <N>       Event loop threads per listener in epoll mode [default: 1]
+      --queue-capacity <N>    Most connections waiting for a worker, 0 for no limit [default: 0]
+      --when-full <POLICY>    What to do with connections past the queue capacity, one of
+                              block, reject, drop-oldest, caller-runs [default: block]
+  -r, --root <DIR>            Directory the html files are served from [default: .]
+      --read-timeout <SECS>   Socket read timeout, 0 disables it [default: 30]
+      --write-timeout <SECS>  Socket write timeout, 0 disables it [default: 30]
+      --header-timeout <SECS> Deadline for receiving the request line and headers,
+                              0 disables it [default: 10]
+      --max-header-size <BYTES>
+                              Largest request line plus headers accepted [default: 8192]
+      --max-headers <N>       Most request headers accepted [default: 100]
+      --max-body-size <BYTES> Largest request body accepted [default: 1048576]
+      --log-level <LEVEL>     One of error, warn, info, debug [default: info]
+      --access-log <TARGET>   `-` for stdout, `off`, or a file path [default: -]
+      --access-log-format <FORMAT>
+                              One of common, combined, json [default: common]
+      --access-log-max-size <BYTES>
+                              Rotate the access log file past this size, 0 never rotates [default: 0]
+      --access-log-keep <N>   Number of rotated access log files to keep [default: 5]
+      --access-log-rotate-every <SECS>
+                              Also rotate the access log file this often, 0 only rotates
+                              by size [default: 0]
+      --metrics-path <PATH>   Serve thread pool metrics in Prometheus format at this path,
+                              `off` disables it [default: off]
+      --cache-size <BYTES>    Most page bytes kept in memory, 0 disables the cache
+                              [default: 16777216]
+      --cache-ttl <SECS>      How long pages are cached before being read again, 0 keeps
+                              them until they're modified [default: 60]
+      --compress-min-size <BYTES>
+                              Smallest text body sent gzip or deflate compressed to clients
+                              that accept it, `off` disables compression [default: 1024]
+      --rate-limit <PER_SEC>  Requests a second each client IP may make on average, `off`
+                              for no limit [default: off]
+      --rate-limit-burst <N>  Requests a client may make at once before the rate applies
+                              [default: 20]
+      --rate-limit-route <PREFIX=PER_SEC/BURST>
+                              A limit of its own for requests under PREFIX, may be repeated
+      --proxy <PREFIX=UPSTREAMS>
+                              Forward requests under PREFIX to a comma separated list of
+                              host:port upstreams in turn, may be repeated
+      --upstream-timeout <SECS>
+                              Timeout for connecting to, reading from and writing to
+                              upstreams, 0 disables it [default: 30]
+      --health-check-interval <SECS>
+                              How often to check which upstreams are up, 0 disables
+                              the checks [default: 10]
+      --sse-heartbeat <SECS>  How often to send event stream subscribers a comment to keep
+                              their connections open, 0 disables it [default: 15]
+  -h, --help                  Print this message";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How connections are read from and written to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IoMode {
+    /// A worker per connection, blocked on it from accept to close
+    #[default]
+    Blocking,
+    /// Non-blocking sockets multiplexed with epoll, see [`event_loop`](crate::event_loop)
+    Epoll,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessLogTarget {
+    Off,
+    Stdout,
+    File(PathBuf),
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was passed, the caller should print [`USAGE`] and exit.
+    HelpRequested,
+    UnknownOption(String),
+    MissingValue(String),
+    InvalidValue {
+        key: String,
+        value: String,
+    },
+    ReadFile {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Syntax {
+        path: PathBuf,
+        line: usize,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::HelpRequested => write!(f, "Help requested."),
+            ConfigError::UnknownOption(opt) => write!(f, "Unknown option '{opt}'."),
+            ConfigError::MissingValue(opt) => write!(f, "Option '{opt}' needs a value."),
+            ConfigError::InvalidValue { key, value } => {
+                write!(f, "Invalid value '{value}' for '{key}'.")
+            }
+            ConfigError::ReadFile { path, source } => {
+                write!(f, "Could not read config file {}: {source}", path.display())
+            }
+            ConfigError::Syntax { path, line } => write!(
+                f,
+                "Expected `key = value` in {} on line {line}.",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Settings for the server binary.
+///
+/// Values come from the defaults, then the optional config file, then the
+/// command line, each overriding the one before it.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    pub unix: Option<PathBuf>,
+    /// `None` leaves it to the pool, which uses the available parallelism
+    pub workers: Option<usize>,
+    /// `None` lets connections queue up without limit
+    pub queue_capacity: Option<usize>,
+    pub when_full: FullPolicy,
+    pub io: IoMode,
+    pub event_loops: usize,
+    pub root: PathBuf,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub header_timeout: Option<Duration>,
+    pub max_header_size: usize,
+    pub max_headers: usize,
+    pub max_body_size: usize,
+    pub log_level: LogLevel,
+    pub access_log: AccessLogTarget,
+    pub access_log_format: LogFormat,
+    pub access_log_max_size: u64,
+    pub access_log_keep: usize,
+    /// How often to rotate the access log file regardless of its size
+    pub access_log_rotate_every: Option<Duration>,
+    /// Request path the pool metrics are served at, `None` to not serve them
+    pub metrics_path: Option<String>,
+    /// `None` reads pages from disk for every request
+    pub cache_size: Option<usize>,
+    pub cache_ttl: Option<Duration>,
+    /// `None` sends every body as it is
+    pub compress_min_size: Option<usize>,
+    /// Requests a second per client, `None` for no limit outside the routes
+    pub rate_limit: Option<f64>,
+    pub rate_limit_burst: u32,
+    pub rate_limit_routes: Vec<RateRoute>,
+    pub proxy: Vec<ProxyRoute>,
+    pub upstream_timeout: Option<Duration>,
+    /// `None` only finds upstreams down, or back up, when forwarding to them
+    pub health_check_interval: Option<Duration>,
+    /// `None` leaves idle event streams to whatever times them out on the way
+    pub sse_heartbeat: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec![IpAddr::from([127, 0, 0, 1])],
+            port: 7878,
+            unix: None,
+            workers: None,
+            queue_capacity: None,
+            when_full: FullPolicy::Block,
+            io: IoMode::Blocking,
+            event_loops: 1,
+            root: PathBuf::from("."),
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            header_timeout: Some(Duration::from_secs(10)),
+            max_header_size: 8192,
+            max_headers: 100,
+            max_body_size: 1024 * 1024,
+            log_level: LogLevel::Info,
+            access_log: AccessLogTarget::Stdout,
+            access_log_format: LogFormat::Common,
+            access_log_max_size: 0,
+            access_log_keep: 5,
+            access_log_rotate_every: None,
+            metrics_path: None,
+            cache_size: Some(16 * 1024 * 1024),
+            cache_ttl: Some(Duration::from_secs(60)),
+            compress_min_size: Some(1024),
+            rate_limit: None,
+            rate_limit_burst: 20,
+            rate_limit_routes: Vec::new(),
+            proxy: Vec::new(),
+            upstream_timeout: Some(Duration::from_secs(30)),
+            health_check_interval: Some(Duration::from_secs(10)),
+            sse_heartbeat: Some(Duration::from_secs(15)),
+        }
+    }
+}
+
+impl Config {
+    /// Builds the config from `env::args()` style arguments.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() for unknown options, missing or invalid values, and an
+    /// unreadable or malformed config file.
+    pub fn build(args: &[String]) -> Result<Config, ConfigError> {
+        let mut cli = Vec::new();
+        let mut config_path = None;
+
+        // Skip the program name
+        let mut args_iterator = args.iter().skip(1);
+        while let Some(arg) = args_iterator.next() {
+            // Accept both `--port 80` and `--port=80`
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let key = match flag {
+                "-h" | "--help" => return Err(ConfigError::HelpRequested),
+                "-c" | "--config" => "config",
+                "-b" | "--bind" => "bind",
+                "-p" | "--port" => "port",
+                "-u" | "--unix" => "unix",
+                "-w" | "--workers" => "workers",
+                "--queue-capacity" => "queue_capacity",
+                "--when-full" => "when_full",
+                "--io" => "io",
+                "--event-loops" => "event_loops",
+                "-r" | "--root" => "root",
+                "--read-timeout" => "read_timeout",
+                "--write-timeout" => "write_timeout",
+                "--header-timeout" => "header_timeout",
+                "--max-header-size" => "max_header_size",
+                "--max-headers" => "max_headers",
+                "--max-body-size" => "max_body_size",
+                "--log-level" => "log_level",
+                "--access-log" => "access_log",
+                "--access-log-format" => "access_log_format",
+                "--access-log-max-size" => "access_log_max_size",
+                "--access-log-keep" => "access_log_keep",
+                "--access-log-rotate-every" => "access_log_rotate_every",
+                "--metrics-path" => "metrics_path",
+                "--cache-size" => "cache_size",
+                "--cache-ttl" => "cache_ttl",
+                "--compress-min-size" => "compress_min_size",
+                "--rate-limit" => "rate_limit",
+                "--rate-limit-burst" => "rate_limit_burst",
+                "--rate-limit-route" => "rate_limit_route",
+                "--proxy" => "proxy",
+                "--upstream-timeout" => "upstream_timeout",
+                "--health-check-interval" => "health_check_interval",
+                "--sse-heartbeat" => "sse_heartbeat",
+                _ => return Err(ConfigError::UnknownOption(arg.clone())),
+            };
+            let value = match inline_value.or_else(|| args_iterator.next().cloned()) {
+                Some(value) => value,
+                None => return Err(ConfigError::MissingValue(flag.to_string())),
+            };
+            if key == "config" {
+                config_path = Some(PathBuf::from(value));
+            } else {
+                cli.push((key.to_string(), value));
+            }
+        }
+
+        let mut config = Config::default();
+        if let Some(path) = config_path {
+            let contents = fs::read_to_string(&path).map_err(|source| ConfigError::ReadFile {
+                path: path.clone(),
+                source,
+            })?;
+            config.apply(&parse_file(&path, &contents)?)?;
+        }
+        config.apply(&cli)?;
+
+        Ok(config)
+    }
+
+    /// The `ip:port` pairs the server should listen on.
+    pub fn addrs(&self) -> Vec<String> {
+        self.bind
+            .iter()
+            .map(|ip| match ip {
+                IpAddr::V4(ip) => format!("{ip}:{}", self.port),
+                IpAddr::V6(ip) => format!("[{ip}]:{}", self.port),
+            })
+            .collect()
+    }
+
+    /// The limits requests are read under.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            read_timeout: self.read_timeout,
+            header_timeout: self.header_timeout,
+            max_header_bytes: self.max_header_size,
+            max_headers: self.max_headers,
+            max_body_bytes: self.max_body_size,
+        }
+    }
+
+    fn apply(&mut self, settings: &[(String, String)]) -> Result<(), ConfigError> {
+        // Bind addresses and routes from a later source replace the earlier
+        // ones rather than adding to them
+        let mut bind = Vec::new();
+        let mut proxy = Vec::new();
+        let mut rate_limit_routes = Vec::new();
+
+        for (key, value) in settings {
+            let invalid = || ConfigError::InvalidValue {
+                key: key.clone(),
+                value: value.clone(),
+            };
+            match key.as_str() {
+                "bind" => {
+                    for addr in value.split(',').map(str::trim) {
+                        bind.push(addr.parse().map_err(|_| invalid())?);
+                    }
+                }
+                "port" => self.port = value.parse().map_err(|_| invalid())?,
+                "unix" => {
+                    if value.is_empty() {
+                        return Err(invalid());
+                    }
+                    self.unix = Some(PathBuf::from(value));
+                }
+                "workers" => {
+                    self.workers = match value.parse() {
+                        Ok(0) | Err(_) => return Err(invalid()),
+                        Ok(n) => Some(n),
+                    }
+                }
+                "queue_capacity" => {
+                    self.queue_capacity = match value.parse().map_err(|_| invalid())? {
+                        0 => None,
+                        n => Some(n),
+                    }
+                }
+                "io" => {
+                    self.io = match value.as_str() {
+                        "blocking" => IoMode::Blocking,
+                        // Refused up front rather than failing once the server starts
+                        "epoll" if cfg!(target_os = "linux") => IoMode::Epoll,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "event_loops" => {
+                    self.event_loops = match value.parse() {
+                        Ok(0) | Err(_) => return Err(invalid()),
+                        Ok(n) => n,
+                    }
+                }
+                "when_full" => {
+                    self.when_full = match value.as_str() {
+                        "block" => FullPolicy::Block,
+                        "reject" => FullPolicy::Reject,
+                        "drop-oldest" | "drop_oldest" => FullPolicy::DropOldest,
+                        "caller-runs" | "caller_runs" => FullPolicy::CallerRuns,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "root" => {
+                    let root = PathBuf::from(value);
+                    if !root.is_dir() {
+                        return Err(invalid());
+                    }
+                    self.root = root;
+                }
+                "read_timeout" => self.read_timeout = parse_timeout(value).ok_or_else(invalid)?,
+                "write_timeout" => self.write_timeout = parse_timeout(value).ok_or_else(invalid)?,
+                "header_timeout" => {
+                    self.header_timeout = parse_timeout(value).ok_or_else(invalid)?
+                }
+                "max_header_size" => {
+                    self.max_header_size = match value.parse() {
+                        // Not even room for a request line
+                        Ok(n) if n < 16 => return Err(invalid()),
+                        Ok(n) => n,
+                        Err(_) => return Err(invalid()),
+                    }
+                }
+                "max_headers" => self.max_headers = value.parse().map_err(|_| invalid())?,
+                "max_body_size" => self.max_body_size = value.parse().map_err(|_| invalid())?,
+                "log_level" => self.log_level = value.parse().map_err(|_| invalid())?,
+                "access_log" => {
+                    self.access_log = match value.as_str() {
+                        "" => return Err(invalid()),
+                        "-" => AccessLogTarget::Stdout,
+                        "off" => AccessLogTarget::Off,
+                        path => AccessLogTarget::File(PathBuf::from(path)),
+                    }
+                }
+                "access_log_format" => {
+                    self.access_log_format = value.parse().map_err(|_| invalid())?
+                }
+                "access_log_max_size" => {
+                    self.access_log_max_size = value.parse().map_err(|_| invalid())?
+                }
+                "access_log_keep" => self.access_log_keep = value.parse().map_err(|_| invalid())?,
+                "access_log_rotate_every" => {
+                    self.access_log_rotate_every = parse_timeout(value).ok_or_else(invalid)?
+                }
+                "metrics_path" => {
+                    self.metrics_path = match value.as_str() {
+                        "off" => None,
+                        path if path.starts_with('/') => Some(path.to_string()),
+                        _ => return Err(invalid()),
+                    }
+                }
+                "cache_size" => {
+                    self.cache_size = match value.parse().map_err(|_| invalid())? {
+                        0 => None,
+                        n => Some(n),
+                    }
+                }
+                "cache_ttl" => self.cache_ttl = parse_timeout(value).ok_or_else(invalid)?,
+                "compress_min_size" => {
+                    self.compress_min_size = match value.as_str() {
+                        "off" => None,
+                        size => Some(size.parse().map_err(|_| invalid())?),
+                    }
+                }
+                "rate_limit" => {
+                    self.rate_limit = match value.as_str() {
+                        "off" => None,
+                        rate => match rate.parse::<f64>() {
+                            Ok(rate) if rate > 0.0 && rate.is_finite() => Some(rate),
+                            _ => return Err(invalid()),
+                        },
+                    }
+                }
+                "rate_limit_burst" => {
+                    self.rate_limit_burst = match value.parse() {
+                        Ok(0) | Err(_) => return Err(invalid()),
+                        Ok(n) => n,
+                    }
+                }
+                "rate_limit_route" => rate_limit_routes.push(value.parse().map_err(|_| invalid())?),
+                "proxy" => proxy.push(value.parse().map_err(|_| invalid())?),
+                "upstream_timeout" => {
+                    self.upstream_timeout = parse_timeout(value).ok_or_else(invalid)?
+                }
+                "health_check_interval" => {
+                    self.health_check_interval = parse_timeout(value).ok_or_else(invalid)?
+                }
+                "sse_heartbeat" => self.sse_heartbeat = parse_timeout(value).ok_or_else(invalid)?,
+                _ => return Err(ConfigError::UnknownOption(key.clone())),
+            }
+        }
+
+        if !bind.is_empty() {
+            self.bind = bind;
+        }
+        if !proxy.is_empty() {
+            self.proxy = proxy;
+        }
+        if !rate_limit_routes.is_empty() {
+            self.rate_limit_routes = rate_limit_routes;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `key = value` lines, ignoring blank lines and `#` comments.
+fn parse_file(path: &Path, contents: &str) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut settings = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                settings.push((key.trim().replace('-', "_"), value.trim().to_string()));
+            }
+            _ => {
+                return Err(ConfigError::Syntax {
+                    path: path.to_path_buf(),
+                    line: i + 1,
+                })
+            }
+        }
+    }
+
+    Ok(settings)
+}
+
+/// `Some(None)` means the timeout is disabled, `None` means the value didn't parse.
+fn parse_timeout(value: &str) -> Option<Option<Duration>> {
+    match value.parse::<u64>().ok()? {
+        0 => Some(None),
+        secs => Some(Some(Duration::from_secs(secs))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("server")
+            .chain(list.iter().copied())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::build(&args(&[])).unwrap();
+        assert_eq!(vec!["127.0.0.1:7878"], config.addrs());
+        assert_eq!(None, config.workers);
+        assert_eq!(LogLevel::Info, config.log_level);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn epoll_flags() {
+        let config = Config::build(&args(&["--io", "epoll", "--event-loops", "2"])).unwrap();
+        assert_eq!(IoMode::Epoll, config.io);
+        assert_eq!(2, config.event_loops);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[test]
+    fn epoll_needs_linux() {
+        assert!(matches!(
+            Config::build(&args(&["--io", "epoll"])),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn flags() {
+        let config = Config::build(&args(&[
+            "--bind",
+            "0.0.0.0",
+            "-b",
+            "::1",
+            "--port=8080",
+            "-w",
+            "8",
+            "--queue-capacity",
+            "64",
+            "--when-full=reject",
+            "--read-timeout",
+            "0",
+            "--log-level",
+            "debug",
+            "--access-log",
+            "access.log",
+            "--access-log-format",
+            "json",
+            "--metrics-path",
+            "/metrics",
+            "--access-log-rotate-every",
+            "3600",
+            "--proxy",
+            "/api=localhost:3000,localhost:3001",
+            "--proxy=/static=[::1]:8000",
+            "--health-check-interval",
+            "0",
+            "--cache-size",
+            "0",
+            "--cache-ttl=0",
+            "--compress-min-size",
+            "off",
+            "--rate-limit=2.5",
+            "--rate-limit-burst",
+            "5",
+            "--rate-limit-route",
+            "/login=0.1/3",
+            "--sse-heartbeat=0",
+        ]))
+        .unwrap();
+        assert_eq!(vec!["0.0.0.0:8080", "[::1]:8080"], config.addrs());
+        assert_eq!(Some(8), config.workers);
+        assert_eq!(Some(64), config.queue_capacity);
+        assert_eq!(FullPolicy::Reject, config.when_full);
+        assert_eq!(None, config.read_timeout);
+        assert_eq!(LogLevel::Debug, config.log_level);
+        assert_eq!(
+            AccessLogTarget::File(PathBuf::from("access.log")),
+            config.access_log
+        );
+        assert_eq!(LogFormat::Json, config.access_log_format);
+        assert_eq!(Some("/metrics"), config.metrics_path.as_deref());
+        assert_eq!(
+            Some(Duration::from_secs(3600)),
+            config.access_log_rotate_every
+        );
+        let prefixes: Vec<&str> = config.proxy.iter().map(|r| r.prefix.as_str()).collect();
+        assert_eq!(vec!["/api", "/static"], prefixes);
+        assert_eq!(2, config.proxy[0].upstreams.len());
+        assert_eq!(None, config.health_check_interval);
+        assert_eq!((None, None), (config.cache_size, config.cache_ttl));
+        assert_eq!(None, config.compress_min_size);
+        assert_eq!((Some(2.5), 5), (config.rate_limit, config.rate_limit_burst));
+        assert_eq!("/login", config.rate_limit_routes[0].prefix);
+        assert_eq!(None, config.sse_heartbeat);
+    }
+
+    #[test]
+    fn invalid_values() {
+        for bad in [
+            &["--port", "70000"][..],
+            &["--workers", "0"],
+            &["--bind", "localhost"],
+            &["--root", "no/such/dir"],
+            &["--log-level", "loud"],
+            &["--when-full", "panic"],
+            &["--io", "uring"],
+            &["--event-loops", "0"],
+            &["--proxy", "/api=localhost"],
+            &["--rate-limit", "0"],
+            &["--rate-limit-burst", "0"],
+            &["--rate-limit-route", "/login=1"],
+        ] {
+            assert!(matches!(
+                Config::build(&args(bad)),
+                Err(ConfigError::InvalidValue { .. })
+            ));
+        }
+        assert!(matches!(
+            Config::build(&args(&["--port"])),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(
+            Config::build(&args(&["--nope"])),
+            Err(ConfigError::UnknownOption(_))
+        ));
+    }
+
+    #[test]
+    fn file_then_flags() {
+        let path = PathBuf::from("server.conf");
+        let contents = "\
+# comment
+bind = 0.0.0.0, ::
+port = 9000
+write-timeout = 5
+proxy = /api=localhost:3000
+";
+        let mut config = Config::default();
+        config.apply(&parse_file(&path, contents).unwrap()).unwrap();
+        config
+            .apply(&[("port".to_string(), "9001".to_string())])
+            .unwrap();
+        assert_eq!(vec!["0.0.0.0:9001", "[::]:9001"], config.addrs());
+        assert_eq!(Some(Duration::from_secs(5)), config.write_timeout);
+        config
+            .apply(&[("proxy".to_string(), "/v2=localhost:4000".to_string())])
+            .unwrap();
+        assert_eq!(1, config.proxy.len());
+        assert_eq!("/v2", config.proxy[0].prefix);
+
+        assert!(matches!(
+            parse_file(&path, "port 9000"),
+            Err(ConfigError::Syntax { line: 1, .. })
+        ));
+    }
+}
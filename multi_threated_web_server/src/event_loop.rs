@@ -0,0 +1,828 @@
+use crate::{
+    access_log::AccessLog,
+    config::{Config, LogLevel},
+    http::{self, Request, Response, Upgrade},
+    server::{self, Services},
+    CancellationToken, ThreadPool,
+};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        raw::c_int,
+        unix::net::UnixStream,
+    },
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Longest the loop waits for events, and so the most a timeout can be overdue by.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Most events taken from the kernel in one wait.
+const MAX_EVENTS: usize = 256;
+
+/// Epoll tokens of the listener and the waker, connections count up from after them.
+const LISTENER: u64 = 0;
+const WAKER: u64 = 1;
+
+/// The bits of `<sys/epoll.h>` we need, declared by hand as there is no libc crate.
+mod sys {
+    use std::os::raw::c_int;
+
+    pub const EPOLL_CLOEXEC: c_int = 0o2_000_000;
+    pub const EPOLL_CTL_ADD: c_int = 1;
+    pub const EPOLL_CTL_DEL: c_int = 2;
+    pub const EPOLL_CTL_MOD: c_int = 3;
+
+    pub const EPOLLIN: u32 = 0x001;
+    pub const EPOLLOUT: u32 = 0x004;
+    pub const EPOLLERR: u32 = 0x008;
+    pub const EPOLLHUP: u32 = 0x010;
+    pub const EPOLLRDHUP: u32 = 0x2000;
+    pub const EPOLLEXCLUSIVE: u32 = 1 << 28;
+
+    /// The kernel packs this struct on x86-64 only.
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    #[cfg_attr(target_arch = "x86_64", repr(packed))]
+    pub struct EpollEvent {
+        pub events: u32,
+        pub data: u64,
+    }
+
+    extern "C" {
+        pub fn epoll_create1(flags: c_int) -> c_int;
+        pub fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+        pub fn epoll_wait(
+            epfd: c_int,
+            events: *mut EpollEvent,
+            maxevents: c_int,
+            timeout: c_int,
+        ) -> c_int;
+    }
+}
+
+use sys::EpollEvent;
+
+/// An epoll instance, closed on drop.
+struct Epoll {
+    fd: OwnedFd,
+}
+
+impl Epoll {
+    fn new() -> io::Result<Epoll> {
+        // SAFETY: takes no pointers, and the result is checked before being used as a descriptor
+        let fd = unsafe { sys::epoll_create1(sys::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just created and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(Epoll { fd })
+    }
+
+    fn ctl(&self, op: c_int, fd: &impl AsRawFd, token: u64, events: u32) -> io::Result<()> {
+        let mut event = EpollEvent {
+            events,
+            data: token,
+        };
+        // SAFETY: `event` is valid for the call, the kernel copies it and keeps no pointer
+        let result = unsafe { sys::epoll_ctl(self.fd.as_raw_fd(), op, fd.as_raw_fd(), &mut event) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn add(&self, fd: &impl AsRawFd, token: u64, events: u32) -> io::Result<()> {
+        self.ctl(sys::EPOLL_CTL_ADD, fd, token, events)
+    }
+
+    fn modify(&self, fd: &impl AsRawFd, token: u64, events: u32) -> io::Result<()> {
+        self.ctl(sys::EPOLL_CTL_MOD, fd, token, events)
+    }
+
+    fn delete(&self, fd: &impl AsRawFd) -> io::Result<()> {
+        self.ctl(sys::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    /// Waits up to `timeout` for events, returning how many were written to the
+    /// front of `events`. An interrupted wait returns none.
+    fn wait(&self, events: &mut [EpollEvent], timeout: Duration) -> io::Result<usize> {
+        let timeout = c_int::try_from(timeout.as_millis()).unwrap_or(c_int::MAX);
+        let max = c_int::try_from(events.len()).unwrap_or(c_int::MAX);
+        // SAFETY: the kernel writes at most `max` events, which fit in the slice
+        let n = unsafe { sys::epoll_wait(self.fd.as_raw_fd(), events.as_mut_ptr(), max, timeout) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        Ok(n as usize)
+    }
+}
+
+/// What a handler sends back for a connection: the response to write and
+/// whatever takes the connection over after it, or `None` to hang up without one.
+type Reply = (u64, Option<(Vec<u8>, Option<Upgrade>)>);
+
+/// Hands a handler's reply back to its event loop and wakes it up.
+///
+/// Dropped without sending, because the pool dropped the job before running
+/// it, the client gets a 503 the same way [`server::serve`] would answer it.
+struct Responder {
+    token: u64,
+    replies: mpsc::Sender<Reply>,
+    waker: Arc<UnixStream>,
+    sent: bool,
+}
+
+impl Responder {
+    fn send(mut self, reply: Option<(Vec<u8>, Option<Upgrade>)>) {
+        self.sent = true;
+        self.deliver(reply);
+    }
+
+    fn deliver(&self, reply: Option<(Vec<u8>, Option<Upgrade>)>) {
+        // A loop that's gone has nobody left to answer
+        if self.replies.send((self.token, reply)).is_ok() {
+            // A full socket means a wakeup is already pending
+            let _ = (&*self.waker).write(&[1]);
+        }
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.deliver(
+                render(&mut server::unavailable(), false)
+                    .ok()
+                    .map(|(out, _)| (out, None)),
+            );
+        }
+    }
+}
+
+/// Writes out a whole response, returning it along with the size of its body.
+fn render(response: &mut Response, chunked: bool) -> io::Result<(Vec<u8>, u64)> {
+    let mut out = Vec::new();
+    let bytes = response.write_to(&mut out, chunked)?;
+    Ok((out, bytes))
+}
+
+enum State {
+    /// Waiting for the rest of the request head
+    Reading { buf: Vec<u8> },
+    /// The request is with the pool. The connection being reset cancels its
+    /// handler, while a client that only shut down its sending side is still
+    /// answered.
+    /// Anything sent after the request is kept for a protocol it may switch to.
+    Handling {
+        cancel: CancellationToken,
+        rest: Vec<u8>,
+    },
+    /// Sending the response, after which the connection is closed
+    Writing { out: Vec<u8>, written: usize },
+}
+
+struct Conn {
+    stream: TcpStream,
+    client: Option<SocketAddr>,
+    accepted: Instant,
+    /// Last time a read or write made progress
+    active: Instant,
+    state: State,
+}
+
+/// Serves connections from `listener` on the calling thread, never returning
+/// unless epoll itself fails. See [`serve_until`] for one that can be stopped.
+///
+/// Sockets are non-blocking and multiplexed with epoll, so a connection only
+/// takes up a worker while its handler runs: the request head is read and the
+/// response written here. Several threads may serve the same listener, each
+/// accepts its own share of the connections.
+///
+/// Responses are rendered in full before being written, which suits the small
+/// pages this server has. A client whose connection is reset while its request
+/// is queued or its handler is running has the job cancelled, see
+/// [`server::respond`].
+///
+/// # Errors
+///
+/// Returns Err() if epoll can't be set up or waited on.
+pub fn serve(
+    listener: &TcpListener,
+    pool: &ThreadPool,
+    config: &Arc<Config>,
+    services: &Services,
+    access_log: &Arc<Option<AccessLog>>,
+) -> io::Result<()> {
+    serve_until(
+        listener,
+        pool,
+        config,
+        services,
+        access_log,
+        &AtomicBool::new(false),
+    )
+}
+
+/// Like [`serve`], returning within a tick of `stop` being set. Connections
+/// still open then are dropped.
+///
+/// # Errors
+///
+/// Returns Err() if epoll can't be set up or waited on.
+pub fn serve_until(
+    listener: &TcpListener,
+    pool: &ThreadPool,
+    config: &Arc<Config>,
+    services: &Services,
+    access_log: &Arc<Option<AccessLog>>,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    EventLoop::new(listener, pool, config, services, access_log)?.run(stop)
+}
+
+struct EventLoop<'a> {
+    epoll: Epoll,
+    listener: &'a TcpListener,
+    pool: &'a ThreadPool,
+    config: &'a Arc<Config>,
+    services: &'a Services,
+    access_log: &'a Arc<Option<AccessLog>>,
+    conns: HashMap<u64, Conn>,
+    /// Never reused, so a reply for a connection that has since closed can't
+    /// reach a newer one
+    next_token: u64,
+    waker: UnixStream,
+    wake: Arc<UnixStream>,
+    replies: mpsc::Receiver<Reply>,
+    reply: mpsc::Sender<Reply>,
+}
+
+impl<'a> EventLoop<'a> {
+    fn new(
+        listener: &'a TcpListener,
+        pool: &'a ThreadPool,
+        config: &'a Arc<Config>,
+        services: &'a Services,
+        access_log: &'a Arc<Option<AccessLog>>,
+    ) -> io::Result<EventLoop<'a>> {
+        let epoll = Epoll::new()?;
+        listener.set_nonblocking(true)?;
+        // Only wake one of the loops sharing the listener for each connection
+        epoll.add(listener, LISTENER, sys::EPOLLIN | sys::EPOLLEXCLUSIVE)?;
+
+        let (waker, wake) = UnixStream::pair()?;
+        waker.set_nonblocking(true)?;
+        wake.set_nonblocking(true)?;
+        epoll.add(&waker, WAKER, sys::EPOLLIN)?;
+
+        let (reply, replies) = mpsc::channel();
+        Ok(EventLoop {
+            epoll,
+            listener,
+            pool,
+            config,
+            services,
+            access_log,
+            conns: HashMap::new(),
+            next_token: WAKER + 1,
+            waker,
+            wake: Arc::new(wake),
+            replies,
+            reply,
+        })
+    }
+
+    fn run(mut self, stop: &AtomicBool) -> io::Result<()> {
+        let mut events = [EpollEvent { events: 0, data: 0 }; MAX_EVENTS];
+        let mut swept = Instant::now();
+        while !stop.load(Ordering::SeqCst) {
+            let n = self.epoll.wait(&mut events, TICK)?;
+            for event in &events[..n] {
+                // Copied out as the struct may be packed
+                let (token, flags) = (event.data, event.events);
+                match token {
+                    LISTENER => self.accept(),
+                    WAKER => self.take_replies(),
+                    token => self.ready(token, flags),
+                }
+            }
+            if swept.elapsed() >= TICK {
+                self.expire();
+                swept = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let (stream, client) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    if self.config.log_level >= LogLevel::Warn {
+                        eprintln!("Failed to accept connection: {err}");
+                    }
+                    return;
+                }
+            };
+            let token = self.next_token;
+            let registered = stream.set_nonblocking(true).and_then(|()| {
+                self.epoll
+                    .add(&stream, token, sys::EPOLLIN | sys::EPOLLRDHUP)
+            });
+            if let Err(err) = registered {
+                if self.config.log_level >= LogLevel::Warn {
+                    eprintln!("Failed to set up connection: {err}");
+                }
+                continue;
+            }
+            self.next_token += 1;
+            let now = Instant::now();
+            self.conns.insert(
+                token,
+                Conn {
+                    stream,
+                    client: Some(client),
+                    accepted: now,
+                    active: now,
+                    state: State::Reading { buf: Vec::new() },
+                },
+            );
+        }
+    }
+
+    fn ready(&mut self, token: u64, flags: u32) {
+        let Some(conn) = self.conns.get(&token) else {
+            return;
+        };
+        // A client may shut down its side once the request is sent and still
+        // read the response, only a reset means it's gone
+        let gone = flags & (sys::EPOLLHUP | sys::EPOLLERR) != 0;
+        match &conn.state {
+            // A hang-up still leaves whatever was sent before it to read
+            State::Reading { .. } => self.read(token),
+            State::Handling { cancel, .. } if gone => {
+                cancel.cancel();
+                self.close(token);
+            }
+            State::Handling { .. } => {}
+            State::Writing { .. } => self.write(token),
+        }
+    }
+
+    fn read(&mut self, token: u64) {
+        let limits = self.config.limits();
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        let State::Reading { buf } = &mut conn.state else {
+            return;
+        };
+
+        let mut chunk = [0; 4096];
+        let mut eof = false;
+        // Past the limits the parser has all it needs to turn the request down
+        while buf.len() <= limits.max_header_bytes + limits.max_body_bytes {
+            match conn.stream.read(&mut chunk) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(n) => {
+                    buf.extend_from_slice(&chunk[..n]);
+                    conn.active = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return self.close(token),
+            }
+        }
+
+        match http::parse_request(buf, &limits) {
+            Ok(Some((request, used))) => {
+                let rest = buf.split_off(used);
+                self.dispatch(token, request, rest);
+            }
+            // Hung up before sending a whole request, nobody to answer
+            Ok(None) if eof => self.close(token),
+            Ok(None) => {}
+            Err(err) => match err.status() {
+                Some(status) => self.fail(token, status),
+                None => self.close(token),
+            },
+        }
+    }
+
+    /// Queues the request's handler on the pool, whose reply comes back
+    /// through [`take_replies`](EventLoop::take_replies).
+    fn dispatch(&mut self, token: u64, mut request: Request, rest: Vec<u8>) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        let cancel = CancellationToken::new();
+        conn.state = State::Handling {
+            cancel: cancel.clone(),
+            rest,
+        };
+        // Hang-ups and errors are reported without asking, which is all that's
+        // wanted until there's a response to write
+        if self.epoll.modify(&conn.stream, token, 0).is_err() {
+            return self.close(token);
+        }
+
+        let (client, started) = (conn.client, conn.accepted);
+        request.client = client;
+        let responder = Responder {
+            token,
+            replies: self.reply.clone(),
+            waker: Arc::clone(&self.wake),
+            sent: false,
+        };
+        let config = Arc::clone(self.config);
+        let access_log = Arc::clone(self.access_log);
+        let services = self.services.clone();
+        let job = {
+            let cancel = cancel.clone();
+            move || {
+                let mut request = request;
+                // HTTP/1.0 clients can't decode chunked bodies
+                let chunked = request.version != "HTTP/1.0";
+                let result = server::respond(&mut request, &config, &services, Some(&cancel))
+                    .and_then(|mut response| {
+                        let status = response.status;
+                        render(&mut response, chunked)
+                            .map(|(out, bytes)| (out, response.upgrade, status, bytes))
+                    });
+                match result {
+                    Ok((out, upgrade, status, bytes)) => {
+                        if let Some(access_log) = access_log.as_ref() {
+                            let entry =
+                                server::access_entry(Some(request), client, status, bytes, started);
+                            access_log.log(&entry);
+                        }
+                        responder.send(Some((out, upgrade)));
+                    }
+                    Err(err) => {
+                        if config.log_level >= LogLevel::Warn && !cancel.is_cancelled() {
+                            eprintln!("Failed to handle connection: {err}");
+                        }
+                        responder.send(None);
+                    }
+                }
+            }
+        };
+        // Waiting for room or running the handler here would stall every
+        // connection on the loop, so a full queue turns the client away. The
+        // rejected job's responder has already queued a 503.
+        if let Err(err) = self.pool.job().cancel_token(cancel).try_execute(job) {
+            if self.config.log_level >= LogLevel::Debug {
+                eprintln!("Turned a connection away: {err}");
+            }
+        }
+    }
+
+    fn take_replies(&mut self) {
+        // Drain the wakeups first, anything sent after that wakes us again
+        let mut drained = [0; 64];
+        while matches!((&self.waker).read(&mut drained), Ok(n) if n > 0) {}
+
+        while let Ok((token, reply)) = self.replies.try_recv() {
+            let handling = self
+                .conns
+                .get(&token)
+                .is_some_and(|conn| matches!(conn.state, State::Handling { .. }));
+            match reply {
+                Some((out, Some(upgrade))) if handling => self.hand_over(token, out, upgrade),
+                Some((out, None)) if handling => self.respond(token, out),
+                Some(_) => {}
+                None => self.close(token),
+            }
+        }
+    }
+
+    /// Answers a request head that couldn't be read with `status` and hangs up.
+    fn fail(&mut self, token: u64, status: u16) {
+        let Some(conn) = self.conns.get(&token) else {
+            return;
+        };
+        if let Some(access_log) = self.access_log.as_ref() {
+            let entry = server::access_entry(None, conn.client, status, 0, conn.accepted);
+            access_log.log(&entry);
+        }
+        match render(&mut server::bad_request(status), false) {
+            Ok((out, _)) => self.respond(token, out),
+            Err(_) => self.close(token),
+        }
+    }
+
+    fn respond(&mut self, token: u64, out: Vec<u8>) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        conn.state = State::Writing { out, written: 0 };
+        conn.active = Instant::now();
+        self.write(token);
+    }
+
+    /// Gives a connection switching protocols to its new owner, who writes
+    /// the response itself, with a blocking socket the loop no longer watches.
+    fn hand_over(&mut self, token: u64, out: Vec<u8>, upgrade: Upgrade) {
+        let Some(conn) = self.conns.remove(&token) else {
+            return;
+        };
+        let _ = self.epoll.delete(&conn.stream);
+        let State::Handling { rest, .. } = conn.state else {
+            return;
+        };
+        let stream = conn.stream;
+        let upgrade: Upgrade = Box::new(move |mut stream, rest| {
+            if stream.write_all(&out).and_then(|()| stream.flush()).is_ok() {
+                upgrade(stream, rest);
+            }
+        });
+        let handed = stream
+            .set_nonblocking(false)
+            .and_then(|()| stream.set_write_timeout(self.config.write_timeout))
+            .and_then(|()| server::hand_over(upgrade, Box::new(stream), rest));
+        if let Err(err) = handed {
+            if self.config.log_level >= LogLevel::Warn {
+                eprintln!("Failed to hand over connection: {err}");
+            }
+        }
+    }
+
+    fn write(&mut self, token: u64) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        let State::Writing { out, written } = &mut conn.state else {
+            return;
+        };
+        while *written < out.len() {
+            match conn.stream.write(&out[*written..]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    *written += n;
+                    conn.active = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    // Pick up where we left off once the socket has room again
+                    if self
+                        .epoll
+                        .modify(&conn.stream, token, sys::EPOLLOUT)
+                        .is_ok()
+                    {
+                        return;
+                    }
+                    break;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        // One request per connection, like the blocking mode
+        self.close(token);
+    }
+
+    fn close(&mut self, token: u64) {
+        if let Some(conn) = self.conns.remove(&token) {
+            // Closing the socket removes it too, unless a handler holds a duplicate
+            let _ = self.epoll.delete(&conn.stream);
+        }
+    }
+
+    /// Answers clients too slow to send their request with a 408, and hangs
+    /// up on those too slow to take their response.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let config = self.config;
+        let overdue = |since: Instant, timeout: Option<Duration>| {
+            timeout.is_some_and(|timeout| now.duration_since(since) >= timeout)
+        };
+
+        let mut slow_readers = Vec::new();
+        let mut slow_writers = Vec::new();
+        for (&token, conn) in &self.conns {
+            match conn.state {
+                State::Reading { .. }
+                    if overdue(conn.accepted, config.header_timeout)
+                        || overdue(conn.active, config.read_timeout) =>
+                {
+                    slow_readers.push(token)
+                }
+                State::Writing { .. } if overdue(conn.active, config.write_timeout) => {
+                    slow_writers.push(token)
+                }
+                _ => {}
+            }
+        }
+        for token in slow_readers {
+            self.fail(token, 408);
+        }
+        for token in slow_writers {
+            self.close(token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FullPolicy, ThreadPoolBuilder};
+    use std::{net::Shutdown, thread};
+
+    /// Starts an event loop in the background, serving with a pool of `workers`.
+    fn start(config: Config, workers: usize, services: Services) -> SocketAddr {
+        start_with(config, ThreadPool::build(workers).unwrap(), services)
+    }
+
+    fn start_with(config: Config, pool: ThreadPool, services: Services) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let services = Services {
+                pool: Some(pool.monitor()),
+                ..services
+            };
+            serve(
+                &listener,
+                &pool,
+                &Arc::new(config),
+                &services,
+                &Arc::new(None),
+            )
+            .unwrap();
+        });
+        addr
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut client = TcpStream::connect(addr).unwrap();
+        write!(client, "GET {path} HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_requests() {
+        let addr = start(Config::default(), 2, Services::default());
+        let response = get(addr, "/");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("0\r\n\r\n"));
+        assert!(get(addr, "/nope").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn idle_connections_hold_no_workers() {
+        let config = Config {
+            header_timeout: Some(Duration::from_millis(500)),
+            ..Config::default()
+        };
+        let addr = start(config, 1, Services::default());
+
+        // Far more idle connections than workers, which would stall a blocking server
+        let mut idle: Vec<TcpStream> = (0..32).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        idle[0].write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        assert!(get(addr, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(started.elapsed() < Duration::from_millis(400));
+
+        for mut stream in idle {
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        }
+    }
+
+    #[test]
+    fn hands_over_websockets() {
+        let greet: crate::websocket::Handler = Arc::new(|mut socket| {
+            socket.send_text("hello").unwrap();
+        });
+        let services = Services {
+            websockets: Arc::new(HashMap::from([("/ws".to_string(), greet)])),
+            ..Services::default()
+        };
+        let addr = start(Config::default(), 1, services);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.ends_with(b"\r\n\r\n\x81\x05hello"));
+
+        // The worker is free again while the socket is with its handler
+        assert!(get(addr, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    /// Closes `stream` with a reset rather than a FIN, by lingering for 0 seconds.
+    fn reset(stream: TcpStream) {
+        extern "C" {
+            fn setsockopt(
+                fd: c_int,
+                level: c_int,
+                name: c_int,
+                value: *const c_int,
+                len: u32,
+            ) -> c_int;
+        }
+        const SOL_SOCKET: c_int = 1;
+        const SO_LINGER: c_int = 13;
+        // `struct linger` is two ints, on and the seconds
+        let linger: [c_int; 2] = [1, 0];
+        // SAFETY: `linger` is valid for the call and the kernel copies it
+        let result = unsafe {
+            setsockopt(
+                stream.as_raw_fd(),
+                SOL_SOCKET,
+                SO_LINGER,
+                linger.as_ptr(),
+                std::mem::size_of_val(&linger) as u32,
+            )
+        };
+        assert_eq!(0, result, "{}", io::Error::last_os_error());
+    }
+
+    #[test]
+    fn client_leaving_cancels_its_handler() {
+        let addr = start(Config::default(), 1, Services::default());
+        let mut leaving = TcpStream::connect(addr).unwrap();
+        leaving.write_all(b"GET /sleep HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        reset(leaving);
+
+        // The only worker is freed long before the sleep would have ended
+        let started = Instant::now();
+        assert!(get(addr, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn full_queue_never_stalls_the_loop() {
+        for policy in [FullPolicy::Block, FullPolicy::CallerRuns] {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(1)
+                .queue_capacity(1)
+                .when_full(policy)
+                .build()
+                .unwrap();
+            let addr = start_with(Config::default(), pool, Services::default());
+
+            // One sleep with the worker and one waiting fill the pool
+            let mut sleepers = Vec::new();
+            for _ in 0..2 {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client.write_all(b"GET /sleep HTTP/1.1\r\n\r\n").unwrap();
+                sleepers.push(client);
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            // Turned away at once, rather than waited on or slept through here
+            for _ in 0..2 {
+                let started = Instant::now();
+                let response = get(addr, "/sleep");
+                assert!(
+                    response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+                    "{policy:?}: {response:?}"
+                );
+                assert!(started.elapsed() < Duration::from_secs(1), "{policy:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn half_closed_clients_are_answered() {
+        let addr = start(Config::default(), 1, Services::default());
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /sleep HTTP/1.1\r\n\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response:?}");
+    }
+}
@@ -0,0 +1,897 @@
+use crate::{
+    access_log::{self, MONTHS},
+    transport::Transport,
+};
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Size of the chunks files are streamed in.
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    /// As announced by `Content-Length`, empty without one
+    pub body: Vec<u8>,
+    /// Filled in by whoever accepted the connection, parsing leaves it `None`
+    pub client: Option<SocketAddr>,
+}
+
+impl Request {
+    /// A request as parsed from `method`, `path` and `headers`, for tests.
+    #[cfg(test)]
+    pub(crate) fn new(method: &str, path: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            body: Vec::new(),
+            client: None,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Bounds on reading a request head, so a slow or hostile client can't hold a worker.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Timeout for each individual read
+    pub read_timeout: Option<Duration>,
+    /// Deadline for the whole request line and headers, however steadily they trickle in
+    pub header_timeout: Option<Duration>,
+    /// Most bytes the request line and headers may take together
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+    pub max_body_bytes: usize,
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// The client hung up before sending a full request.
+    Closed,
+    TimedOut,
+    HeadersTooLarge,
+    TooManyHeaders,
+    BodyTooLarge,
+    /// The body isn't framed by `Content-Length`, the only framing we read.
+    LengthRequired,
+    Malformed,
+    Io(io::Error),
+}
+
+impl RequestError {
+    /// The status to answer with, `None` when there is nobody to answer.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            RequestError::Closed | RequestError::Io(_) => None,
+            RequestError::TimedOut => Some(408),
+            RequestError::HeadersTooLarge | RequestError::TooManyHeaders => Some(431),
+            RequestError::BodyTooLarge => Some(413),
+            RequestError::LengthRequired => Some(411),
+            RequestError::Malformed => Some(400),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Closed => write!(f, "Connection closed before the request was complete."),
+            RequestError::TimedOut => write!(f, "Timed out reading the request."),
+            RequestError::HeadersTooLarge => write!(f, "Request headers are too large."),
+            RequestError::TooManyHeaders => write!(f, "Request has too many headers."),
+            RequestError::BodyTooLarge => write!(f, "Request body is too large."),
+            RequestError::LengthRequired => write!(f, "Request body has no Content-Length."),
+            RequestError::Malformed => write!(f, "Malformed request."),
+            RequestError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+/// Reads the request line, headers and body.
+///
+/// # Errors
+///
+/// Returns Err() if the client hangs up, is too slow, or sends a request that
+/// is malformed or over the `limits`.
+pub fn read_request<T: Transport>(
+    reader: &mut BufReader<T>,
+    limits: &Limits,
+) -> Result<Request, RequestError> {
+    let deadline = limits
+        .header_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let mut budget = limits.max_header_bytes;
+
+    let request_line = match read_line(reader, limits, deadline, &mut budget)? {
+        Some(line) => line,
+        None => return Err(RequestError::Closed),
+    };
+    let mut request = parse_request_line(&request_line)?;
+
+    loop {
+        let line = match read_line(reader, limits, deadline, &mut budget)? {
+            Some(line) => line,
+            None => return Err(RequestError::Closed),
+        };
+        if line.is_empty() {
+            break;
+        }
+        if request.headers.len() == limits.max_headers {
+            return Err(RequestError::TooManyHeaders);
+        }
+        request.headers.push(parse_header(&line)?);
+    }
+
+    let len = body_length(&request, limits)?;
+    if len > 0 {
+        request.body = read_body(reader, limits, len)?;
+    }
+    Ok(request)
+}
+
+/// The length of the body the head announces.
+fn body_length(request: &Request, limits: &Limits) -> Result<usize, RequestError> {
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(RequestError::LengthRequired);
+    }
+    let len = match request.header("Content-Length") {
+        Some(len) => len.parse().map_err(|_| RequestError::Malformed)?,
+        None => 0,
+    };
+    if len > limits.max_body_bytes {
+        return Err(RequestError::BodyTooLarge);
+    }
+    Ok(len)
+}
+
+/// Reads a body of `len` bytes, each read bounded by the read timeout only as
+/// the header deadline is for the head alone.
+fn read_body<T: Transport>(
+    reader: &mut BufReader<T>,
+    limits: &Limits,
+    len: usize,
+) -> Result<Vec<u8>, RequestError> {
+    reader
+        .get_ref()
+        .set_read_timeout(limits.read_timeout)
+        .map_err(RequestError::Io)?;
+    let mut body = vec![0; len];
+    match reader.read_exact(&mut body) {
+        Ok(()) => Ok(body),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Err(RequestError::TimedOut)
+        }
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Err(RequestError::Closed),
+        Err(err) => Err(RequestError::Io(err)),
+    }
+}
+
+/// Parses a request from the start of `buf`, for callers doing their own
+/// non-blocking reads. Returns `Ok(None)` while the request is incomplete, or
+/// the request along with the number of bytes it took up.
+///
+/// Timeouts are left to the caller, the other `limits` are checked the same
+/// way [`read_request`] checks them.
+///
+/// # Errors
+///
+/// Returns Err() if the head is malformed or over the `limits`.
+pub fn parse_request(
+    buf: &[u8],
+    limits: &Limits,
+) -> Result<Option<(Request, usize)>, RequestError> {
+    let Some((line, mut start)) = head_line(buf, 0, limits)? else {
+        return Ok(None);
+    };
+    let mut request = parse_request_line(line)?;
+
+    let head = loop {
+        let Some((line, end)) = head_line(buf, start, limits)? else {
+            return Ok(None);
+        };
+        start = end;
+        if line.is_empty() {
+            break end;
+        }
+        if request.headers.len() == limits.max_headers {
+            return Err(RequestError::TooManyHeaders);
+        }
+        request.headers.push(parse_header(line)?);
+    };
+
+    let end = head + body_length(&request, limits)?;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    request.body = buf[head..end].to_vec();
+    Ok(Some((request, end)))
+}
+
+/// The line of the head starting at `start` in `buf`, without its line
+/// ending, and where the next one starts. `None` while it's incomplete.
+fn head_line<'a>(
+    buf: &'a [u8],
+    start: usize,
+    limits: &Limits,
+) -> Result<Option<(&'a str, usize)>, RequestError> {
+    let end = match buf[start..].iter().position(|&b| b == b'\n') {
+        Some(i) => start + i + 1,
+        None if buf.len() > limits.max_header_bytes => return Err(RequestError::HeadersTooLarge),
+        None => return Ok(None),
+    };
+    if end > limits.max_header_bytes {
+        return Err(RequestError::HeadersTooLarge);
+    }
+    let line = buf[start..end - 1]
+        .strip_suffix(b"\r")
+        .unwrap_or(&buf[start..end - 1]);
+    let line = std::str::from_utf8(line).map_err(|_| RequestError::Malformed)?;
+    Ok(Some((line, end)))
+}
+
+/// Splits a request line into a [`Request`] without headers yet.
+fn parse_request_line(line: &str) -> Result<Request, RequestError> {
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version), None) if version.starts_with("HTTP/") => {
+            Ok(Request {
+                method: method.to_string(),
+                path: path.to_string(),
+                version: version.to_string(),
+                headers: Vec::new(),
+                body: Vec::new(),
+                client: None,
+            })
+        }
+        _ => Err(RequestError::Malformed),
+    }
+}
+
+fn parse_header(line: &str) -> Result<(String, String), RequestError> {
+    match line.split_once(':') {
+        Some((name, value)) if !name.is_empty() && !name.ends_with(' ') => {
+            Ok((name.to_string(), value.trim().to_string()))
+        }
+        _ => Err(RequestError::Malformed),
+    }
+}
+
+/// Reads one CRLF (or bare LF) terminated line, `None` on a clean EOF before any bytes.
+fn read_line<T: Transport>(
+    reader: &mut BufReader<T>,
+    limits: &Limits,
+    deadline: Option<Instant>,
+    budget: &mut usize,
+) -> Result<Option<String>, RequestError> {
+    let mut line = Vec::new();
+
+    loop {
+        // Each read may wait for the read timeout, but never past the header deadline
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(RequestError::TimedOut);
+                }
+                Some(
+                    limits
+                        .read_timeout
+                        .map_or(left, |timeout| timeout.min(left)),
+                )
+            }
+            None => limits.read_timeout,
+        };
+        reader
+            .get_ref()
+            .set_read_timeout(timeout)
+            .map_err(RequestError::Io)?;
+
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(RequestError::TimedOut)
+            }
+            Err(err) => return Err(RequestError::Io(err)),
+        };
+        if available.is_empty() {
+            return if line.is_empty() {
+                Ok(None)
+            } else {
+                Err(RequestError::Closed)
+            };
+        }
+
+        let (used, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (available.len(), false),
+        };
+        if used > *budget {
+            return Err(RequestError::HeadersTooLarge);
+        }
+        *budget -= used;
+        line.extend_from_slice(&available[..used]);
+        reader.consume(used);
+
+        if done {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return String::from_utf8(line)
+                .map(Some)
+                .map_err(|_| RequestError::Malformed);
+        }
+    }
+}
+
+pub type Chunks = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>;
+pub type BodyWriter = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+/// Takes over the connection once the response is out, along with whatever
+/// the client sent after its request.
+pub type Upgrade = Box<dyn FnOnce(Box<dyn Transport + Send>, Vec<u8>) + Send>;
+
+pub enum Body {
+    Empty,
+    /// Sent in one go with a `Content-Length`
+    Full(Vec<u8>),
+    /// Sent with `Transfer-Encoding: chunked`, one chunk per item, as the iterator yields them
+    Stream(Chunks),
+    /// Sent with `Transfer-Encoding: chunked`, every write the closure makes becomes a chunk
+    Writer(BodyWriter),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => write!(f, "Empty"),
+            Body::Full(bytes) => write!(f, "Full({} bytes)", bytes.len()),
+            Body::Stream(_) => write!(f, "Stream"),
+            Body::Writer(_) => write!(f, "Writer"),
+        }
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    /// The connection is handed to it instead of being closed, for
+    /// `101 Switching Protocols` or a body its new owner writes itself
+    pub upgrade: Option<Upgrade>,
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &self.body)
+            .field("upgrade", &self.upgrade.is_some())
+            .finish()
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+            upgrade: None,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Full(body.into());
+        self
+    }
+
+    /// Streams the body from an iterator of chunks instead of buffering it.
+    pub fn with_stream<I>(mut self, chunks: I) -> Response
+    where
+        I: IntoIterator<Item = io::Result<Vec<u8>>>,
+        I::IntoIter: Send + 'static,
+    {
+        self.body = Body::Stream(Box::new(chunks.into_iter()));
+        self
+    }
+
+    /// Streams the body by handing the closure a writer once the headers are out.
+    pub fn with_writer<F>(mut self, f: F) -> Response
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        self.body = Body::Writer(Box::new(f));
+        self
+    }
+
+    /// Hands the connection to `f` once the response head has been written,
+    /// for `101 Switching Protocols` or to write a body of unknown length
+    /// until the connection closes.
+    pub fn with_upgrade<F>(mut self, f: F) -> Response
+    where
+        F: FnOnce(Box<dyn Transport + Send>, Vec<u8>) + Send + 'static,
+    {
+        self.upgrade = Some(Box::new(f));
+        self
+    }
+
+    /// A response streaming the file at `path` in fixed size chunks.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the file can't be opened.
+    pub fn file(status: u16, path: impl AsRef<Path>) -> io::Result<Response> {
+        Ok(Response::new(status).with_file(File::open(path)?))
+    }
+
+    /// Streams the body from an already open file in fixed size chunks.
+    pub fn with_file(self, file: File) -> Response {
+        self.with_stream(FileChunks { file })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Writes the status line, headers and body, returning the number of body bytes sent.
+    /// The upgrade, if any, is left for the caller to hand the connection to.
+    ///
+    /// Clients that don't understand chunked encoding (`chunked` is false, as
+    /// for HTTP/1.0) get streamed bodies unframed and the end of the body is
+    /// marked by closing the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if writing to `stream` fails or a streamed body yields an error.
+    pub fn write_to<W: Write>(&mut self, stream: &mut W, chunked: bool) -> io::Result<u64> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        // Informational and 204 responses can't have a body, so say nothing
+        // about one, and neither can we for a body someone else writes
+        let bodiless = self.status < 200 || self.status == 204 || self.upgrade.is_some();
+        match &self.body {
+            Body::Empty if bodiless => {}
+            Body::Empty => head.push_str("Content-Length: 0\r\n"),
+            Body::Full(bytes) => head.push_str(&format!("Content-Length: {}\r\n", bytes.len())),
+            Body::Stream(_) | Body::Writer(_) if chunked => {
+                head.push_str("Transfer-Encoding: chunked\r\n")
+            }
+            Body::Stream(_) | Body::Writer(_) => head.push_str("Connection: close\r\n"),
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+
+        let sent = match mem::replace(&mut self.body, Body::Empty) {
+            Body::Empty => 0,
+            Body::Full(bytes) => {
+                stream.write_all(&bytes)?;
+                bytes.len() as u64
+            }
+            Body::Stream(chunks) => {
+                let mut writer = ChunkedWriter::new(&mut *stream, chunked);
+                for chunk in chunks {
+                    writer.write_all(&chunk?)?;
+                }
+                writer.finish()?
+            }
+            Body::Writer(f) => {
+                let mut writer = ChunkedWriter::new(&mut *stream, chunked);
+                f(&mut writer)?;
+                writer.finish()?
+            }
+        };
+        stream.flush()?;
+        Ok(sent)
+    }
+}
+
+/// Frames every write as one chunk of a `Transfer-Encoding: chunked` body.
+///
+/// [`finish`](ChunkedWriter::finish) must be called to send the terminating
+/// zero length chunk.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    chunked: bool,
+    sent: u64,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    /// With `chunked` false writes pass straight through, for HTTP/1.0 clients.
+    pub fn new(inner: W, chunked: bool) -> ChunkedWriter<W> {
+        ChunkedWriter {
+            inner,
+            chunked,
+            sent: 0,
+        }
+    }
+
+    /// Ends the body, returning how many body bytes were written.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the final chunk can't be written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        if self.chunked {
+            self.inner.write_all(b"0\r\n\r\n")?;
+        }
+        self.inner.flush()?;
+        Ok(self.sent)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body early
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chunked {
+            self.inner
+                .write_all(format!("{:x}\r\n", buf.len()).as_bytes())?;
+            self.inner.write_all(buf)?;
+            self.inner.write_all(b"\r\n")?;
+        } else {
+            self.inner.write_all(buf)?;
+        }
+        self.sent += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct FileChunks {
+    file: File,
+}
+
+impl Iterator for FileChunks {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = vec![0; FILE_CHUNK_SIZE];
+        match self.file.read(&mut chunk) {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some(Ok(chunk))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// Whether `path` is `prefix` or below it, going by whole path segments.
+pub(crate) fn is_under(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with(['/', '?']),
+        None => false,
+    }
+}
+
+/// `Sun, 06 Nov 1994 08:49:37 GMT`, as `Last-Modified` and `If-Range` use.
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    let (year, month, day, hour, min, sec) = access_log::utc_parts(time);
+    // The epoch was a Thursday
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / 86_400);
+    format!(
+        "{}, {day:02} {} {year} {hour:02}:{min:02}:{sec:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1]
+    )
+}
+
+/// Standard base64 with padding.
+pub(crate) fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryStream;
+
+    const LIMITS: Limits = Limits {
+        read_timeout: Some(Duration::from_millis(200)),
+        header_timeout: Some(Duration::from_millis(500)),
+        max_header_bytes: 256,
+        max_headers: 4,
+        max_body_bytes: 16,
+    };
+
+    fn parse(raw: &str) -> Result<Request, RequestError> {
+        let (mut client, server) = MemoryStream::pair();
+        client.write_all(raw.as_bytes()).unwrap();
+        read_request(&mut BufReader::new(server), &LIMITS)
+    }
+
+    #[test]
+    fn reads_request_head() {
+        let request =
+            parse("GET /index.html HTTP/1.1\r\nHost: localhost\r\nAccept:*/*\r\n\r\nbody").unwrap();
+        assert_eq!(
+            Request::new(
+                "GET",
+                "/index.html",
+                &[("Host", "localhost"), ("Accept", "*/*")]
+            ),
+            request
+        );
+        assert_eq!(Some("localhost"), request.header("host"));
+    }
+
+    #[test]
+    fn rejects_bad_heads() {
+        assert!(matches!(
+            parse("GET /\r\n\r\n"),
+            Err(RequestError::Malformed)
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nno colon\r\n\r\n"),
+            Err(RequestError::Malformed)
+        ));
+        assert!(matches!(
+            parse(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(300))),
+            Err(RequestError::HeadersTooLarge)
+        ));
+        assert!(matches!(
+            parse(&format!("GET / HTTP/1.1\r\n{}\r\n", "X: y\r\n".repeat(5))),
+            Err(RequestError::TooManyHeaders)
+        ));
+    }
+
+    #[test]
+    fn reads_body_by_content_length() {
+        let raw = "POST /form HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody and more";
+        assert_eq!(b"body", &parse(raw).unwrap().body[..]);
+
+        // The non-blocking parser waits for the whole body
+        let head_len = raw.find("body").unwrap();
+        assert!(parse_request(&raw.as_bytes()[..head_len + 3], &LIMITS)
+            .unwrap()
+            .is_none());
+        let (request, len) = parse_request(raw.as_bytes(), &LIMITS).unwrap().unwrap();
+        assert_eq!((&b"body"[..], head_len + 4), (&request.body[..], len));
+
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n"),
+            Err(RequestError::BodyTooLarge)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Err(RequestError::LengthRequired)
+        ));
+        // A body that stops short gets the same read timeout as the head
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbo"),
+            Err(RequestError::TimedOut)
+        ));
+    }
+
+    #[test]
+    fn parses_request_head_in_pieces() {
+        let head = b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\nbody";
+        for end in [0, 10, 26, 44] {
+            assert!(parse_request(&head[..end], &LIMITS).unwrap().is_none());
+        }
+        let (request, len) = parse_request(head, &LIMITS).unwrap().unwrap();
+        assert_eq!(45, len);
+        assert_eq!("/index.html", request.path);
+        assert_eq!(Some("localhost"), request.header("host"));
+
+        let tight = Limits {
+            max_header_bytes: 16,
+            ..LIMITS
+        };
+        assert!(matches!(
+            parse_request(b"GET /a-longer-path", &tight),
+            Err(RequestError::HeadersTooLarge)
+        ));
+        assert!(matches!(
+            parse_request(b"GET /\r\n", &LIMITS),
+            Err(RequestError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn stalled_client_times_out() {
+        let started = Instant::now();
+        // The client never finishes its headers and never hangs up
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost: x"),
+            Err(RequestError::TimedOut)
+        ));
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn trickling_client_hits_header_deadline() {
+        let (mut client, server) = MemoryStream::pair();
+        let trickle = std::thread::spawn(move || {
+            // Each byte arrives well within the read timeout
+            for &b in b"GET / HTTP/1.1\r\nHost: localhost\r\n".iter() {
+                if client.write_all(&[b]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let started = Instant::now();
+        let result = read_request(&mut BufReader::new(server), &LIMITS);
+        assert!(matches!(result, Err(RequestError::TimedOut)));
+        assert!(started.elapsed() < Duration::from_millis(700));
+        trickle.join().unwrap();
+    }
+
+    fn written(mut response: Response, chunked: bool) -> (String, u64) {
+        let mut out = Vec::new();
+        let sent = response.write_to(&mut out, chunked).unwrap();
+        (String::from_utf8(out).unwrap(), sent)
+    }
+
+    #[test]
+    fn full_body() {
+        let response = Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_body("hello");
+        assert_eq!(
+            (
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello"
+                    .to_string(),
+                5
+            ),
+            written(response, true)
+        );
+    }
+
+    #[test]
+    fn streamed_body_is_chunked() {
+        let chunks = ["hello ", "", "chunked world"].map(|c| Ok(c.as_bytes().to_vec()));
+        let response = Response::new(200).with_stream(chunks);
+        assert_eq!(
+            (
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 6\r\nhello \r\nd\r\nchunked world\r\n0\r\n\r\n"
+                    .to_string(),
+                19
+            ),
+            written(response, true)
+        );
+    }
+
+    #[test]
+    fn writer_body() {
+        let response = Response::new(200).with_writer(|w| {
+            for i in 0..3 {
+                write!(w, "{i}")?;
+            }
+            Ok(())
+        });
+        assert_eq!(
+            (
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 1\r\n0\r\n1\r\n1\r\n1\r\n2\r\n0\r\n\r\n"
+                    .to_string(),
+                3
+            ),
+            written(response, true)
+        );
+    }
+
+    #[test]
+    fn streamed_body_without_chunking() {
+        let response = Response::new(200).with_stream([Ok(b"ab".to_vec()), Ok(b"cd".to_vec())]);
+        assert_eq!(
+            (
+                "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nabcd".to_string(),
+                4
+            ),
+            written(response, false)
+        );
+    }
+
+    #[test]
+    fn file_is_streamed() {
+        let response = Response::file(200, "200.html").unwrap();
+        let (out, sent) = written(response, true);
+        let body = std::fs::read_to_string("200.html").unwrap();
+        assert_eq!(body.len() as u64, sent);
+        assert!(out.ends_with(&format!("{:x}\r\n{body}\r\n0\r\n\r\n", body.len())));
+    }
+}
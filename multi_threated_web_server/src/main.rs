@@ -1,40 +1,27 @@
-use multi_threaded_web_server::ThreadPool;
-use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
-    thread,
-    time::Duration,
+use multi_threaded_web_server::{
+    config::{Config, ConfigError, USAGE},
+    middleware::RequestId,
+    server::Server,
 };
+use std::{env, process};
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::build(4).unwrap();
-
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        pool.execute(|| {
-            handle_connection(stream);
-        });
-    }
-}
-
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader: BufReader<_> = BufReader::new(&mut stream);
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
-
-    let (status_line, filename) = match &request_line[..] {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "200.html"),
-        "GET /sleep HTTP/1.1" => {
-            thread::sleep(Duration::from_secs(5)); // loads after 5s
-            ("HTTP/1.1 200 OK", "200.html")
+    let args: Vec<String> = env::args().collect();
+    let config = Config::build(&args).unwrap_or_else(|err| {
+        if let ConfigError::HelpRequested = err {
+            println!("{USAGE}");
+            process::exit(0);
         }
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html"),
-    };
-
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+        eprintln!("Problem parsing arguments: {err}\n\n{USAGE}");
+        process::exit(1);
+    });
 
-    stream.write_all(response.as_bytes()).unwrap();
+    let server = Server::builder(config)
+        .middleware(RequestId::new())
+        .build()
+        .unwrap_or_else(|err| {
+            eprintln!("{err}");
+            process::exit(1);
+        });
+    server.run();
 }
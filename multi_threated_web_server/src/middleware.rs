@@ -0,0 +1,672 @@
+use crate::http::{self, Request, Response};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Wraps request handling with behavior of its own, such as checking
+/// credentials or adding headers to every response.
+///
+/// Middleware runs in the order it was registered, each one either passing
+/// the request on with [`Next::run`] or answering it itself. Plain closures
+/// taking the same arguments work too.
+///
+/// ```
+/// use multi_threaded_web_server::{http::Request, middleware::Next};
+///
+/// let server_header = |request: &mut Request, next: Next<'_>| {
+///     next.run(request).with_header("Server", "multi_threaded_web_server")
+/// };
+/// # let _ = server_header;
+/// ```
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, Next<'_>) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the pipeline: the middleware registered later, then the handler.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Fn(&mut Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        middleware: &'a [Box<dyn Middleware>],
+        handler: &'a dyn Fn(&mut Request) -> Response,
+    ) -> Next<'a> {
+        Next {
+            middleware,
+            handler,
+        }
+    }
+
+    /// Passes the request on, returning the response the rest of the pipeline made.
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.handler)),
+            None => (self.handler)(request),
+        }
+    }
+}
+
+/// Gives each request an `X-Request-Id`, keeping one the client sent, and
+/// echoes it on the response so the two can be matched up in logs.
+pub struct RequestId {
+    /// Tells ids from different runs apart
+    prefix: String,
+    next: AtomicU64,
+}
+
+impl RequestId {
+    pub fn new() -> RequestId {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        RequestId {
+            prefix: format!("{:x}", started.as_millis()),
+            next: AtomicU64::new(1),
+        }
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        RequestId::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let id = match request.header("X-Request-Id") {
+            Some(id) if id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()) => {
+                id.to_string()
+            }
+            _ => {
+                let id = format!(
+                    "{}-{}",
+                    self.prefix,
+                    self.next.fetch_add(1, Ordering::Relaxed)
+                );
+                request
+                    .headers
+                    .retain(|(name, _)| !name.eq_ignore_ascii_case("X-Request-Id"));
+                request
+                    .headers
+                    .push(("X-Request-Id".to_string(), id.clone()));
+                id
+            }
+        };
+        let response = next.run(request);
+        if response.header("X-Request-Id").is_some() {
+            return response;
+        }
+        response.with_header("X-Request-Id", id)
+    }
+}
+
+/// Prints a line to stderr for every request once it has been answered,
+/// with its request id if [`RequestId`] runs before it.
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let started = Instant::now();
+        let line = format!(
+            "{} \"{} {}\"",
+            request
+                .client
+                .map_or_else(|| "-".to_string(), |client| client.to_string()),
+            request.method,
+            request.path
+        );
+        let id = request.header("X-Request-Id").map(String::from);
+        let response = next.run(request);
+        eprintln!(
+            "{line} {} {:.1}ms{}",
+            response.status,
+            started.elapsed().as_secs_f64() * 1e3,
+            id.map_or_else(String::new, |id| format!(" {id}"))
+        );
+        response
+    }
+}
+
+/// Answers `401 Unauthorized` to requests under `prefix` without the right
+/// `Authorization` header.
+pub struct Auth {
+    prefix: String,
+    expected: String,
+    challenge: String,
+}
+
+impl Auth {
+    /// Requires HTTP Basic credentials.
+    pub fn basic(prefix: &str, user: &str, password: &str) -> Auth {
+        Auth {
+            prefix: prefix.to_string(),
+            expected: format!(
+                "Basic {}",
+                http::base64(format!("{user}:{password}").as_bytes())
+            ),
+            challenge: "Basic realm=\"restricted\", charset=\"UTF-8\"".to_string(),
+        }
+    }
+
+    /// Requires `token` as a bearer token.
+    pub fn bearer(prefix: &str, token: &str) -> Auth {
+        Auth {
+            prefix: prefix.to_string(),
+            expected: format!("Bearer {token}"),
+            challenge: "Bearer".to_string(),
+        }
+    }
+}
+
+impl Middleware for Auth {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        if !http::is_under(&request.path, &self.prefix) {
+            return next.run(request);
+        }
+        match request.header("Authorization") {
+            Some(given) if constant_time_eq(given.as_bytes(), self.expected.as_bytes()) => {
+                next.run(request)
+            }
+            _ => Response::new(401).with_header("WWW-Authenticate", self.challenge.as_str()),
+        }
+    }
+}
+
+/// Compares in time that depends only on the lengths, so a guess can't be
+/// refined by timing how long it takes to be turned down.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Lets pages from other origins call the server, answering CORS preflight
+/// requests itself.
+pub struct Cors {
+    /// Empty allows any origin
+    origins: Vec<String>,
+    max_age: Duration,
+}
+
+impl Cors {
+    /// Allows requests from any origin.
+    pub fn any() -> Cors {
+        Cors::origins(&[])
+    }
+
+    /// Allows requests from these origins only, like `https://example.com`.
+    pub fn origins(origins: &[&str]) -> Cors {
+        Cors {
+            origins: origins.iter().map(|o| o.to_string()).collect(),
+            max_age: Duration::from_secs(600),
+        }
+    }
+
+    /// How long browsers may cache a preflight answer.
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = max_age;
+        self
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let allow_origin = match request.header("Origin") {
+            Some(_) if self.origins.is_empty() => "*".to_string(),
+            Some(origin) if self.origins.iter().any(|o| o == origin) => origin.to_string(),
+            // Not a cross-origin request, or one the browser should refuse
+            _ => return next.run(request),
+        };
+
+        let response = if request.method == "OPTIONS"
+            && request.header("Access-Control-Request-Method").is_some()
+        {
+            let mut response = Response::new(204)
+                .with_header(
+                    "Access-Control-Allow-Methods",
+                    "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS",
+                )
+                .with_header("Access-Control-Max-Age", self.max_age.as_secs().to_string());
+            if let Some(headers) = request.header("Access-Control-Request-Headers") {
+                response = response.with_header("Access-Control-Allow-Headers", headers);
+            }
+            response
+        } else {
+            next.run(request)
+        };
+        let response = response.with_header("Access-Control-Allow-Origin", allow_origin);
+        // The answer depends on the origin unless every origin gets the same one
+        if self.origins.is_empty() {
+            response
+        } else {
+            response.with_header("Vary", "Origin")
+        }
+    }
+}
+
+/// A rate and burst allowance a [`RateLimit`] applies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Limit {
+    burst: f64,
+    per_second: f64,
+}
+
+impl Limit {
+    /// # Panics
+    ///
+    /// Panics if `burst` is 0 or `per_second` isn't positive.
+    fn new(burst: u32, per_second: f64) -> Limit {
+        assert!(burst > 0, "burst must allow at least one request");
+        assert!(per_second > 0.0, "refill rate must be positive");
+        Limit {
+            burst: burst.into(),
+            per_second,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limit: Limit,
+}
+
+impl Bucket {
+    /// The tokens there are by `now`, never more than the burst.
+    fn refill(&self, now: Instant) -> f64 {
+        let refilled = now.duration_since(self.updated).as_secs_f64() * self.limit.per_second;
+        (self.tokens + refilled).min(self.limit.burst)
+    }
+}
+
+/// A route with a rate limit of its own, parsed from `PREFIX=PER_SEC/BURST`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateRoute {
+    pub prefix: String,
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl FromStr for RateRoute {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, limit) = s.split_once('=').ok_or(())?;
+        let (per_second, burst) = limit.split_once('/').ok_or(())?;
+        let per_second: f64 = per_second.trim().parse().map_err(|_| ())?;
+        let burst: u32 = burst.trim().parse().map_err(|_| ())?;
+        let valid = prefix.starts_with('/') && per_second > 0.0 && per_second.is_finite();
+        if !valid || burst == 0 {
+            return Err(());
+        }
+        Ok(RateRoute {
+            prefix: prefix.to_string(),
+            per_second,
+            burst,
+        })
+    }
+}
+
+/// Buckets are per client IP, and per route for the requests a route limits
+type BucketKey = (IpAddr, Option<usize>);
+
+/// Answers `429 Too Many Requests` to clients going faster than their
+/// limit, with a `Retry-After` saying when the next request will be let through.
+///
+/// Each client IP gets a token bucket holding up to `burst` requests and
+/// refilling at `per_second`. Routes can have limits of their own, which
+/// requests under them count against instead, in a bucket of their own.
+/// Requests over a Unix socket have no IP and aren't limited.
+///
+/// Buckets of clients that have gone quiet are only dropped by
+/// [`sweep`](RateLimit::sweep). Clones share their buckets, so one can be
+/// kept for sweeping once the other is registered.
+#[derive(Clone)]
+pub struct RateLimit {
+    /// `None` leaves requests outside the routes unlimited
+    default: Option<Limit>,
+    routes: Vec<(String, Limit)>,
+    buckets: Arc<Mutex<HashMap<BucketKey, Bucket>>>,
+}
+
+impl RateLimit {
+    /// Limits every client to `per_second` requests a second on average,
+    /// in bursts of up to `burst`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `burst` is 0 or `per_second` isn't positive.
+    pub fn new(burst: u32, per_second: f64) -> RateLimit {
+        RateLimit {
+            default: Some(Limit::new(burst, per_second)),
+            ..RateLimit::routes_only()
+        }
+    }
+
+    /// Limits nothing until given [`route`](RateLimit::route)s.
+    pub fn routes_only() -> RateLimit {
+        RateLimit {
+            default: None,
+            routes: Vec::new(),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Gives requests under `prefix` a limit of their own, the longest
+    /// matching prefix winning.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `burst` is 0 or `per_second` isn't positive.
+    pub fn route(mut self, prefix: &str, burst: u32, per_second: f64) -> RateLimit {
+        self.routes
+            .push((prefix.to_string(), Limit::new(burst, per_second)));
+        self
+    }
+
+    /// The route the path falls under, if any, and the limit it gets.
+    fn limit_for(&self, path: &str) -> Option<(Option<usize>, Limit)> {
+        let route = self
+            .routes
+            .iter()
+            .enumerate()
+            .filter(|(_, (prefix, _))| http::is_under(path, prefix))
+            .max_by_key(|(_, (prefix, _))| prefix.len());
+        match route {
+            Some((i, (_, limit))) => Some((Some(i), *limit)),
+            None => self.default.map(|limit| (None, limit)),
+        }
+    }
+
+    /// Takes a token from the bucket, or says how long until there will be one.
+    fn take(&self, key: BucketKey, limit: Limit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+            limit,
+        });
+        bucket.tokens = bucket.refill(now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            // A tiny enough rate waits longer than a Duration holds
+            Err(
+                Duration::try_from_secs_f64((1.0 - bucket.tokens) / limit.per_second)
+                    .unwrap_or(Duration::MAX),
+            )
+        }
+    }
+
+    fn buckets(&self) -> MutexGuard<'_, HashMap<BucketKey, Bucket>> {
+        // A bucket is updated in one go, so a poisoned lock still holds good ones
+        self.buckets.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Drops the buckets that have filled up again, which are no different
+    /// from the fresh ones their clients would get. Returns how many there were.
+    pub fn sweep(&self) -> usize {
+        let now = Instant::now();
+        let mut buckets = self.buckets();
+        let before = buckets.len();
+        buckets.retain(|_, bucket| bucket.refill(now) < bucket.limit.burst);
+        before - buckets.len()
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let (Some(client), Some((route, limit))) = (request.client, self.limit_for(&request.path))
+        else {
+            return next.run(request);
+        };
+        match self.take((client.ip(), route), limit) {
+            Ok(()) => next.run(request),
+            Err(wait) => Response::new(429).with_header(
+                "Retry-After",
+                // Whole seconds, rounded up
+                wait.as_secs()
+                    .saturating_add(u64::from(wait.subsec_nanos() > 0))
+                    .max(1)
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            client: Some("10.0.0.1:4000".parse().unwrap()),
+            ..Request::new(method, path, headers)
+        }
+    }
+
+    /// Runs `request` through `middleware` to a handler answering 200.
+    fn run(middleware: &[Box<dyn Middleware>], request: &mut Request) -> Response {
+        Next::new(middleware, &|_: &mut Request| Response::new(200)).run(request)
+    }
+
+    #[test]
+    fn runs_in_registration_order() {
+        let tag = |name: &'static str| -> Box<dyn Middleware> {
+            Box::new(move |request: &mut Request, next: Next<'_>| {
+                request.headers.push(("Seen".to_string(), name.to_string()));
+                let response = next.run(request);
+                response.with_header("Unwound", name)
+            })
+        };
+        let middleware = [tag("outer"), tag("inner")];
+        let mut request = request("GET", "/", &[]);
+        let response = run(&middleware, &mut request);
+
+        let seen: Vec<&str> = request.headers.iter().map(|(_, v)| v.as_str()).collect();
+        assert_eq!(vec!["outer", "inner"], seen);
+        let unwound: Vec<&str> = response.headers.iter().map(|(_, v)| v.as_str()).collect();
+        assert_eq!(vec!["inner", "outer"], unwound);
+    }
+
+    #[test]
+    fn request_ids() {
+        let middleware: [Box<dyn Middleware>; 1] = [Box::new(RequestId::new())];
+        let mut first = request("GET", "/", &[]);
+        let mut second = request("GET", "/", &[("X-Request-Id", "bad id")]);
+        let first_id = run(&middleware, &mut first)
+            .header("X-Request-Id")
+            .map(String::from);
+        let second_id = run(&middleware, &mut second)
+            .header("X-Request-Id")
+            .map(String::from);
+        assert_ne!(first_id, second_id);
+        assert_eq!(first_id.as_deref(), first.header("X-Request-Id"));
+        assert_eq!(second_id.as_deref(), second.header("X-Request-Id"));
+
+        let mut given = request("GET", "/", &[("X-Request-Id", "abc-123")]);
+        let response = run(&middleware, &mut given);
+        assert_eq!(Some("abc-123"), response.header("X-Request-Id"));
+    }
+
+    #[test]
+    fn auth() {
+        assert_eq!("dXNlcjpwYXNz", http::base64(b"user:pass"));
+        assert_eq!("YQ==", http::base64(b"a"));
+        let middleware: [Box<dyn Middleware>; 2] = [
+            Box::new(Auth::basic("/admin", "user", "pass")),
+            Box::new(Auth::bearer("/api", "s3cret")),
+        ];
+        let status = |path, headers: &[(&str, &str)]| {
+            run(&middleware, &mut request("GET", path, headers)).status
+        };
+        assert_eq!(200, status("/", &[]));
+        assert_eq!(200, status("/administrator", &[]));
+        assert_eq!(401, status("/admin/users", &[]));
+        assert_eq!(
+            401,
+            status("/admin", &[("Authorization", "Basic dXNlcjpwYXN3")])
+        );
+        assert_eq!(
+            200,
+            status("/admin", &[("Authorization", "Basic dXNlcjpwYXNz")])
+        );
+        assert_eq!(401, status("/api?x=1", &[("Authorization", "Bearer nope")]));
+        assert_eq!(
+            200,
+            status("/api?x=1", &[("Authorization", "Bearer s3cret")])
+        );
+
+        let response = run(&middleware, &mut request("GET", "/admin", &[]));
+        assert_eq!(
+            Some("Basic realm=\"restricted\", charset=\"UTF-8\""),
+            response.header("WWW-Authenticate")
+        );
+    }
+
+    #[test]
+    fn cors() {
+        let middleware: [Box<dyn Middleware>; 1] =
+            [Box::new(Cors::origins(&["https://example.com"]))];
+
+        let response = run(&middleware, &mut request("GET", "/", &[]));
+        assert_eq!(None, response.header("Access-Control-Allow-Origin"));
+        let response = run(
+            &middleware,
+            &mut request("GET", "/", &[("Origin", "https://evil.example")]),
+        );
+        assert_eq!(None, response.header("Access-Control-Allow-Origin"));
+
+        let response = run(
+            &middleware,
+            &mut request("GET", "/", &[("Origin", "https://example.com")]),
+        );
+        assert_eq!(200, response.status);
+        assert_eq!(
+            Some("https://example.com"),
+            response.header("Access-Control-Allow-Origin")
+        );
+        assert_eq!(Some("Origin"), response.header("Vary"));
+
+        let preflight = &mut request(
+            "OPTIONS",
+            "/api",
+            &[
+                ("Origin", "https://example.com"),
+                ("Access-Control-Request-Method", "PUT"),
+                ("Access-Control-Request-Headers", "content-type"),
+            ],
+        );
+        let response = run(&middleware, preflight);
+        assert_eq!(204, response.status);
+        assert_eq!(
+            Some("content-type"),
+            response.header("Access-Control-Allow-Headers")
+        );
+        assert_eq!(Some("600"), response.header("Access-Control-Max-Age"));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let middleware: [Box<dyn Middleware>; 1] = [Box::new(RateLimit::new(2, 1.0))];
+        let statuses: Vec<u16> = (0..3)
+            .map(|_| run(&middleware, &mut request("GET", "/", &[])).status)
+            .collect();
+        assert_eq!(vec![200, 200, 429], statuses);
+
+        let response = run(&middleware, &mut request("GET", "/", &[]));
+        assert_eq!(Some("1"), response.header("Retry-After"));
+
+        // Other clients have their own buckets
+        let mut other = request("GET", "/", &[]);
+        other.client = Some("10.0.0.2:4000".parse().unwrap());
+        assert_eq!(200, run(&middleware, &mut other).status);
+
+        // So slow a refill that the wait doesn't fit in a Duration
+        let middleware: [Box<dyn Middleware>; 1] = [Box::new(RateLimit::new(1, 1e-300))];
+        assert_eq!(200, run(&middleware, &mut request("GET", "/", &[])).status);
+        let response = run(&middleware, &mut request("GET", "/", &[]));
+        assert_eq!(429, response.status);
+        assert_eq!(
+            Some(u64::MAX.to_string().as_str()),
+            response.header("Retry-After")
+        );
+    }
+
+    #[test]
+    fn rate_limit_routes() {
+        let limit = RateLimit::routes_only()
+            .route("/api", 3, 1.0)
+            .route("/api/login", 1, 0.1);
+        let middleware: [Box<dyn Middleware>; 1] = [Box::new(limit.clone())];
+        let status = |path| run(&middleware, &mut request("POST", path, &[])).status;
+
+        assert_eq!(200, status("/api/login"));
+        let response = run(&middleware, &mut request("POST", "/api/login", &[]));
+        assert_eq!(429, response.status);
+        assert_eq!(Some("10"), response.header("Retry-After"));
+
+        // Each route counts separately, and unlisted paths aren't limited
+        let statuses: Vec<u16> = (0..4).map(|_| status("/api/items")).collect();
+        assert_eq!(vec![200, 200, 200, 429], statuses);
+        assert!((0..10).all(|_| status("/") == 200));
+        assert_eq!(0, limit.sweep());
+    }
+
+    #[test]
+    fn parses_rate_routes() {
+        assert_eq!(
+            Ok(RateRoute {
+                prefix: "/login".to_string(),
+                per_second: 0.5,
+                burst: 5,
+            }),
+            "/login=0.5/5".parse()
+        );
+        for bad in [
+            "login=1/5",
+            "/login=1",
+            "/login=0/5",
+            "/login=1/0",
+            "/login=inf/5",
+        ] {
+            assert_eq!(Err(()), bad.parse::<RateRoute>(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn sweeps_refilled_buckets() {
+        let limit = RateLimit::new(1, 100.0);
+        let middleware: [Box<dyn Middleware>; 1] = [Box::new(limit.clone())];
+        for client in ["10.0.0.1:1", "10.0.0.2:1"] {
+            let mut request = request("GET", "/", &[]);
+            request.client = Some(client.parse().unwrap());
+            run(&middleware, &mut request);
+        }
+        assert_eq!(0, limit.sweep());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(2, limit.sweep());
+        assert!(limit.buckets.lock().unwrap().is_empty());
+    }
+}
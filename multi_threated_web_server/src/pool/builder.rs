@@ -0,0 +1,192 @@
+use super::{
+    FullPolicy, Metrics, PanicHandler, PoolCreationError, Queue, Shared, ThreadHook, ThreadPool,
+    Worker,
+};
+use std::{
+    num::NonZeroUsize,
+    sync::{atomic::AtomicUsize, Arc, Mutex, OnceLock, RwLock},
+    thread,
+    time::Duration,
+};
+
+/// How long a worker above the minimum waits for a job before exiting, by default.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// Configures a [`ThreadPool`] before any of its threads are started.
+///
+/// A pool has a fixed number of threads unless given separate
+/// [`min_threads`](ThreadPoolBuilder::min_threads) and
+/// [`max_threads`](ThreadPoolBuilder::max_threads), in which case it starts
+/// with the minimum, grows while jobs are waiting with no idle worker to take
+/// them, and shrinks back as workers sit idle past the
+/// [`keep_alive`](ThreadPoolBuilder::keep_alive).
+///
+/// ```
+/// use multi_threaded_web_server::ThreadPoolBuilder;
+///
+/// let pool = ThreadPoolBuilder::new()
+///     .num_threads(8)
+///     .thread_name(|id| format!("http-worker-{id}"))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct ThreadPoolBuilder {
+    min_threads: Option<usize>,
+    max_threads: Option<usize>,
+    keep_alive: Option<Duration>,
+    thread_name: Option<Box<dyn Fn(usize) -> String + Send + Sync>>,
+    stack_size: Option<usize>,
+    queue_capacity: Option<usize>,
+    full_policy: FullPolicy,
+    on_thread_start: Option<Arc<ThreadHook>>,
+    on_thread_exit: Option<Arc<ThreadHook>>,
+    panic_handler: Option<Arc<PanicHandler>>,
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
+
+    /// Number of worker threads, defaults to the machine's available parallelism.
+    ///
+    /// Shorthand for the same `min_threads` and `max_threads`.
+    pub fn num_threads(self, num_threads: usize) -> ThreadPoolBuilder {
+        self.min_threads(num_threads).max_threads(num_threads)
+    }
+
+    /// Worker threads kept running however idle the pool is, defaults to `max_threads`.
+    pub fn min_threads(mut self, min_threads: usize) -> ThreadPoolBuilder {
+        self.min_threads = Some(min_threads);
+        self
+    }
+
+    /// Most worker threads the pool grows to, defaults to the machine's
+    /// available parallelism or `min_threads`, whichever is larger.
+    pub fn max_threads(mut self, max_threads: usize) -> ThreadPoolBuilder {
+        self.max_threads = Some(max_threads);
+        self
+    }
+
+    /// How long a worker above `min_threads` waits for a job before exiting,
+    /// defaults to 60 seconds.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Names each worker thread from its id, threads are unnamed otherwise.
+    pub fn thread_name<F>(mut self, name: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) -> String + Send + Sync + 'static,
+    {
+        self.thread_name = Some(Box::new(name));
+        self
+    }
+
+    /// Stack size in bytes for each worker thread, the std default otherwise.
+    pub fn stack_size(mut self, stack_size: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Most jobs allowed to wait in the queue at once, unbounded by default.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// What to do with new jobs once the queue is at capacity, defaults to
+    /// [`FullPolicy::Block`]. Has no effect on an unbounded queue.
+    pub fn when_full(mut self, policy: FullPolicy) -> ThreadPoolBuilder {
+        self.full_policy = policy;
+        self
+    }
+
+    /// Runs on each worker thread, with its id, before it takes any jobs.
+    pub fn on_thread_start<F>(mut self, hook: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_thread_start = Some(Arc::new(hook));
+        self
+    }
+
+    /// Runs on each worker thread, with its id, as it exits, whether it shut down or died.
+    pub fn on_thread_exit<F>(mut self, hook: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_thread_exit = Some(Arc::new(hook));
+        self
+    }
+
+    /// See [`ThreadPool::set_panic_handler`].
+    pub fn panic_handler<F>(mut self, handler: F) -> ThreadPoolBuilder
+    where
+        F: Fn(Option<usize>, &str) + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Starts the pool's threads.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the maximum thread count, stack size or queue capacity
+    /// is zero, if the minimum thread count is above the maximum, or if the OS
+    /// refuses to start a thread.
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        let max = match self.max_threads {
+            Some(0) => return Err(PoolCreationError::SizeTooSmall),
+            Some(max) => max,
+            None => thread::available_parallelism()
+                .map_or(1, NonZeroUsize::get)
+                .max(self.min_threads.unwrap_or(0)),
+        };
+        let min = self.min_threads.unwrap_or(max);
+        if min > max {
+            return Err(PoolCreationError::MinAboveMax);
+        }
+        if self.stack_size == Some(0) {
+            return Err(PoolCreationError::StackSizeTooSmall);
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(PoolCreationError::CapacityTooSmall);
+        }
+
+        let pool = ThreadPool {
+            timer: OnceLock::new(),
+            shared: Arc::new(Shared {
+                queue: Queue::new(min, self.queue_capacity),
+                full_policy: self.full_policy,
+                panic_handler: RwLock::new(self.panic_handler),
+                workers: Mutex::new(Vec::with_capacity(min)),
+                live: AtomicUsize::new(0),
+                busy: AtomicUsize::new(0),
+                min_threads: AtomicUsize::new(min),
+                max_threads: AtomicUsize::new(max),
+                metrics: Metrics::default(),
+                keep_alive: if min < max {
+                    Some(self.keep_alive.unwrap_or(KEEP_ALIVE))
+                } else {
+                    None
+                },
+                thread_name: self.thread_name,
+                stack_size: self.stack_size,
+                on_thread_start: self.on_thread_start,
+                on_thread_exit: self.on_thread_exit,
+            }),
+        };
+
+        let mut workers = pool.shared.workers();
+        for id in 0..min {
+            // Dropping the pool shuts down the workers already started
+            Worker::spawn(id, &pool.shared, &mut workers).map_err(PoolCreationError::Spawn)?;
+        }
+        drop(workers);
+        Ok(pool)
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    any::Any,
+    error::Error,
+    fmt,
+    sync::mpsc::{self, RecvTimeoutError, TryRecvError},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+    /// The job panicked, with the panic message if it had one.
+    Panicked(String),
+    /// The job was dropped without ever running.
+    Canceled,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked(msg) => write!(f, "Job panicked: {msg}"),
+            JobError::Canceled => write!(f, "Job was canceled before it ran."),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+/// Waits on the result of a job handed to [`ThreadPool::submit`](super::ThreadPool::submit).
+///
+/// Dropping the handle detaches the job, it still runs but its result is thrown away.
+#[derive(Debug)]
+pub struct JobHandle<T> {
+    receiver: Option<mpsc::Receiver<Result<T, JobError>>>,
+}
+
+impl<T> JobHandle<T> {
+    pub(super) fn new() -> (mpsc::Sender<Result<T, JobError>>, JobHandle<T>) {
+        let (sender, receiver) = mpsc::channel();
+        (
+            sender,
+            JobHandle {
+                receiver: Some(receiver),
+            },
+        )
+    }
+
+    /// Blocks until the job finishes.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the job panicked or was dropped without running.
+    pub fn join(mut self) -> Result<T, JobError> {
+        match self.receiver().recv() {
+            Ok(result) => result,
+            Err(_) => Err(JobError::Canceled),
+        }
+    }
+
+    /// Returns the result if the job has finished, `None` if it is still queued or running.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called again after a result was returned.
+    pub fn try_join(&mut self) -> Option<Result<T, JobError>> {
+        let result = match self.receiver().try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(JobError::Canceled),
+        };
+        self.receiver = None;
+        Some(result)
+    }
+
+    /// Like [`join`](JobHandle::join) but gives up after `timeout`, returning `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called again after a result was returned.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JobError>> {
+        let result = match self.receiver().recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => return None,
+            Err(RecvTimeoutError::Disconnected) => Err(JobError::Canceled),
+        };
+        self.receiver = None;
+        Some(result)
+    }
+
+    fn receiver(&mut self) -> &mpsc::Receiver<Result<T, JobError>> {
+        self.receiver
+            .as_ref()
+            .expect("JobHandle polled after it returned a result")
+    }
+}
+
+/// Pulls the message out of a panic payload, which is a `&str` or `String` for `panic!` calls.
+pub(super) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
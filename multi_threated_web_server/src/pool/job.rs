@@ -0,0 +1,268 @@
+use super::{
+    panic_message, queue::Task, ExecuteError, FullPolicy, JobError, JobHandle, ThreadPool,
+};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// How soon a job runs relative to the others waiting.
+///
+/// Workers take any `High` job before a `Normal` one, and only get to `Low`
+/// jobs once nothing else is queued. Within a priority jobs keep the order
+/// the work-stealing queue gives them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Shared flag for calling off jobs.
+///
+/// A job queued with a token that gets cancelled is dropped instead of run
+/// once a worker gets to it. A job already running isn't interrupted, but can
+/// keep a clone of the token and check [`is_cancelled`](CancellationToken::is_cancelled)
+/// to stop early.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancels every job holding this token or a clone of it. Can't be undone.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Options for a single job, from [`ThreadPool::job`].
+///
+/// ```
+/// use multi_threaded_web_server::{CancellationToken, Priority, ThreadPool};
+///
+/// let pool = ThreadPool::build(2).unwrap();
+/// let token = CancellationToken::new();
+/// let handle = pool
+///     .job()
+///     .priority(Priority::High)
+///     .cancel_token(token.clone())
+///     .submit(|| 6 * 7)
+///     .unwrap();
+/// assert_eq!(Ok(42), handle.join());
+/// ```
+#[must_use = "a job is only queued by `execute` or `submit`"]
+pub struct JobBuilder<'pool> {
+    pool: &'pool ThreadPool,
+    priority: Priority,
+    token: Option<CancellationToken>,
+}
+
+impl<'pool> JobBuilder<'pool> {
+    pub(super) fn new(pool: &'pool ThreadPool) -> JobBuilder<'pool> {
+        JobBuilder {
+            pool,
+            priority: Priority::Normal,
+            token: None,
+        }
+    }
+
+    /// Defaults to [`Priority::Normal`].
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Skips the job if `token` is cancelled before it starts.
+    pub fn cancel_token(mut self, token: CancellationToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Queues `f` like [`ThreadPool::execute`].
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the queue is full and the pool uses [`FullPolicy::Reject`](super::FullPolicy::Reject).
+    pub fn execute<F>(self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let policy = self.pool.shared.full_policy;
+        self.dispatch(f, policy)
+    }
+
+    /// Queues `f` like [`execute`](JobBuilder::execute) but never waits for
+    /// room or runs `f` on the calling thread, for callers that mustn't
+    /// stall. Where the pool's [`FullPolicy`] would do either, a full queue
+    /// rejects the job instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the queue is full and the pool doesn't use [`FullPolicy::DropOldest`].
+    pub fn try_execute<F>(self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let policy = match self.pool.shared.full_policy {
+            FullPolicy::DropOldest => FullPolicy::DropOldest,
+            FullPolicy::Block | FullPolicy::Reject | FullPolicy::CallerRuns => FullPolicy::Reject,
+        };
+        self.dispatch(f, policy)
+    }
+
+    fn dispatch<F>(self, f: F, policy: FullPolicy) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let task = Task {
+            priority: self.priority,
+            token: self.token,
+            ..Task::new(Box::new(f))
+        };
+        self.pool.dispatch(task, policy)
+    }
+
+    /// Queues `f` like [`ThreadPool::submit`]. A job skipped because its token
+    /// was cancelled reports [`JobError::Canceled`] through the handle.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() in the same cases as [`execute`](JobBuilder::execute).
+    pub fn submit<F, T>(self, f: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, handle) = JobHandle::new();
+        self.execute(move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+            // Nobody to tell if the handle was dropped
+            Ok(value) => {
+                let _ = sender.send(Ok(value));
+            }
+            Err(payload) => {
+                let _ = sender.send(Err(JobError::Panicked(panic_message(&*payload))));
+                // Let the worker report it like any other panicking job
+                panic::resume_unwind(payload);
+            }
+        })?;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPoolBuilder;
+    use std::{sync::mpsc, time::Duration};
+
+    #[test]
+    fn higher_priority_jobs_run_first() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        let (sender, received) = mpsc::channel();
+        // Keep the only worker busy while the rest queue up
+        pool.execute(move || blocked.recv().unwrap()).unwrap();
+
+        for (n, priority) in [Priority::Low, Priority::Normal, Priority::High]
+            .into_iter()
+            .enumerate()
+        {
+            let sender = sender.clone();
+            pool.job()
+                .priority(priority)
+                .execute(move || sender.send(n).unwrap())
+                .unwrap();
+        }
+        release.send(()).unwrap();
+        assert_eq!(vec![2, 1, 0], received.iter().take(3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cancelled_jobs_never_start() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || blocked.recv().unwrap()).unwrap();
+
+        let token = CancellationToken::new();
+        let skipped = pool.job().cancel_token(token.clone()).submit(|| 1).unwrap();
+        let kept = pool.submit(|| 2).unwrap();
+        token.cancel();
+        release.send(()).unwrap();
+
+        assert_eq!(Err(JobError::Canceled), skipped.join());
+        assert_eq!(Ok(2), kept.join());
+        let start = std::time::Instant::now();
+        while pool.stats().cancelled == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn try_execute_never_blocks_or_runs_inline() {
+        for policy in [FullPolicy::Block, FullPolicy::CallerRuns] {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(1)
+                .queue_capacity(1)
+                .when_full(policy)
+                .build()
+                .unwrap();
+            let (release, blocked) = mpsc::channel::<()>();
+            let (started, running) = mpsc::channel();
+            pool.execute(move || {
+                started.send(()).unwrap();
+                blocked.recv().unwrap();
+            })
+            .unwrap();
+            running.recv().unwrap();
+            pool.execute(|| ()).unwrap();
+
+            let ran = Arc::new(AtomicBool::new(false));
+            let flag = Arc::clone(&ran);
+            let result = pool
+                .job()
+                .try_execute(move || flag.store(true, Ordering::SeqCst));
+            assert_eq!(Err(ExecuteError::QueueFull), result, "{policy:?}");
+            assert!(!ran.load(Ordering::SeqCst));
+            release.send(()).unwrap();
+        }
+    }
+
+    #[test]
+    fn running_jobs_can_check_their_token() {
+        let pool = ThreadPool::build(1).unwrap();
+        let token = CancellationToken::new();
+        let (started, running) = mpsc::channel();
+        let handle = {
+            let token = token.clone();
+            pool.job()
+                .cancel_token(token.clone())
+                .submit(move || {
+                    started.send(()).unwrap();
+                    let mut rounds = 0;
+                    while !token.is_cancelled() {
+                        rounds += 1;
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    rounds
+                })
+                .unwrap()
+        };
+        running.recv().unwrap();
+        token.cancel();
+        assert!(handle.join().is_ok());
+    }
+}
@@ -0,0 +1,985 @@
+mod builder;
+mod handle;
+mod job;
+mod queue;
+mod scope;
+mod stats;
+mod timer;
+
+pub use builder::ThreadPoolBuilder;
+pub use handle::{JobError, JobHandle};
+pub use job::{CancellationToken, JobBuilder, Priority};
+pub use scope::Scope;
+pub use stats::{Histogram, PoolMonitor, PoolStats};
+pub use timer::ScheduleHandle;
+
+use handle::panic_message;
+use queue::{Pop, Queue, Task};
+use stats::Metrics;
+use std::{
+    any::Any,
+    cell::Cell,
+    error::Error,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use timer::Timer;
+
+thread_local! {
+    /// The pool (by the address of its shared state) and id of the worker running on this thread
+    static WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// Called with the worker id and panic message whenever a job panics. The id
+/// is `None` for a job run on the submitting thread by [`FullPolicy::CallerRuns`].
+pub type PanicHandler = dyn Fn(Option<usize>, &str) + Send + Sync;
+
+/// Called with the worker id as a worker thread starts or exits.
+pub type ThreadHook = dyn Fn(usize) + Send + Sync;
+
+/// State the pool and its workers share.
+struct Shared {
+    queue: Queue,
+    full_policy: FullPolicy,
+    panic_handler: RwLock<Option<Arc<PanicHandler>>>,
+    /// Indexed by worker id, slots of retired workers are reused
+    workers: Mutex<Vec<Worker>>,
+    /// Workers running, only changed while holding the `workers` lock
+    live: AtomicUsize,
+    /// Workers running a job right now
+    busy: AtomicUsize,
+    min_threads: AtomicUsize,
+    max_threads: AtomicUsize,
+    /// How long a worker above `min_threads` may sit idle, forever if `None`
+    keep_alive: Option<Duration>,
+    metrics: Metrics,
+    thread_name: Option<Box<dyn Fn(usize) -> String + Send + Sync>>,
+    stack_size: Option<usize>,
+    on_thread_start: Option<Arc<ThreadHook>>,
+    on_thread_exit: Option<Arc<ThreadHook>>,
+}
+
+impl Shared {
+    /// Identifies the pool in the `WORKER` thread local.
+    fn key(self: &Arc<Self>) -> usize {
+        Arc::as_ptr(self) as usize
+    }
+
+    /// The id of the worker running on this thread, if it's one of ours.
+    fn local_worker(self: &Arc<Self>) -> Option<usize> {
+        let key = self.key();
+        WORKER
+            .with(|worker| worker.get())
+            .filter(|(pool, _)| *pool == key)
+            .map(|(_, id)| id)
+    }
+
+    /// Runs a task on `worker`, or the submitting thread if `None`, recording
+    /// how it went and reporting it if it panics. A cancelled task is dropped instead.
+    fn run(&self, worker: Option<usize>, task: Task) {
+        if task.is_cancelled() {
+            self.metrics.cancelled.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let started = Instant::now();
+        self.metrics.queue_wait.record(started - task.queued);
+        let result = panic::catch_unwind(AssertUnwindSafe(task.job));
+        self.metrics.execution.record(started.elapsed());
+        match result {
+            Ok(()) => {
+                self.metrics.completed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(payload) => {
+                self.metrics.panicked.fetch_add(1, Ordering::Relaxed);
+                self.report_panic(worker, &*payload);
+            }
+        }
+    }
+
+    /// Queues a task, falling back on `policy` if the queue is full.
+    fn dispatch(self: &Arc<Self>, task: Task, policy: FullPolicy) -> Result<(), ExecuteError> {
+        // Jobs queued from one of our own workers stay on its deque, where they're
+        // cheapest to pick up and can still be stolen by idle workers
+        let local = self.local_worker();
+
+        let queue = &self.queue;
+        let task = match queue.try_push(task, local) {
+            Ok(()) => {
+                self.grow();
+                return Ok(());
+            }
+            Err(task) => task,
+        };
+        let metrics = &self.metrics;
+        match policy {
+            FullPolicy::Block if local.is_none() => {
+                if queue.push_blocking(task, None).is_err() {
+                    return Err(ExecuteError::ShuttingDown);
+                }
+            }
+            FullPolicy::Reject => {
+                metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(ExecuteError::QueueFull);
+            }
+            FullPolicy::DropOldest => {
+                if let Some(oldest) = queue.push_displacing(task, local) {
+                    metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                    drop(oldest);
+                }
+            }
+            FullPolicy::Block | FullPolicy::CallerRuns => {
+                self.run(local, task);
+                return Ok(());
+            }
+        }
+        self.grow();
+        Ok(())
+    }
+
+    fn live(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+
+    fn workers(&self) -> MutexGuard<'_, Vec<Worker>> {
+        // Nothing panics while holding the lock, but Drop must not panic even if something did
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts another worker if there are more jobs waiting than idle workers
+    /// to take them and room under `max_threads`.
+    fn grow(self: &Arc<Self>) {
+        // Checked without the lock first since this runs for every job
+        let live = self.live();
+        if live >= self.max_threads.load(Ordering::SeqCst) {
+            return;
+        }
+        let idle = live.saturating_sub(self.busy.load(Ordering::SeqCst));
+        if self.queue.len() <= idle {
+            return;
+        }
+
+        let mut workers = self.workers();
+        if self.live() >= self.max_threads.load(Ordering::SeqCst) {
+            return;
+        }
+        let id = workers
+            .iter()
+            .position(|worker| !worker.running)
+            .unwrap_or(workers.len());
+        // The job still gets run by the workers already there
+        if let Err(err) = Worker::spawn(id, self, &mut workers) {
+            eprintln!("Failed to start worker {id}: {err}");
+        }
+    }
+
+    /// Takes worker `id` out of the pool if there are more workers than
+    /// `max_threads`, or if it has been idle past the keep-alive and there are
+    /// more than `min_threads`.
+    fn retire(&self, id: usize, expired: bool) -> bool {
+        let mut workers = self.workers();
+        let live = self.live();
+        if live <= self.max_threads.load(Ordering::SeqCst)
+            && !(expired && live > self.min_threads.load(Ordering::SeqCst))
+        {
+            return false;
+        }
+        workers[id].running = false;
+        self.live.fetch_sub(1, Ordering::SeqCst);
+        drop(workers);
+        self.queue.retire(id);
+        true
+    }
+
+    fn report_panic(&self, id: Option<usize>, payload: &(dyn Any + Send)) {
+        let message = panic_message(payload);
+        // Clone the handler out so a slow one doesn't hold the lock
+        let handler = self
+            .panic_handler
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        match (handler, id) {
+            (Some(handler), _) => handler(id, &message),
+            (None, Some(id)) => eprintln!("Worker {id} job panicked: {message}"),
+            (None, None) => eprintln!("Job run by the submitting thread panicked: {message}"),
+        }
+    }
+}
+
+struct Worker {
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
+    /// False once the worker has retired, its thread may still be finishing up
+    running: bool,
+}
+
+impl Worker {
+    /// Starts worker `id` and stores it in its slot of `workers`, the locked
+    /// `shared.workers`. A slot that was already running keeps counting as one worker.
+    ///
+    /// The lock is held while spawning so a worker that dies straight away
+    /// can't store its replacement before we've stored it.
+    fn spawn(id: usize, shared: &Arc<Shared>, workers: &mut Vec<Worker>) -> io::Result<()> {
+        let mut builder = thread::Builder::new();
+        if let Some(name) = &shared.thread_name {
+            builder = builder.name(name(id));
+        }
+        if let Some(stack_size) = shared.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        shared.queue.add_worker(id);
+        let keep_alive = shared.keep_alive;
+        let worker_shared = Arc::clone(shared);
+        let thread = builder.spawn(move || {
+            let shared = worker_shared;
+            WORKER.with(|worker| worker.set(Some((shared.key(), id))));
+            let _sentinel = Sentinel {
+                id,
+                shared: &shared,
+            };
+            if let Some(hook) = &shared.on_thread_start {
+                hook(id);
+            }
+            let shrinking = || shared.live() > shared.max_threads.load(Ordering::SeqCst);
+            loop {
+                match shared.queue.pop(id, keep_alive, shrinking) {
+                    Pop::Job(task) => {
+                        shared.busy.fetch_add(1, Ordering::SeqCst);
+                        shared.run(Some(id), task);
+                        shared.busy.fetch_sub(1, Ordering::SeqCst);
+                        // The pool may have been shrunk while we were busy
+                        if shrinking() && shared.retire(id, false) {
+                            break;
+                        }
+                    }
+                    Pop::Idle { timed_out } => {
+                        if shared.retire(id, timed_out) {
+                            break;
+                        }
+                    }
+                    Pop::Closed => break,
+                }
+            }
+            println!("Worker {id} shutting down.");
+        })?;
+
+        let worker = Worker {
+            id,
+            thread: Some(thread),
+            running: true,
+        };
+        let replaced = match workers.get_mut(id) {
+            Some(slot) => std::mem::replace(slot, worker),
+            None => {
+                workers.push(worker);
+                shared.live.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            }
+        };
+        // A running slot is only replaced by a dying worker's own Sentinel, a
+        // retired one has a thread that's already left its loop and can be joined
+        if !replaced.running {
+            shared.live.fetch_add(1, Ordering::SeqCst);
+            if let Some(thread) = replaced.thread {
+                let _ = thread.join();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lives on a worker's stack to run the exit hook and to spawn a replacement if
+/// the worker unwinds, which only happens when something outside a job (like
+/// the panic handler) panics.
+struct Sentinel<'a> {
+    id: usize,
+    shared: &'a Arc<Shared>,
+}
+
+impl Drop for Sentinel<'_> {
+    fn drop(&mut self) {
+        if let Some(hook) = &self.shared.on_thread_exit {
+            // A second panic while unwinding would abort the process
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| hook(self.id)));
+        }
+        if thread::panicking() {
+            println!("Worker {} died; respawning.", self.id);
+            if let Err(err) = Worker::spawn(self.id, self.shared, &mut self.shared.workers()) {
+                eprintln!("Failed to respawn worker {}: {err}", self.id);
+            }
+        }
+    }
+}
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    /// Started by the first scheduled job
+    timer: OnceLock<Timer>,
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// What [`ThreadPool::execute`] does when the pool was built with a queue
+/// capacity and that many jobs are already waiting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Wait for a worker to take a job off the queue. Jobs queued from one of
+    /// the pool's own workers run on that worker instead, as it could
+    /// otherwise end up waiting on itself.
+    #[default]
+    Block,
+    /// Return [`ExecuteError::QueueFull`], dropping the job.
+    Reject,
+    /// Drop the job that has been waiting longest to make room.
+    DropOldest,
+    /// Run the job right away on the thread that submitted it.
+    CallerRuns,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// The queue is at capacity and the pool uses [`FullPolicy::Reject`].
+    QueueFull,
+    /// The pool shut down while the job waited for room in the queue.
+    ShuttingDown,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull => write!(f, "Job queue is full."),
+            ExecuteError::ShuttingDown => write!(f, "Thread pool is shutting down."),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+#[derive(Debug)]
+pub enum PoolCreationError {
+    SizeTooSmall,
+    MinAboveMax,
+    StackSizeTooSmall,
+    CapacityTooSmall,
+    /// The OS refused to start a worker thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolCreationError::SizeTooSmall => write!(f, "Pool size is too small."),
+            PoolCreationError::MinAboveMax => {
+                write!(f, "Minimum thread count is above the maximum.")
+            }
+            PoolCreationError::StackSizeTooSmall => write!(f, "Stack size is too small."),
+            PoolCreationError::CapacityTooSmall => write!(f, "Queue capacity is too small."),
+            PoolCreationError::Spawn(err) => write!(f, "Could not start worker thread: {err}"),
+        }
+    }
+}
+
+impl ThreadPool {
+    /// Creates a new ThreadPool
+    ///
+    /// The size is the # of threads in the pool, use [`ThreadPoolBuilder`]
+    /// for anything beyond that.
+    ///
+    /// # Errors
+    ///
+    /// The `build` function will return Err() if the size is 0 or a thread can't be started.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPoolBuilder::new().num_threads(size).build()
+    }
+
+    /// Number of worker threads running.
+    pub fn num_threads(&self) -> usize {
+        self.shared.live()
+    }
+
+    /// Changes the pool to `size` worker threads, as if it had been built with
+    /// [`num_threads(size)`](ThreadPoolBuilder::num_threads).
+    ///
+    /// Growing starts the new workers straight away. Shrinking lets idle
+    /// workers exit now and busy ones once they finish their current job.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the size is 0 or a thread can't be started.
+    pub fn resize(&self, size: usize) -> Result<(), PoolCreationError> {
+        if size == 0 {
+            return Err(PoolCreationError::SizeTooSmall);
+        }
+        let shared = &self.shared;
+        let mut workers = shared.workers();
+        shared.min_threads.store(size, Ordering::SeqCst);
+        shared.max_threads.store(size, Ordering::SeqCst);
+        while shared.live() < size {
+            let id = workers
+                .iter()
+                .position(|worker| !worker.running)
+                .unwrap_or(workers.len());
+            Worker::spawn(id, shared, &mut workers).map_err(PoolCreationError::Spawn)?;
+        }
+        drop(workers);
+        shared.queue.wake_all();
+        Ok(())
+    }
+
+    /// The id of the pool worker running the current job, `None` outside of a pool.
+    pub fn current_worker_id() -> Option<usize> {
+        WORKER.with(|worker| worker.get()).map(|(_, id)| id)
+    }
+
+    /// Replaces what happens when a job panics, by default the panic is printed to stderr.
+    ///
+    /// The worker carries on with the next job either way. Should the handler
+    /// itself panic the worker dies and is replaced with a fresh one.
+    pub fn set_panic_handler<F>(&self, handler: F)
+    where
+        F: Fn(Option<usize>, &str) + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .panic_handler
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(handler));
+    }
+
+    /// Queues `f` to run on one of the workers.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the queue is full and the pool uses [`FullPolicy::Reject`],
+    /// every other policy finds a way to accept the job.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.dispatch(Task::new(Box::new(f)), self.shared.full_policy)
+    }
+
+    /// Sets up a job with a [`Priority`] or [`CancellationToken`] before queueing it.
+    pub fn job(&self) -> JobBuilder<'_> {
+        JobBuilder::new(self)
+    }
+
+    /// Queues a task, falling back on `policy` if the queue is full.
+    fn dispatch(&self, task: Task, policy: FullPolicy) -> Result<(), ExecuteError> {
+        self.shared.dispatch(task, policy)
+    }
+
+    /// Runs `f` with a [`Scope`] whose jobs may borrow anything that outlives
+    /// the call, returning once every job spawned on it has finished.
+    ///
+    /// ```
+    /// use multi_threaded_web_server::ThreadPool;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let pool = ThreadPool::build(4).unwrap();
+    /// let words = ["borrowed", "from", "the", "stack"];
+    /// let letters = AtomicUsize::new(0);
+    /// pool.scope(|s| {
+    ///     for word in &words {
+    ///         let letters = &letters;
+    ///         s.spawn(move || {
+    ///             letters.fetch_add(word.len(), Ordering::Relaxed);
+    ///         });
+    ///     }
+    /// });
+    /// assert_eq!(20, letters.into_inner());
+    /// ```
+    ///
+    /// Called from one of the pool's own workers, that worker runs queued jobs
+    /// while it waits rather than sitting on its thread.
+    ///
+    /// # Panics
+    ///
+    /// Once every job is done, panics with `f`'s panic if it had one, or else
+    /// with the first panic from a spawned job.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope::new(self);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.wait();
+        match (result, scope.take_panic()) {
+            (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+            (Ok(value), None) => value,
+        }
+    }
+
+    /// Queues `f` on the pool once `delay` has passed.
+    ///
+    /// The delay is kept by a timer thread the pool starts for its first
+    /// scheduled job. Once due, the job is queued like any other and waits its
+    /// turn behind the jobs already queued.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timer thread can't be started.
+    pub fn schedule_after<F>(&self, delay: Duration, f: F) -> ScheduleHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.timer().once(delay, Box::new(f))
+    }
+
+    /// Queues `f` on the pool every `interval`, starting one `interval` from now,
+    /// until the returned handle is cancelled.
+    ///
+    /// Runs never overlap. One that comes due while the last is still running
+    /// is skipped, and a timer that falls behind carries on from the current
+    /// time rather than catching up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero or the timer thread can't be started.
+    pub fn schedule_every<F>(&self, interval: Duration, f: F) -> ScheduleHandle
+    where
+        F: FnMut() + Send + 'static,
+    {
+        assert!(
+            !interval.is_zero(),
+            "schedule_every needs a non-zero interval"
+        );
+        self.timer().every(interval, Box::new(f))
+    }
+
+    fn timer(&self) -> &Timer {
+        self.timer.get_or_init(|| {
+            Timer::spawn(Arc::clone(&self.shared)).expect("failed to spawn the pool's timer thread")
+        })
+    }
+
+    /// Number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.shared.queue.len()
+    }
+
+    /// A snapshot of the pool's counters and timings.
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+
+    /// A handle for reading [`stats`](ThreadPool::stats) from jobs or other
+    /// threads that can't borrow the pool.
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Runs `f` on the pool and returns a handle to its result.
+    ///
+    /// A panic in `f` is caught and reported through the handle as
+    /// [`JobError::Panicked`] instead of taking the worker down with it.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() in the same cases as [`execute`](ThreadPool::execute).
+    pub fn submit<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.job().submit(f)
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Stop scheduling before the queue closes under the timer
+        drop(self.timer.take());
+        self.shared.queue.close();
+        // Dropped from a job, the worker running it can't be joined. It's
+        // detached instead, and finishes the queue once the job returns.
+        let own = WORKER
+            .with(|worker| worker.get())
+            .filter(|(key, _)| *key == self.shared.key())
+            .map(|(_, id)| id);
+        let ids: Vec<usize> = self.shared.workers().iter().map(|w| w.id).collect();
+        for id in ids {
+            if self.shared.workers()[id].running {
+                println!("Shutting down worker {id}");
+            }
+            if Some(id) == own {
+                drop(self.shared.workers()[id].thread.take());
+                continue;
+            }
+            // A worker dying while we wait leaves its replacement in the slot, so keep
+            // joining until the slot is empty. The replacement exits once the queue
+            // is drained as it's already closed.
+            loop {
+                let thread = self.shared.workers()[id].thread.take();
+                match thread {
+                    // Join errors are worker panics, which have already been reported
+                    Some(thread) => {
+                        let _ = thread.join();
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Instant};
+
+    #[test]
+    fn submit_returns_result() {
+        let pool = ThreadPool::build(2).unwrap();
+        let handles: Vec<_> = (0..8)
+            .map(|i| pool.submit(move || i * i).unwrap())
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(vec![0, 1, 4, 9, 16, 25, 36, 49], results);
+    }
+
+    #[test]
+    fn submit_reports_panics() {
+        let pool = ThreadPool::build(1).unwrap();
+        let handle = pool.submit(|| -> u32 { panic!("boom") }).unwrap();
+        assert_eq!(Err(JobError::Panicked("boom".to_string())), handle.join());
+
+        // The worker survived the panic
+        assert_eq!(Ok(7), pool.submit(|| 7).unwrap().join());
+    }
+
+    #[test]
+    fn panics_go_to_handler_and_pool_keeps_its_size() {
+        let pool = ThreadPool::build(2).unwrap();
+        let (report, reports) = mpsc::channel();
+        let report = Mutex::new(report);
+        pool.set_panic_handler(move |_, message| {
+            report.lock().unwrap().send(message.to_string()).unwrap();
+        });
+
+        for i in 0..4 {
+            pool.execute(move || panic!("job {i}")).unwrap();
+        }
+        let mut messages: Vec<String> = reports.iter().take(4).collect();
+        messages.sort();
+        assert_eq!(vec!["job 0", "job 1", "job 2", "job 3"], messages);
+
+        // Both workers are still around to run two jobs at once
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                pool.submit(move || {
+                    barrier.wait();
+                })
+                .unwrap()
+            })
+            .collect();
+        for mut handle in handles {
+            assert_eq!(Some(Ok(())), handle.join_timeout(Duration::from_secs(5)));
+        }
+    }
+
+    #[test]
+    fn dead_workers_are_respawned() {
+        let pool = ThreadPool::build(1).unwrap();
+        // A panicking handler kills the worker
+        pool.set_panic_handler(|_, _| panic!("handler panicked"));
+        assert!(pool
+            .submit(|| panic!("job panicked"))
+            .unwrap()
+            .join()
+            .is_err());
+
+        // Its replacement picks up the next job, and dropping the pool doesn't panic
+        assert_eq!(
+            Ok(Some(0)),
+            pool.submit(ThreadPool::current_worker_id).unwrap().join()
+        );
+        drop(pool);
+    }
+
+    #[test]
+    fn builder() {
+        assert!(matches!(
+            ThreadPool::build(0),
+            Err(PoolCreationError::SizeTooSmall)
+        ));
+        assert!(matches!(
+            ThreadPoolBuilder::new().stack_size(0).build(),
+            Err(PoolCreationError::StackSizeTooSmall)
+        ));
+        assert!(matches!(
+            ThreadPoolBuilder::new().queue_capacity(0).build(),
+            Err(PoolCreationError::CapacityTooSmall)
+        ));
+
+        let (events, received) = mpsc::channel();
+        let (start, exit) = (Mutex::new(events.clone()), Mutex::new(events));
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(16)
+            .thread_name(|id| format!("test-worker-{id}"))
+            .stack_size(256 * 1024)
+            .on_thread_start(move |id| start.lock().unwrap().send(("start", id)).unwrap())
+            .on_thread_exit(move |id| exit.lock().unwrap().send(("exit", id)).unwrap())
+            .build()
+            .unwrap();
+
+        let name = pool
+            .submit(|| thread::current().name().map(String::from))
+            .unwrap();
+        assert!(name.join().unwrap().unwrap().starts_with("test-worker-"));
+
+        drop(pool);
+        let mut events: Vec<_> = received.iter().collect();
+        events.sort();
+        let expected: Vec<_> = ["exit", "start"]
+            .into_iter()
+            .flat_map(|event| (0..16).map(move |id| (event, id)))
+            .collect();
+        assert_eq!(expected, events);
+    }
+
+    #[test]
+    fn jobs_queued_from_workers_run() {
+        let pool = Arc::new(ThreadPool::build(4).unwrap());
+        let (done, finished) = mpsc::channel();
+        for _ in 0..10 {
+            let (inner_pool, done) = (Arc::clone(&pool), done.clone());
+            pool.execute(move || {
+                // These land on this worker's own deque for the others to steal
+                for _ in 0..10 {
+                    let done = done.clone();
+                    inner_pool.execute(move || done.send(()).unwrap()).unwrap();
+                }
+            })
+            .unwrap();
+        }
+        assert_eq!(100, finished.iter().take(100).count());
+        assert_eq!(0, pool.queued());
+    }
+
+    #[test]
+    fn dropped_from_its_own_worker() {
+        let pool = Arc::new(ThreadPool::build(2).unwrap());
+        let (done, finished) = mpsc::channel();
+        let (release, wait) = mpsc::channel::<()>();
+        let last = Arc::clone(&pool);
+        pool.execute(move || {
+            wait.recv().unwrap();
+            // The last reference goes on the worker, which must not join itself
+            drop(last);
+            done.send(ThreadPool::current_worker_id()).unwrap();
+        })
+        .unwrap();
+        drop(pool);
+        release.send(()).unwrap();
+        assert!(finished
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn try_join_and_timeout() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (release, wait) = std::sync::mpsc::channel::<()>();
+        let mut handle = pool
+            .submit(move || {
+                wait.recv().unwrap();
+                "done"
+            })
+            .unwrap();
+
+        assert_eq!(None, handle.try_join());
+        assert_eq!(None, handle.join_timeout(Duration::from_millis(20)));
+        release.send(()).unwrap();
+        assert_eq!(
+            Some(Ok("done")),
+            handle.join_timeout(Duration::from_secs(5))
+        );
+    }
+
+    /// Polls until the pool has `threads` workers, failing after a few seconds.
+    fn wait_for_threads(pool: &ThreadPool, threads: usize) {
+        let started = Instant::now();
+        while pool.num_threads() != threads {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "expected {threads} threads, have {}",
+                pool.num_threads()
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn grows_under_load_and_reaps_idle_workers() {
+        let pool = ThreadPoolBuilder::new()
+            .min_threads(1)
+            .max_threads(4)
+            .keep_alive(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        assert_eq!(1, pool.num_threads());
+
+        // Jobs that hold on to their worker until released force the pool to grow
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = Arc::new(Mutex::new(wait));
+        let handles: Vec<_> = (0..6)
+            .map(|_| {
+                let wait = Arc::clone(&wait);
+                pool.submit(move || wait.lock().unwrap().recv().unwrap())
+                    .unwrap()
+            })
+            .collect();
+        wait_for_threads(&pool, 4);
+
+        for _ in 0..6 {
+            release.send(()).unwrap();
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // Never past the maximum, and back down to the minimum once idle
+        assert!(pool.num_threads() <= 4);
+        wait_for_threads(&pool, 1);
+        assert_eq!(Ok(2), pool.submit(|| 1 + 1).unwrap().join());
+
+        assert!(matches!(
+            ThreadPoolBuilder::new()
+                .min_threads(2)
+                .max_threads(1)
+                .build(),
+            Err(PoolCreationError::MinAboveMax)
+        ));
+    }
+
+    #[test]
+    fn huge_maximum_costs_nothing_up_front() {
+        let pool = ThreadPoolBuilder::new()
+            .min_threads(1)
+            .max_threads(usize::MAX)
+            .build()
+            .unwrap();
+        assert_eq!(1, pool.num_threads());
+        assert_eq!(Ok(2), pool.submit(|| 1 + 1).unwrap().join());
+    }
+
+    #[test]
+    fn resize() {
+        let pool = ThreadPool::build(2).unwrap();
+        pool.resize(5).unwrap();
+        assert_eq!(5, pool.num_threads());
+
+        let ids: Vec<_> = (0..50)
+            .map(|_| pool.submit(ThreadPool::current_worker_id).unwrap())
+            .collect();
+        for id in ids {
+            assert!(id.join().unwrap().unwrap() < 5);
+        }
+
+        pool.resize(1).unwrap();
+        wait_for_threads(&pool, 1);
+        assert_eq!(Ok(2), pool.submit(|| 1 + 1).unwrap().join());
+        assert!(matches!(
+            pool.resize(0),
+            Err(PoolCreationError::SizeTooSmall)
+        ));
+    }
+
+    #[test]
+    fn stats() {
+        let pool = ThreadPool::build(2).unwrap();
+        pool.set_panic_handler(|_, _| {});
+        let monitor = pool.monitor();
+        let (release, wait) = mpsc::channel::<()>();
+        let blocked = pool.submit(move || wait.recv().unwrap()).unwrap();
+        for i in 0..10 {
+            pool.submit(move || assert!(i % 5 != 0))
+                .unwrap()
+                .join()
+                .ok();
+        }
+
+        // Handles hear back before the worker has finished counting the job
+        let mut stats = monitor.stats();
+        while stats.completed + stats.panicked < 10 || stats.active > 1 {
+            thread::yield_now();
+            stats = monitor.stats();
+        }
+        assert_eq!(2, stats.threads);
+        assert_eq!(1, stats.active);
+        assert_eq!(0, stats.queued);
+        assert_eq!(8, stats.completed);
+        assert_eq!(2, stats.panicked);
+        assert_eq!(10, stats.execution.count);
+        assert_eq!(11, stats.queue_wait.count);
+
+        release.send(()).unwrap();
+        blocked.join().unwrap();
+        while pool.stats().completed < 9 {
+            thread::yield_now();
+        }
+        assert_eq!(0, pool.stats().active);
+    }
+
+    #[test]
+    fn full_queue_policies() {
+        // One busy worker and one queued job, so the next job finds the queue full
+        let full_pool = |policy| {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(1)
+                .queue_capacity(1)
+                .when_full(policy)
+                .build()
+                .unwrap();
+            let (release, wait) = mpsc::channel::<()>();
+            pool.execute(move || wait.recv().unwrap()).unwrap();
+            while pool.queued() > 0 {
+                thread::yield_now();
+            }
+            let queued = pool.submit(|| "queued").unwrap();
+            (pool, release, queued)
+        };
+
+        let (pool, release, queued) = full_pool(FullPolicy::Reject);
+        assert_eq!(Err(ExecuteError::QueueFull), pool.execute(|| ()));
+        release.send(()).unwrap();
+        assert_eq!(Ok("queued"), queued.join());
+
+        let (pool, release, queued) = full_pool(FullPolicy::DropOldest);
+        let newest = pool.submit(|| "newest").unwrap();
+        release.send(()).unwrap();
+        assert_eq!(Err(JobError::Canceled), queued.join());
+        assert_eq!(Ok("newest"), newest.join());
+
+        let (pool, release, queued) = full_pool(FullPolicy::CallerRuns);
+        let caller = thread::current().id();
+        let ran_on = pool.submit(move || thread::current().id()).unwrap();
+        assert_eq!(Ok(caller), ran_on.join());
+        release.send(()).unwrap();
+        assert_eq!(Ok("queued"), queued.join());
+
+        let (pool, release, queued) = full_pool(FullPolicy::Block);
+        let pool = Arc::new(pool);
+        let producer = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || pool.submit(|| "blocked").unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!producer.is_finished());
+        release.send(()).unwrap();
+        assert_eq!(Ok("queued"), queued.join());
+        assert_eq!(Ok("blocked"), producer.join().unwrap().join());
+    }
+}
@@ -0,0 +1,688 @@
+use super::{CancellationToken, Job, Priority};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Most jobs a worker moves from the injector to its own deque in one go.
+const BATCH: usize = 32;
+
+/// Times an idle worker looks for work again, yielding in between, before going to sleep.
+const SPINS: usize = 16;
+
+/// Work-stealing job queue.
+///
+/// Jobs submitted from outside the pool go into a global injector, jobs
+/// submitted from a worker go onto that worker's own deque. A worker looks for
+/// work in its own deque first, then takes a batch from the injector, then
+/// steals half of another worker's deque. Each lock is only held to move jobs
+/// around, never while one runs, so workers rarely wait on each other.
+///
+/// Jobs at [`Priority::High`] or [`Priority::Low`] go into lanes of their own
+/// instead, checked before and after everything above respectively. They're
+/// expected to be the exception, so the lanes are only locked when not empty.
+///
+/// With a capacity, `pending` is never allowed past it and producers either
+/// get their job back or wait in [`push_blocking`](Queue::push_blocking).
+/// Cancelled jobs are swept out before a push is turned away, so they don't
+/// hold on to room until a worker gets around to them.
+///
+/// There is a deque for every worker id handed out so far, added by
+/// [`add_worker`](Queue::add_worker) as the pool grows into it, so a high
+/// maximum costs nothing until that many workers are actually running.
+pub(super) struct Queue {
+    injector: Mutex<VecDeque<Task>>,
+    /// Only written to when a worker gets an id past the end
+    locals: RwLock<Vec<Mutex<VecDeque<Task>>>>,
+    high: Lane,
+    low: Lane,
+    capacity: Option<usize>,
+    /// Jobs pushed but not yet popped, counted before they become visible
+    pending: AtomicUsize,
+    /// Workers asleep, or about to be, waiting for `pending` to go up
+    sleepers: AtomicUsize,
+    /// Producers waiting, or about to, for `pending` to go down
+    blocked: AtomicUsize,
+    /// Cancelled jobs taken out by [`sweep`](Queue::sweep) rather than a worker
+    swept: AtomicU64,
+    closed: AtomicBool,
+    sleep: Mutex<()>,
+    wakeup: Condvar,
+    space: Condvar,
+}
+
+/// A queued job, when it was queued and the options it was queued with.
+pub(super) struct Task {
+    pub(super) job: Job,
+    pub(super) queued: Instant,
+    pub(super) priority: Priority,
+    pub(super) token: Option<CancellationToken>,
+}
+
+impl Task {
+    /// A task at normal priority that can't be cancelled.
+    pub(super) fn new(job: Job) -> Task {
+        Task {
+            job,
+            queued: Instant::now(),
+            priority: Priority::Normal,
+            token: None,
+        }
+    }
+
+    pub(super) fn is_cancelled(&self) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+}
+
+/// A plain FIFO for one priority, with its length readable without the lock.
+#[derive(Default)]
+struct Lane {
+    tasks: Mutex<VecDeque<Task>>,
+    len: AtomicUsize,
+}
+
+impl Lane {
+    fn push(&self, task: Task) {
+        let mut tasks = lock(&self.tasks);
+        tasks.push_back(task);
+        self.len.store(tasks.len(), Ordering::SeqCst);
+    }
+
+    fn pop(&self) -> Option<Task> {
+        if self.len.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let mut tasks = lock(&self.tasks);
+        let task = tasks.pop_front();
+        self.len.store(tasks.len(), Ordering::SeqCst);
+        task
+    }
+
+    fn take_cancelled(&self, into: &mut Vec<Task>) {
+        if self.len.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut tasks = lock(&self.tasks);
+        take_cancelled(&mut tasks, into);
+        self.len.store(tasks.len(), Ordering::SeqCst);
+    }
+}
+
+/// What a worker got from [`Queue::pop`].
+pub(super) enum Pop {
+    Job(Task),
+    /// Nothing turned up before the timeout, or `stop` said to give up.
+    Idle {
+        timed_out: bool,
+    },
+    /// The queue is closed and empty.
+    Closed,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Jobs never run under these locks, so a poisoned one still holds a consistent deque
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Moves the cancelled tasks in `tasks` onto `into`, keeping the rest in order.
+fn take_cancelled(tasks: &mut VecDeque<Task>, into: &mut Vec<Task>) {
+    for task in tasks.drain(..).collect::<Vec<_>>() {
+        if task.is_cancelled() {
+            into.push(task);
+        } else {
+            tasks.push_back(task);
+        }
+    }
+}
+
+impl Queue {
+    /// A queue with deques for workers `0..workers`, holding at most `capacity` jobs if given.
+    pub(super) fn new(workers: usize, capacity: Option<usize>) -> Queue {
+        Queue {
+            injector: Mutex::new(VecDeque::new()),
+            locals: RwLock::new((0..workers).map(|_| Mutex::new(VecDeque::new())).collect()),
+            high: Lane::default(),
+            low: Lane::default(),
+            capacity,
+            pending: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            blocked: AtomicUsize::new(0),
+            swept: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            sleep: Mutex::new(()),
+            wakeup: Condvar::new(),
+            space: Condvar::new(),
+        }
+    }
+
+    /// Makes sure worker `id` has a deque of its own, before it starts.
+    pub(super) fn add_worker(&self, id: usize) {
+        if id < self.locals().len() {
+            return;
+        }
+        let mut locals = self.locals.write().unwrap_or_else(PoisonError::into_inner);
+        while locals.len() <= id {
+            locals.push(Mutex::new(VecDeque::new()));
+        }
+    }
+
+    fn locals(&self) -> RwLockReadGuard<'_, Vec<Mutex<VecDeque<Task>>>> {
+        self.locals.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hands whatever is left on worker `id`'s deque to the other workers, as it's about to exit.
+    pub(super) fn retire(&self, id: usize) {
+        let tasks: VecDeque<Task> = match self.locals().get(id) {
+            Some(deque) => lock(deque).drain(..).collect(),
+            None => return,
+        };
+        if tasks.is_empty() {
+            return;
+        }
+        lock(&self.injector).extend(tasks);
+        let _guard = lock(&self.sleep);
+        self.wakeup.notify_all();
+    }
+
+    /// Queues a task, on worker `local`'s own deque if given or the injector
+    /// otherwise, handing it back if the queue is full.
+    pub(super) fn try_push(&self, task: Task, local: Option<usize>) -> Result<(), Task> {
+        if !self.reserve() && (self.sweep() == 0 || !self.reserve()) {
+            return Err(task);
+        }
+        self.enqueue(task, local);
+        Ok(())
+    }
+
+    /// Counts one more job in `pending` if there is room for it.
+    fn reserve(&self) -> bool {
+        match self.capacity {
+            None => {
+                self.pending.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Some(capacity) => self
+                .pending
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    (n < capacity).then_some(n + 1)
+                })
+                .is_ok(),
+        }
+    }
+
+    /// Takes every cancelled task out of the queue, returning how many there were.
+    fn sweep(&self) -> usize {
+        let mut cancelled = Vec::new();
+        self.high.take_cancelled(&mut cancelled);
+        self.low.take_cancelled(&mut cancelled);
+        take_cancelled(&mut lock(&self.injector), &mut cancelled);
+        for deque in self.locals().iter() {
+            take_cancelled(&mut lock(deque), &mut cancelled);
+        }
+        let swept = cancelled.len();
+        if swept > 0 {
+            self.pending.fetch_sub(swept, Ordering::SeqCst);
+            self.swept.fetch_add(swept as u64, Ordering::Relaxed);
+            if self.blocked.load(Ordering::SeqCst) > 0 {
+                let _guard = lock(&self.sleep);
+                self.space.notify_all();
+            }
+        }
+        // Dropped outside the locks, a job may own something with work to do on drop
+        drop(cancelled);
+        swept
+    }
+
+    /// Like [`try_push`](Queue::try_push) but waits for room instead of
+    /// failing. The task is handed back if the queue closes while full, as
+    /// nothing would run it.
+    pub(super) fn push_blocking(&self, mut task: Task, local: Option<usize>) -> Result<(), Task> {
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(task);
+            }
+            task = match self.try_push(task, local) {
+                Ok(()) => return Ok(()),
+                Err(task) => task,
+            };
+
+            let capacity = self.capacity.unwrap_or(usize::MAX);
+            let mut guard = lock(&self.sleep);
+            // Paired with the check in `pop` the same way sleepers are paired with `enqueue`
+            self.blocked.fetch_add(1, Ordering::SeqCst);
+            while self.len() >= capacity && !self.closed.load(Ordering::SeqCst) {
+                guard = self
+                    .space
+                    .wait(guard)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            self.blocked.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Makes room for `task` by taking the oldest queued task of the lowest
+    /// priority out, which is returned for the caller to drop. Queues as
+    /// normal if there is nothing to take.
+    pub(super) fn push_displacing(&self, task: Task, local: Option<usize>) -> Option<Task> {
+        // Workers only take from the injector once their own deques are empty,
+        // so whatever is at its front has waited longest
+        let oldest = self
+            .low
+            .pop()
+            .or_else(|| lock(&self.injector).pop_front())
+            .or_else(|| {
+                self.locals()
+                    .iter()
+                    .find_map(|deque| lock(deque).pop_front())
+            })
+            .or_else(|| self.high.pop());
+        if oldest.is_none() {
+            self.pending.fetch_add(1, Ordering::SeqCst);
+        }
+        // Otherwise one job went out and one comes in, so `pending` stays put
+        self.enqueue(task, local);
+        oldest
+    }
+
+    /// Makes a job already counted in `pending` visible to the workers.
+    fn enqueue(&self, task: Task, local: Option<usize>) {
+        match task.priority {
+            Priority::High => self.high.push(task),
+            Priority::Low => self.low.push(task),
+            Priority::Normal => {
+                let locals = self.locals();
+                match local.and_then(|id| locals.get(id)) {
+                    Some(deque) => lock(deque).push_back(task),
+                    None => lock(&self.injector).push_back(task),
+                }
+            }
+        }
+        // Paired with the check in `pop`: either the sleeper sees `pending` go
+        // up or we see it registered as a sleeper and wake it
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _guard = lock(&self.sleep);
+            self.wakeup.notify_one();
+        }
+    }
+
+    /// Blocks until there is a job for worker `id`, giving up once it has
+    /// waited `timeout` if given. Returns [`Pop::Closed`] once the queue is
+    /// closed and every queued job has been handed out.
+    ///
+    /// `stop` is checked before sleeping and on every wakeup, under the same
+    /// lock [`wake_all`](Queue::wake_all) takes, so one that turns true before
+    /// a `wake_all` is never missed.
+    pub(super) fn pop(&self, id: usize, timeout: Option<Duration>, stop: impl Fn() -> bool) -> Pop {
+        let mut idle_since = None;
+        loop {
+            // Sleeping and being woken costs far more than a tiny job, so look
+            // around for a bit before giving up
+            for _ in 0..SPINS {
+                if let Some(task) = self.try_pop(id) {
+                    return Pop::Job(task);
+                }
+                if self.closed.load(Ordering::SeqCst) && self.len() == 0 {
+                    return Pop::Closed;
+                }
+                thread::yield_now();
+            }
+
+            // Only read the clock once spinning turned up nothing, it's too slow for every job
+            let idle_since = *idle_since.get_or_insert_with(Instant::now);
+            let mut guard = lock(&self.sleep);
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            let mut timed_out = false;
+            if self.pending.load(Ordering::SeqCst) == 0
+                && !self.closed.load(Ordering::SeqCst)
+                && !stop()
+            {
+                guard = match timeout {
+                    Some(timeout) => {
+                        let left = timeout.saturating_sub(idle_since.elapsed());
+                        let (guard, result) = self
+                            .wakeup
+                            .wait_timeout(guard, left)
+                            .unwrap_or_else(PoisonError::into_inner);
+                        timed_out = result.timed_out();
+                        guard
+                    }
+                    None => self
+                        .wakeup
+                        .wait(guard)
+                        .unwrap_or_else(PoisonError::into_inner),
+                };
+            }
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+            if self.pending.load(Ordering::SeqCst) == 0 {
+                if self.closed.load(Ordering::SeqCst) {
+                    return Pop::Closed;
+                }
+                if timed_out || stop() {
+                    return Pop::Idle { timed_out };
+                }
+            }
+            drop(guard);
+        }
+    }
+
+    /// Takes a job for worker `id` if there is one to be had right now.
+    pub(super) fn try_pop(&self, id: usize) -> Option<Task> {
+        // Only take locks when there's something to find
+        if self.len() == 0 {
+            return None;
+        }
+        let task = self.find(id)?;
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _guard = lock(&self.sleep);
+            self.space.notify_one();
+        }
+        Some(task)
+    }
+
+    /// Stops workers from sleeping, they exit once the queue is empty.
+    pub(super) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.wake_all();
+        let _guard = lock(&self.sleep);
+        self.space.notify_all();
+    }
+
+    /// Makes every sleeping worker check its `stop` in [`pop`](Queue::pop).
+    pub(super) fn wake_all(&self) {
+        let _guard = lock(&self.sleep);
+        self.wakeup.notify_all();
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Cancelled jobs swept out to make room, which no worker will see.
+    pub(super) fn swept(&self) -> u64 {
+        self.swept.load(Ordering::Relaxed)
+    }
+
+    fn find(&self, id: usize) -> Option<Task> {
+        if let Some(task) = self.high.pop() {
+            return Some(task);
+        }
+
+        let locals = self.locals();
+        let own = locals.get(id);
+        if let Some(task) = own.and_then(|own| lock(own).pop_front()) {
+            return Some(task);
+        }
+
+        {
+            let mut injector = lock(&self.injector);
+            if let Some(task) = injector.pop_front() {
+                // Take a fair share of the rest so we don't come back for every job
+                let batch = (injector.len() / locals.len()).min(BATCH);
+                if let Some(own) = own.filter(|_| batch > 0) {
+                    lock(own).extend(injector.drain(..batch));
+                }
+                return Some(task);
+            }
+        }
+
+        let workers = locals.len();
+        for victim in (1..=workers).map(|i| (id + i) % workers) {
+            if victim == id {
+                continue;
+            }
+            let mut stolen = {
+                let mut deque = lock(&locals[victim]);
+                // Without a deque to put the rest on, steal just the one
+                let take = match own {
+                    Some(_) => deque.len().div_ceil(2),
+                    None => deque.len().min(1),
+                };
+                if take == 0 {
+                    continue;
+                }
+                // The victim works from the front, so steal from the back
+                let at = deque.len() - take;
+                deque.split_off(at)
+            };
+            let task = stolen.pop_front();
+            if let Some(own) = own.filter(|_| !stolen.is_empty()) {
+                lock(own).extend(stolen);
+            }
+            return task;
+        }
+
+        self.low.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+
+    fn job(sender: &mpsc::Sender<usize>, n: usize) -> Task {
+        let sender = sender.clone();
+        Task::new(Box::new(move || sender.send(n).unwrap()))
+    }
+
+    /// Waits for worker `id`'s next job, `None` once the queue is closed.
+    fn next(queue: &Queue, id: usize) -> Option<Job> {
+        loop {
+            match queue.pop(id, None, || false) {
+                Pop::Job(task) => return Some(task.job),
+                Pop::Idle { .. } => continue,
+                Pop::Closed => return None,
+            }
+        }
+    }
+
+    #[test]
+    fn own_deque_then_injector_then_steal() {
+        let queue = Queue::new(2, None);
+        let (sender, received) = mpsc::channel();
+        assert!(queue.try_push(job(&sender, 1), None).is_ok());
+        assert!(queue.try_push(job(&sender, 2), Some(0)).is_ok());
+        assert!(queue.try_push(job(&sender, 3), Some(1)).is_ok());
+        assert!(queue.try_push(job(&sender, 4), Some(1)).is_ok());
+        assert_eq!(4, queue.len());
+
+        // Worker 0 drains its own deque, then the injector, then steals from worker 1
+        for _ in 0..3 {
+            next(&queue, 0).unwrap()();
+        }
+        // Worker 1 still has the job it didn't get robbed of
+        next(&queue, 1).unwrap()();
+        assert_eq!(vec![2, 1, 4, 3], received.try_iter().collect::<Vec<_>>());
+        assert_eq!(0, queue.len());
+    }
+
+    #[test]
+    fn priority_lanes_come_before_and_after_the_rest() {
+        let queue = Queue::new(2, Some(3));
+        let (sender, received) = mpsc::channel();
+        let with = |n, priority| Task {
+            priority,
+            ..job(&sender, n)
+        };
+        assert!(queue.try_push(with(1, Priority::Low), None).is_ok());
+        assert!(queue.try_push(with(2, Priority::Normal), Some(1)).is_ok());
+        assert!(queue.try_push(with(3, Priority::High), Some(1)).is_ok());
+
+        // A full queue gives up its low priority job first
+        let dropped = queue.push_displacing(with(4, Priority::Normal), None);
+        assert_eq!(Priority::Low, dropped.unwrap().priority);
+        assert!(queue.try_push(with(5, Priority::Low), None).is_err());
+
+        for _ in 0..3 {
+            next(&queue, 0).unwrap()();
+        }
+        assert_eq!(vec![3, 4, 2], received.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn close_drains_then_stops() {
+        let queue = Arc::new(Queue::new(1, None));
+        let (sender, received) = mpsc::channel();
+        assert!(queue.try_push(job(&sender, 1), None).is_ok());
+        queue.close();
+        next(&queue, 0).unwrap()();
+        assert!(next(&queue, 0).is_none());
+        assert_eq!(vec![1], received.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn push_wakes_sleeping_worker() {
+        let queue = Arc::new(Queue::new(1, None));
+        let worker = {
+            let queue = Arc::clone(&queue);
+            std::thread::spawn(move || {
+                let mut ran = 0;
+                while let Some(job) = next(&queue, 0) {
+                    job();
+                    ran += 1;
+                }
+                ran
+            })
+        };
+        let (sender, received) = mpsc::channel();
+        for n in 0..100 {
+            assert!(queue.try_push(job(&sender, n), None).is_ok());
+        }
+        assert_eq!(
+            (0..100).collect::<Vec<_>>(),
+            received.iter().take(100).collect::<Vec<_>>()
+        );
+        queue.close();
+        assert_eq!(100, worker.join().unwrap());
+    }
+
+    #[test]
+    fn idle_and_retire() {
+        let queue = Queue::new(1, None);
+        assert!(matches!(
+            queue.pop(0, Some(Duration::from_millis(10)), || false),
+            Pop::Idle { timed_out: true }
+        ));
+        assert!(matches!(
+            queue.pop(0, None, || true),
+            Pop::Idle { timed_out: false }
+        ));
+
+        // Worker 1 leaves its jobs behind for worker 0
+        let queue = Queue::new(2, None);
+        let (sender, received) = mpsc::channel();
+        assert!(queue.try_push(job(&sender, 1), Some(1)).is_ok());
+        queue.retire(1);
+        next(&queue, 0).unwrap()();
+        assert_eq!(vec![1], received.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn workers_added_later_get_a_deque() {
+        let queue = Queue::new(1, None);
+        let (sender, received) = mpsc::channel();
+        queue.add_worker(3);
+        assert!(queue.try_push(job(&sender, 1), Some(3)).is_ok());
+        assert!(queue.try_push(job(&sender, 2), Some(3)).is_ok());
+        assert_eq!(0, lock(&queue.injector).len());
+
+        // Worker 3 works from the front of its own deque, worker 0 steals from the back
+        next(&queue, 3).unwrap()();
+        next(&queue, 0).unwrap()();
+        assert_eq!(vec![1, 2], received.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn capacity() {
+        let queue = Queue::new(1, Some(2));
+        let (sender, received) = mpsc::channel();
+        assert!(queue.try_push(job(&sender, 1), None).is_ok());
+        assert!(queue.try_push(job(&sender, 2), None).is_ok());
+        assert!(queue.try_push(job(&sender, 3), None).is_err());
+
+        // The oldest job makes way for the new one
+        let dropped = queue.push_displacing(job(&sender, 4), None).unwrap();
+        drop(dropped);
+        assert_eq!(2, queue.len());
+        next(&queue, 0).unwrap()();
+        next(&queue, 0).unwrap()();
+        assert_eq!(vec![2, 4], received.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cancelled_jobs_give_up_their_room() {
+        let queue = Queue::new(2, Some(3));
+        let (sender, received) = mpsc::channel();
+        let token = CancellationToken::new();
+        let cancellable = |n, priority| Task {
+            priority,
+            token: Some(token.clone()),
+            ..job(&sender, n)
+        };
+        assert!(queue
+            .try_push(cancellable(1, Priority::Normal), None)
+            .is_ok());
+        assert!(queue
+            .try_push(cancellable(2, Priority::High), Some(1))
+            .is_ok());
+        assert!(queue
+            .try_push(cancellable(3, Priority::Normal), Some(1))
+            .is_ok());
+        assert!(queue.try_push(job(&sender, 4), None).is_err());
+
+        token.cancel();
+        assert!(queue.try_push(job(&sender, 4), None).is_ok());
+        assert_eq!(1, queue.len());
+        assert_eq!(3, queue.swept());
+        next(&queue, 0).unwrap()();
+        assert_eq!(vec![4], received.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn push_blocking_waits_for_room() {
+        let queue = Arc::new(Queue::new(1, Some(1)));
+        let (sender, received) = mpsc::channel();
+        assert!(queue.push_blocking(job(&sender, 1), None).is_ok());
+
+        let producer = {
+            let (queue, sender) = (Arc::clone(&queue), sender.clone());
+            std::thread::spawn(move || queue.push_blocking(job(&sender, 2), None).is_ok())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!producer.is_finished());
+
+        next(&queue, 0).unwrap()();
+        assert!(producer.join().unwrap());
+        next(&queue, 0).unwrap()();
+        assert_eq!(vec![1, 2], received.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn push_blocking_gives_up_when_closed() {
+        let queue = Arc::new(Queue::new(1, Some(1)));
+        let (sender, received) = mpsc::channel();
+        assert!(queue.push_blocking(job(&sender, 1), None).is_ok());
+
+        let producer = {
+            let (queue, sender) = (Arc::clone(&queue), sender.clone());
+            std::thread::spawn(move || queue.push_blocking(job(&sender, 2), None).is_err())
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        queue.close();
+        // Handed back while the queue is still full, rather than spinning until it drains
+        assert!(producer.join().unwrap());
+
+        next(&queue, 0).unwrap()();
+        assert!(next(&queue, 0).is_none());
+        assert_eq!(vec![1], received.try_iter().collect::<Vec<_>>());
+    }
+}
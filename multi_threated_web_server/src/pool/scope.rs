@@ -0,0 +1,237 @@
+use super::{FullPolicy, Job, Task, ThreadPool};
+use std::{
+    any::Any,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+/// How often a worker waiting on a scope looks for queued jobs to help with.
+const HELP_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Spawns jobs that may borrow from outside the [`ThreadPool::scope`] call.
+///
+/// The `'scope` lifetime is that of the scope itself, `'env` that of anything
+/// borrowed by its jobs, as with [`std::thread::Scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    /// Shared rather than borrowed so a job finishing up never touches the
+    /// scope's stack after `ThreadPool::scope` has returned
+    state: Arc<State>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct State {
+    /// Jobs spawned but not yet finished or dropped
+    running: Mutex<usize>,
+    done: Condvar,
+    /// The first panic from a job
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Only counters and the panic payload live under these locks
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A job spawned on a scope, which counts as finished once it has been
+/// dropped, whether or not it ever ran.
+struct ScopedJob<'scope> {
+    f: Option<Box<dyn FnOnce() + Send + 'scope>>,
+    state: Arc<State>,
+}
+
+impl ScopedJob<'_> {
+    fn run(mut self) {
+        let f = self.f.take().unwrap();
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            lock(&self.state.panic).get_or_insert(payload);
+        }
+    }
+}
+
+impl Drop for ScopedJob<'_> {
+    fn drop(&mut self) {
+        // Whatever the job borrowed must be let go of before the scope can end
+        drop(self.f.take());
+        let mut running = lock(&self.state.running);
+        *running -= 1;
+        if *running == 0 {
+            self.state.done.notify_all();
+        }
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub(super) fn new(pool: &'scope ThreadPool) -> Scope<'scope, 'env> {
+        Scope {
+            pool,
+            state: Arc::new(State {
+                running: Mutex::new(0),
+                done: Condvar::new(),
+                panic: Mutex::new(None),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        }
+    }
+
+    /// Queues `f` to run on the pool before the scope ends.
+    ///
+    /// A job the pool has no room for runs right away on the calling thread
+    /// rather than being rejected. A job pushed out of the queue by
+    /// [`FullPolicy::DropOldest`] counts as done without having run.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *lock(&self.state.running) += 1;
+        let scoped = ScopedJob {
+            f: Some(Box::new(f)),
+            state: Arc::clone(&self.state),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || scoped.run());
+        // SAFETY: `ThreadPool::scope` doesn't return before `running` is back
+        // to zero, and a `ScopedJob` only counts itself out once it's dropped
+        // along with everything it borrowed, so nothing outlives `'scope`
+        let job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+
+        let policy = match self.pool.shared.full_policy {
+            FullPolicy::Reject => FullPolicy::CallerRuns,
+            policy => policy,
+        };
+        // Only `Reject` fails, and that's been swapped out
+        let _ = self.pool.dispatch(Task::new(job), policy);
+    }
+
+    /// Blocks until every spawned job is done. A worker of the pool keeps
+    /// running queued jobs in the meantime, one of which may be what it's waiting on.
+    pub(super) fn wait(&self) {
+        let shared = &self.pool.shared;
+        let local = shared.local_worker();
+        let mut running = lock(&self.state.running);
+        while *running > 0 {
+            match local {
+                Some(id) => {
+                    drop(running);
+                    match shared.queue.try_pop(id) {
+                        Some(task) => shared.run(Some(id), task),
+                        None => {
+                            let guard = lock(&self.state.running);
+                            if *guard > 0 {
+                                let _ = self
+                                    .state
+                                    .done
+                                    .wait_timeout(guard, HELP_INTERVAL)
+                                    .unwrap_or_else(PoisonError::into_inner);
+                            }
+                        }
+                    }
+                    running = lock(&self.state.running);
+                }
+                None => {
+                    running = self
+                        .state
+                        .done
+                        .wait(running)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+        }
+    }
+
+    pub(super) fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+        lock(&self.state.panic).take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FullPolicy, ThreadPool, ThreadPoolBuilder};
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[test]
+    fn jobs_borrow_and_finish_before_scope_returns() {
+        let pool = ThreadPool::build(4).unwrap();
+        let mut numbers: Vec<u64> = (0..1000).collect();
+        let total = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for chunk in numbers.chunks_mut(100) {
+                let total = &total;
+                s.spawn(move || {
+                    for n in chunk.iter_mut() {
+                        *n *= 2;
+                    }
+                    total.fetch_add(chunk.len(), Ordering::Relaxed);
+                });
+            }
+        });
+        assert_eq!(1000, total.into_inner());
+        assert_eq!((0..1000).map(|n| n * 2).collect::<Vec<u64>>(), numbers);
+    }
+
+    #[test]
+    fn nested_scopes_on_a_single_worker() {
+        // The only worker waits on jobs that can only run if it helps out
+        let pool = ThreadPool::build(1).unwrap();
+        let count = AtomicUsize::new(0);
+        pool.scope(|outer| {
+            for _ in 0..4 {
+                outer.spawn(|| {
+                    pool.scope(|inner| {
+                        for _ in 0..4 {
+                            inner.spawn(|| {
+                                count.fetch_add(1, Ordering::Relaxed);
+                            });
+                        }
+                    });
+                });
+            }
+        });
+        assert_eq!(16, count.into_inner());
+    }
+
+    #[test]
+    fn panics_surface_after_every_job_is_done() {
+        let pool = ThreadPool::build(2).unwrap();
+        let count = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| panic!("scoped job panicked"));
+                for _ in 0..10 {
+                    s.spawn(|| {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            })
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(Some(&"scoped job panicked"), payload.downcast_ref::<&str>());
+        assert_eq!(10, count.into_inner());
+    }
+
+    #[test]
+    fn full_queue_runs_jobs_on_caller() {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .queue_capacity(1)
+            .when_full(FullPolicy::Reject)
+            .build()
+            .unwrap();
+        let count = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for _ in 0..50 {
+                s.spawn(|| {
+                    count.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        });
+        assert_eq!(50, count.into_inner());
+    }
+}
@@ -0,0 +1,164 @@
+use super::Shared;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the histogram buckets, with one more bucket for anything slower.
+const BOUNDS: [Duration; 14] = [
+    Duration::from_micros(10),
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+];
+
+/// Counters the workers bump as they go, read by [`ThreadPool::stats`](super::ThreadPool::stats).
+#[derive(Default)]
+pub(super) struct Metrics {
+    pub(super) completed: AtomicU64,
+    pub(super) panicked: AtomicU64,
+    pub(super) rejected: AtomicU64,
+    pub(super) dropped: AtomicU64,
+    pub(super) cancelled: AtomicU64,
+    pub(super) queue_wait: AtomicHistogram,
+    pub(super) execution: AtomicHistogram,
+}
+
+#[derive(Default)]
+pub(super) struct AtomicHistogram {
+    buckets: [AtomicU64; BOUNDS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl AtomicHistogram {
+    pub(super) fn record(&self, value: Duration) {
+        let bucket = BOUNDS.partition_point(|bound| *bound < value);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(value.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        let mut count = 0;
+        let mut buckets = Vec::with_capacity(BOUNDS.len());
+        for (bound, bucket) in BOUNDS.iter().zip(&self.buckets) {
+            count += bucket.load(Ordering::Relaxed);
+            buckets.push((*bound, count));
+        }
+        count += self.buckets[BOUNDS.len()].load(Ordering::Relaxed);
+        Histogram {
+            buckets,
+            count,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Distribution of durations, in the cumulative form Prometheus uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    /// Upper bounds paired with how many values were at or below them
+    pub buckets: Vec<(Duration, u64)>,
+    /// Every value recorded, including those above the last bound
+    pub count: u64,
+    pub sum: Duration,
+}
+
+/// A snapshot of what the pool is doing and has done.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Worker threads running
+    pub threads: usize,
+    /// Workers running a job right now
+    pub active: usize,
+    /// Jobs waiting for a worker
+    pub queued: usize,
+    /// Jobs that ran to completion
+    pub completed: u64,
+    /// Jobs that panicked
+    pub panicked: u64,
+    /// Jobs turned away by [`FullPolicy::Reject`](super::FullPolicy::Reject)
+    pub rejected: u64,
+    /// Jobs pushed out of the queue by [`FullPolicy::DropOldest`](super::FullPolicy::DropOldest)
+    pub dropped: u64,
+    /// Jobs skipped because their [`CancellationToken`](super::CancellationToken) was cancelled
+    pub cancelled: u64,
+    /// Time from being queued to starting to run
+    pub queue_wait: Histogram,
+    /// Time spent running
+    pub execution: Histogram,
+}
+
+impl Shared {
+    pub(super) fn stats(&self) -> PoolStats {
+        let metrics = &self.metrics;
+        PoolStats {
+            threads: self.live(),
+            active: self.busy.load(Ordering::SeqCst),
+            queued: self.queue.len(),
+            completed: metrics.completed.load(Ordering::Relaxed),
+            panicked: metrics.panicked.load(Ordering::Relaxed),
+            rejected: metrics.rejected.load(Ordering::Relaxed),
+            dropped: metrics.dropped.load(Ordering::Relaxed),
+            cancelled: metrics.cancelled.load(Ordering::Relaxed) + self.queue.swept(),
+            queue_wait: metrics.queue_wait.snapshot(),
+            execution: metrics.execution.snapshot(),
+        }
+    }
+}
+
+/// Reads a pool's [`PoolStats`] from anywhere, without keeping the pool alive.
+///
+/// Cheap to clone, so each job that reports on the pool can carry its own.
+#[derive(Clone)]
+pub struct PoolMonitor {
+    pub(super) shared: Arc<Shared>,
+}
+
+impl PoolMonitor {
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = AtomicHistogram::default();
+        histogram.record(Duration::from_micros(5));
+        histogram.record(Duration::from_micros(10));
+        histogram.record(Duration::from_millis(3));
+        histogram.record(Duration::from_secs(120));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(4, snapshot.count);
+        assert_eq!((Duration::from_micros(10), 2), snapshot.buckets[0]);
+        assert_eq!((Duration::from_millis(1), 2), snapshot.buckets[4]);
+        assert_eq!((Duration::from_millis(5), 3), snapshot.buckets[5]);
+        // The slowest value only shows up in the count
+        assert_eq!(
+            (Duration::from_secs(60), 3),
+            *snapshot.buckets.last().unwrap()
+        );
+        assert_eq!(
+            Duration::from_secs(120) + Duration::from_micros(3015),
+            snapshot.sum
+        );
+    }
+}
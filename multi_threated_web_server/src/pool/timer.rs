@@ -0,0 +1,262 @@
+use super::{CancellationToken, Job, Shared, Task};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    io,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, TryLockError, Weak},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Handle to a job queued with [`ThreadPool::schedule_after`](super::ThreadPool::schedule_after)
+/// or [`ThreadPool::schedule_every`](super::ThreadPool::schedule_every).
+///
+/// Dropping the handle leaves the schedule running, like a detached thread.
+#[derive(Debug, Clone)]
+pub struct ScheduleHandle {
+    token: CancellationToken,
+    /// Weak so a handle kept around doesn't keep the timer's entries alive
+    state: Weak<State>,
+}
+
+impl ScheduleHandle {
+    /// Stops the job from running again. A run already queued on the pool is
+    /// skipped, one already going is left to finish.
+    pub fn cancel(&self) {
+        self.token.cancel();
+        // Take the entry out now rather than when it comes due, which for a
+        // long delay could be never as far as memory is concerned
+        if let Some(state) = self.state.upgrade() {
+            lock(&state)
+                .heap
+                .retain(|Reverse(entry)| !entry.token.is_cancelled());
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+enum Kind {
+    Once(Job),
+    /// Shared with the runs queued on the pool, which skip themselves while
+    /// another run still holds the lock
+    Every(Arc<Mutex<Box<dyn FnMut() + Send>>>, Duration),
+}
+
+struct Entry {
+    at: Instant,
+    /// Breaks ties between entries due at the same time, first scheduled goes first
+    seq: u64,
+    token: CancellationToken,
+    kind: Kind,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    heap: BinaryHeap<Reverse<Entry>>,
+    next_seq: u64,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct State {
+    entries: Mutex<Entries>,
+    changed: Condvar,
+}
+
+fn lock(state: &State) -> MutexGuard<'_, Entries> {
+    // Jobs never run under the lock, so a poisoned one still holds a consistent heap
+    state.entries.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A thread that sleeps until the earliest scheduled job is due and then
+/// queues it on the pool like any other job.
+pub(super) struct Timer {
+    state: Arc<State>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Timer {
+    pub(super) fn spawn(shared: Arc<Shared>) -> io::Result<Timer> {
+        let state = Arc::new(State::default());
+        let thread = {
+            let state = Arc::clone(&state);
+            thread::Builder::new()
+                .name("pool-timer".to_string())
+                .spawn(move || run(&state, &shared))?
+        };
+        Ok(Timer {
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    pub(super) fn once(&self, delay: Duration, job: Job) -> ScheduleHandle {
+        self.add(delay, Kind::Once(job))
+    }
+
+    pub(super) fn every(&self, interval: Duration, job: Box<dyn FnMut() + Send>) -> ScheduleHandle {
+        self.add(interval, Kind::Every(Arc::new(Mutex::new(job)), interval))
+    }
+
+    fn add(&self, delay: Duration, kind: Kind) -> ScheduleHandle {
+        let token = CancellationToken::new();
+        let mut entries = lock(&self.state);
+        let seq = entries.next_seq;
+        entries.next_seq += 1;
+        entries.heap.push(Reverse(Entry {
+            at: Instant::now() + delay,
+            seq,
+            token: token.clone(),
+            kind,
+        }));
+        // The new entry may be due before whatever the timer is sleeping until
+        self.state.changed.notify_one();
+        ScheduleHandle {
+            token,
+            state: Arc::downgrade(&self.state),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        lock(&self.state).shutdown = true;
+        self.state.changed.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(state: &State, shared: &Arc<Shared>) {
+    let mut entries = lock(state);
+    while !entries.shutdown {
+        let now = Instant::now();
+        let due = match entries.heap.peek() {
+            None => {
+                entries = state
+                    .changed
+                    .wait(entries)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+            Some(Reverse(entry)) if entry.at > now => {
+                let left = entry.at - now;
+                entries = state
+                    .changed
+                    .wait_timeout(entries, left)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+                continue;
+            }
+            Some(_) => entries.heap.pop().unwrap().0,
+        };
+        if due.token.is_cancelled() {
+            continue;
+        }
+
+        let job: Job = match due.kind {
+            Kind::Once(job) => job,
+            Kind::Every(job, interval) => {
+                // Fall behind rather than fire a burst of runs to catch up
+                let next = Entry {
+                    at: (due.at + interval).max(now),
+                    seq: due.seq,
+                    token: due.token.clone(),
+                    kind: Kind::Every(Arc::clone(&job), interval),
+                };
+                entries.heap.push(Reverse(next));
+                Box::new(move || match job.try_lock() {
+                    Ok(mut job) => job(),
+                    Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner()(),
+                    Err(TryLockError::WouldBlock) => {}
+                })
+            }
+        };
+        let task = Task {
+            token: Some(due.token),
+            ..Task::new(job)
+        };
+        // Queueing may have to wait for room, don't hold up new schedules meanwhile
+        drop(entries);
+        let _ = shared.dispatch(task, shared.full_policy);
+        entries = lock(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lock;
+    use crate::ThreadPool;
+    use std::{
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn runs_after_the_delay_in_order() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (sender, received) = mpsc::channel();
+        let start = Instant::now();
+        for (n, delay) in [(2, 60), (1, 30), (3, 90)] {
+            let sender = sender.clone();
+            pool.schedule_after(Duration::from_millis(delay), move || {
+                sender.send(n).unwrap()
+            });
+        }
+        assert_eq!(vec![1, 2, 3], received.iter().take(3).collect::<Vec<_>>());
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn cancelled_schedules_stop_running() {
+        let pool = ThreadPool::build(2).unwrap();
+        let (sender, received) = mpsc::channel();
+        let skipped = {
+            let sender = sender.clone();
+            pool.schedule_after(Duration::from_millis(20), move || sender.send(0).unwrap())
+        };
+        let queued = |pool: &ThreadPool| lock(&pool.timer.get().unwrap().state).heap.len();
+        assert_eq!(1, queued(&pool));
+        skipped.cancel();
+        assert_eq!(0, queued(&pool));
+
+        let mut runs = 0;
+        let every = pool.schedule_every(Duration::from_millis(5), move || {
+            runs += 1;
+            sender.send(runs).unwrap();
+        });
+        assert_eq!(vec![1, 2, 3], received.iter().take(3).collect::<Vec<_>>());
+        every.cancel();
+        assert!(every.is_cancelled());
+        assert_eq!(0, queued(&pool));
+
+        // At most a run already queued when it was cancelled gets through
+        std::thread::sleep(Duration::from_millis(50));
+        let late: Vec<_> = received.try_iter().collect();
+        assert!(late.len() <= 1 && !late.contains(&0), "{late:?}");
+    }
+}
@@ -0,0 +1,557 @@
+use crate::http::{self, Request, Response};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Headers that only apply to one hop and are never passed along.
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Longest status, header or chunk size line accepted from an upstream.
+const MAX_LINE: u64 = 8192;
+
+/// Size of the chunks upstream bodies are relayed in.
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long a health check waits to connect when there is no upstream timeout.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests whose path is `prefix` or below it are forwarded to one of the
+/// `upstreams`, each a `host:port`.
+///
+/// Parsed from `PREFIX=HOST:PORT[,HOST:PORT...]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyRoute {
+    pub prefix: String,
+    pub upstreams: Vec<String>,
+}
+
+impl FromStr for ProxyRoute {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, upstreams) = s.split_once('=').ok_or(())?;
+        if !prefix.starts_with('/') {
+            return Err(());
+        }
+        let upstreams: Vec<String> = upstreams.split(',').map(|u| u.trim().to_string()).collect();
+        for upstream in &upstreams {
+            match upstream.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+                _ => return Err(()),
+            }
+        }
+        Ok(ProxyRoute {
+            prefix: prefix.to_string(),
+            upstreams,
+        })
+    }
+}
+
+struct Upstream {
+    addr: String,
+    healthy: AtomicBool,
+}
+
+impl Upstream {
+    fn connect(&self, timeout: Option<Duration>) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in self.addr.to_socket_addrs()? {
+            let result = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "upstream has no addresses")
+        }))
+    }
+}
+
+struct Backend {
+    prefix: String,
+    upstreams: Vec<Upstream>,
+    /// Round-robin position
+    next: AtomicUsize,
+}
+
+/// Forwards requests to upstream servers over HTTP/1.1, one connection per request.
+///
+/// Each route's upstreams take turns, skipping those marked down by a failed
+/// connection or [`check_health`](Proxy::check_health) for as long as any are up.
+/// Paths are forwarded unchanged.
+pub struct Proxy {
+    backends: Vec<Backend>,
+    timeout: Option<Duration>,
+}
+
+impl Proxy {
+    /// `timeout` bounds connecting to an upstream and each read from or write to it.
+    pub fn new(routes: &[ProxyRoute], timeout: Option<Duration>) -> Proxy {
+        let backends = routes
+            .iter()
+            .map(|route| Backend {
+                prefix: route.prefix.clone(),
+                upstreams: route
+                    .upstreams
+                    .iter()
+                    .map(|addr| Upstream {
+                        addr: addr.clone(),
+                        healthy: AtomicBool::new(true),
+                    })
+                    .collect(),
+                next: AtomicUsize::new(0),
+            })
+            .collect();
+        Proxy { backends, timeout }
+    }
+
+    /// Forwards the request if its path falls under one of the routes, the
+    /// longest matching prefix winning. Returns `None` for paths that don't.
+    ///
+    /// Upstreams that can't be reached get a `502 Bad Gateway`, those that
+    /// time out a `504 Gateway Timeout`.
+    pub fn forward(&self, request: &Request) -> Option<Response> {
+        let backend = self
+            .backends
+            .iter()
+            .filter(|backend| http::is_under(&request.path, &backend.prefix))
+            .max_by_key(|backend| backend.prefix.len())?;
+        Some(match self.exchange(backend, request) {
+            Ok(response) => response,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Response::new(504)
+            }
+            Err(_) => Response::new(502),
+        })
+    }
+
+    /// Tries connecting to every upstream, marking each up or down by how
+    /// that went. Returns the upstreams that changed, with whether they're up now.
+    pub fn check_health(&self) -> Vec<(String, bool)> {
+        let timeout = self.timeout.unwrap_or(HEALTH_CHECK_TIMEOUT);
+        let mut changed = Vec::new();
+        for upstream in self.backends.iter().flat_map(|b| &b.upstreams) {
+            let healthy = upstream.connect(Some(timeout)).is_ok();
+            if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                changed.push((upstream.addr.clone(), healthy));
+            }
+        }
+        changed
+    }
+
+    fn exchange(&self, backend: &Backend, request: &Request) -> io::Result<Response> {
+        let (stream, upstream) = self.connect(backend)?;
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+
+        let mut writer = BufWriter::new(&stream);
+        writer.write_all(forwarded_head(request, upstream).as_bytes())?;
+        writer.write_all(&request.body)?;
+        writer.flush()?;
+        drop(writer);
+
+        read_response(BufReader::new(stream), request.method == "HEAD")
+    }
+
+    /// Connects to the backend's next upstream that's up, falling back on the
+    /// ones marked down in case they've recovered since.
+    fn connect<'a>(&self, backend: &'a Backend) -> io::Result<(TcpStream, &'a str)> {
+        let count = backend.upstreams.len();
+        let start = backend.next.fetch_add(1, Ordering::Relaxed);
+        let (up, down): (Vec<&Upstream>, Vec<&Upstream>) = (0..count)
+            .map(|i| &backend.upstreams[(start + i) % count])
+            .partition(|upstream| upstream.healthy.load(Ordering::Relaxed));
+
+        let mut last_err = None;
+        for upstream in up.into_iter().chain(down) {
+            match upstream.connect(self.timeout) {
+                Ok(stream) => {
+                    upstream.healthy.store(true, Ordering::Relaxed);
+                    return Ok((stream, &upstream.addr));
+                }
+                Err(err) => {
+                    upstream.healthy.store(false, Ordering::Relaxed);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "route has no upstreams")))
+    }
+}
+
+fn is_hop_by_hop(request: &Request, name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+        // Headers the client listed in `Connection` are for us alone too
+        || request
+            .header("Connection")
+            .is_some_and(|listed| listed.split(',').any(|l| l.trim().eq_ignore_ascii_case(name)))
+}
+
+/// The request head as sent upstream: end-to-end headers only, the client
+/// added to `X-Forwarded-For`, our own `X-Forwarded-Proto` in place of any the
+/// client sent, and asking the upstream to close when done.
+fn forwarded_head(request: &Request, upstream: &str) -> String {
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, request.path);
+    let mut forwarded_for = None;
+    for (name, value) in &request.headers {
+        if name.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for = Some(value.clone());
+        } else if !is_hop_by_hop(request, name)
+            && !name.eq_ignore_ascii_case("Content-Length")
+            && !name.eq_ignore_ascii_case("X-Forwarded-Proto")
+        {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    if request.header("Host").is_none() {
+        head.push_str(&format!("Host: {upstream}\r\n"));
+    }
+    if let Some(client) = request.client {
+        let ip = client.ip().to_string();
+        let chain = match forwarded_for {
+            Some(earlier) => format!("{earlier}, {ip}"),
+            None => ip,
+        };
+        head.push_str(&format!("X-Forwarded-For: {chain}\r\n"));
+    } else if let Some(earlier) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {earlier}\r\n"));
+    }
+    head.push_str("X-Forwarded-Proto: http\r\n");
+    if !request.body.is_empty() || request.header("Content-Length").is_some() {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(malformed("line too long or cut short"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| malformed("line is not UTF-8"))
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad upstream response: {what}"),
+    )
+}
+
+/// Reads the upstream's response head, leaving the body to be streamed to the client.
+fn read_response(mut reader: BufReader<TcpStream>, head_only: bool) -> io::Result<Response> {
+    let (status, headers) = loop {
+        let status_line = read_line(&mut reader)?;
+        let status: u16 = match status_line.split_whitespace().nth(1) {
+            Some(status) if status_line.starts_with("HTTP/") => {
+                status.parse().map_err(|_| malformed("status"))?
+            }
+            _ => return Err(malformed("status line")),
+        };
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+            match line.split_once(':') {
+                Some((name, value)) => headers.push((name.to_string(), value.trim().to_string())),
+                None => return Err(malformed("header")),
+            }
+        }
+        // Skip interim responses, the final one follows
+        if !(100..200).contains(&status) {
+            break (status, headers);
+        }
+    };
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+    let framing = if head_only || status == 204 || status == 304 {
+        None
+    } else if header("Transfer-Encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"))
+    {
+        Some(Framing::Chunked { left: 0 })
+    } else if let Some(len) = header("Content-Length") {
+        Some(Framing::Length(
+            len.parse().map_err(|_| malformed("Content-Length"))?,
+        ))
+    } else {
+        Some(Framing::Close)
+    };
+
+    let mut response = Response::new(status);
+    for (name, value) in &headers {
+        let hop_by_hop = HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name));
+        if !hop_by_hop && !name.eq_ignore_ascii_case("Content-Length") {
+            response = response.with_header(name, value.as_str());
+        }
+    }
+    Ok(match framing {
+        Some(framing) => response.with_stream(UpstreamBody { reader, framing }),
+        None => response,
+    })
+}
+
+enum Framing {
+    /// Bytes left
+    Length(u64),
+    /// Bytes left in the current chunk
+    Chunked {
+        left: u64,
+    },
+    /// Until the upstream closes
+    Close,
+    Done,
+}
+
+/// An upstream body, undone from whatever framing it came in so the client
+/// side can frame it afresh.
+struct UpstreamBody {
+    reader: BufReader<TcpStream>,
+    framing: Framing,
+}
+
+impl UpstreamBody {
+    /// Reads at most `max` bytes, failing at EOF unless `eof_ok`.
+    fn read_some(&mut self, max: u64, eof_ok: bool) -> io::Result<Vec<u8>> {
+        let available = self.reader.fill_buf()?;
+        if available.is_empty() && !eof_ok {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let n = available.len().min(BODY_CHUNK_SIZE).min(max as usize);
+        let chunk = available[..n].to_vec();
+        self.reader.consume(n);
+        Ok(chunk)
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            return match self.framing {
+                Framing::Done | Framing::Length(0) => Ok(None),
+                Framing::Length(left) => {
+                    let chunk = self.read_some(left, false)?;
+                    self.framing = Framing::Length(left - chunk.len() as u64);
+                    Ok(Some(chunk))
+                }
+                Framing::Close => {
+                    let chunk = self.read_some(u64::MAX, true)?;
+                    if chunk.is_empty() {
+                        self.framing = Framing::Done;
+                        return Ok(None);
+                    }
+                    Ok(Some(chunk))
+                }
+                Framing::Chunked { left: 0 } => {
+                    let line = read_line(&mut self.reader)?;
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let size =
+                        u64::from_str_radix(size, 16).map_err(|_| malformed("chunk size"))?;
+                    if size == 0 {
+                        // Trailers are dropped, as hop-by-hop as the framing they came in
+                        while !read_line(&mut self.reader)?.is_empty() {}
+                        self.framing = Framing::Done;
+                        return Ok(None);
+                    }
+                    self.framing = Framing::Chunked { left: size };
+                    continue;
+                }
+                Framing::Chunked { left } => {
+                    let chunk = self.read_some(left, false)?;
+                    let left = left - chunk.len() as u64;
+                    if left == 0 && !read_line(&mut self.reader)?.is_empty() {
+                        return Err(malformed("chunk"));
+                    }
+                    self.framing = Framing::Chunked { left };
+                    Ok(Some(chunk))
+                }
+            };
+        }
+    }
+}
+
+impl Iterator for UpstreamBody {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.next_chunk();
+        if result.is_err() {
+            self.framing = Framing::Done;
+        }
+        result.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, sync::mpsc, thread};
+
+    /// Starts a stand-in upstream answering every connection with `response`,
+    /// passing on the request heads it gets.
+    fn upstream(response: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (sender, heads) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut head = String::new();
+                while reader.read_line(&mut head).unwrap() > 2 {}
+                let _ = sender.send(head);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (addr, heads)
+    }
+
+    fn request(path: &str, headers: &[(&str, &str)]) -> Request {
+        Request::new("GET", path, headers)
+    }
+
+    fn body(mut response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn parses_routes() {
+        assert_eq!(
+            Ok(ProxyRoute {
+                prefix: "/api".to_string(),
+                upstreams: vec!["localhost:3000".to_string(), "[::1]:3001".to_string()],
+            }),
+            "/api=localhost:3000, [::1]:3001".parse()
+        );
+        for bad in ["api=localhost:3000", "/api", "/api=localhost", "/api=:80"] {
+            assert_eq!(Err(()), bad.parse::<ProxyRoute>(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn forwards_with_rewritten_headers() {
+        let (addr, heads) = upstream(
+            "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\nX-Upstream: yes\r\n\r\n\
+             5\r\nhello\r\n6; ext=1\r\n world\r\n0\r\nTrailer: gone\r\n\r\n",
+        );
+        let route = format!("/api={addr}").parse().unwrap();
+        let proxy = Proxy::new(&[route], Some(Duration::from_secs(5)));
+
+        assert!(proxy.forward(&request("/apiary", &[])).is_none());
+        let mut forwarded = request(
+            "/api/items?id=1",
+            &[
+                ("Host", "example.com"),
+                ("Connection", "X-Secret"),
+                ("X-Secret", "hop"),
+                ("X-Forwarded-For", "10.0.0.1"),
+                ("X-Forwarded-Proto", "https"),
+            ],
+        );
+        forwarded.client = Some("10.0.0.2:5555".parse().unwrap());
+        let response = proxy.forward(&forwarded).unwrap();
+        assert_eq!(201, response.status);
+        assert_eq!(Some("yes"), response.header("X-Upstream"));
+        assert_eq!(None, response.header("Transfer-Encoding"));
+        assert_eq!("hello world", body(response));
+
+        let head = heads.recv().unwrap();
+        assert!(
+            head.starts_with("GET /api/items?id=1 HTTP/1.1\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("Host: example.com\r\n"));
+        assert!(head.contains("X-Forwarded-For: 10.0.0.1, 10.0.0.2\r\n"));
+        // The client doesn't get to say how it reached us
+        assert_eq!(1, head.matches("X-Forwarded-Proto").count(), "{head}");
+        assert!(head.contains("X-Forwarded-Proto: http\r\n"));
+        assert!(head.contains("Connection: close\r\n"));
+        assert!(!head.contains("X-Secret"));
+    }
+
+    #[test]
+    fn round_robin_skips_dead_upstreams() {
+        let (first, first_heads) = upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n1");
+        let (second, second_heads) = upstream("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n2");
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let route = ProxyRoute {
+            prefix: "/".to_string(),
+            upstreams: vec![first, dead.clone(), second],
+        };
+        let proxy = Proxy::new(&[route], Some(Duration::from_secs(5)));
+
+        let bodies: Vec<String> = (0..6)
+            .map(|_| body(proxy.forward(&request("/", &[])).unwrap()))
+            .collect();
+        // The dead upstream's turns go to the one after it
+        assert_eq!(vec!["1", "2", "2", "1", "2", "2"], bodies);
+        assert_eq!(2, first_heads.try_iter().count());
+        assert_eq!(4, second_heads.try_iter().count());
+
+        // Already marked down, so health checks have nothing new to report
+        assert!(proxy.check_health().is_empty());
+    }
+
+    #[test]
+    fn upstream_errors() {
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let route = ProxyRoute {
+            prefix: "/slow".to_string(),
+            upstreams: vec![silent.local_addr().unwrap().to_string()],
+        };
+        let proxy = Proxy::new(&[route], Some(Duration::from_millis(100)));
+        assert_eq!(504, proxy.forward(&request("/slow", &[])).unwrap().status);
+
+        let addr = silent.local_addr().unwrap().to_string();
+        drop(silent);
+        assert_eq!(502, proxy.forward(&request("/slow", &[])).unwrap().status);
+        // The failed forward already marked it down
+        assert!(proxy.check_health().is_empty());
+
+        let _back = TcpListener::bind(&addr).unwrap();
+        assert_eq!(vec![(addr, true)], proxy.check_health());
+    }
+}
@@ -0,0 +1,306 @@
+use crate::http::{Request, Response};
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    sync::Arc,
+};
+
+/// Most ranges served in one response, past which the whole body is sent
+/// rather than let a client ask for the same bytes over and over.
+const MAX_RANGES: usize = 16;
+
+/// Separates the parts of a `multipart/byteranges` body.
+const BOUNDARY: &str = "byteranges-7f3a9c2e51d4b806";
+
+/// The parts of a body a request's `Range` header asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ranges {
+    /// All of it, as there was no range to honour or `If-Range` didn't match
+    All,
+    /// These byte ranges, in the order asked for
+    Partial(Vec<Range<u64>>),
+    /// None of the ranges asked for are in the body (`416`)
+    Unsatisfiable,
+}
+
+impl Ranges {
+    /// The ranges `request` asks for of a body `len` bytes long, whose
+    /// current validators are `etag` and `last_modified`.
+    ///
+    /// A `Range` header that can't be parsed is ignored, as is one whose
+    /// `If-Range` names another version of the body.
+    pub fn for_request(request: &Request, len: u64, etag: &str, last_modified: &str) -> Ranges {
+        let Some(header) = request.header("Range") else {
+            return Ranges::All;
+        };
+        if request.method != "GET" {
+            return Ranges::All;
+        }
+        if let Some(validator) = request.header("If-Range") {
+            if validator != etag && validator != last_modified {
+                return Ranges::All;
+            }
+        }
+        Ranges::parse(header, len)
+    }
+
+    fn parse(header: &str, len: u64) -> Ranges {
+        let specs = match header.split_once('=') {
+            Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+            _ => return Ranges::All,
+        };
+        let specs: Vec<&str> = specs
+            .split(',')
+            .map(str::trim)
+            .filter(|spec| !spec.is_empty())
+            .collect();
+        if specs.is_empty() || specs.len() > MAX_RANGES {
+            return Ranges::All;
+        }
+
+        let mut ranges = Vec::new();
+        for spec in specs {
+            let Some((first, last)) = spec.split_once('-') else {
+                return Ranges::All;
+            };
+            let range = match (first.trim(), last.trim()) {
+                // The last `n` bytes
+                ("", suffix) => match number(suffix) {
+                    Some(n) => len.saturating_sub(n)..len,
+                    None => return Ranges::All,
+                },
+                (first, "") => match number(first) {
+                    Some(first) => first..len,
+                    None => return Ranges::All,
+                },
+                (first, last) => match (number(first), number(last)) {
+                    (Some(first), Some(last)) if first <= last => first..len.min(last + 1),
+                    _ => return Ranges::All,
+                },
+            };
+            if range.start < range.end {
+                ranges.push(range);
+            }
+        }
+        if ranges.is_empty() {
+            Ranges::Unsatisfiable
+        } else {
+            Ranges::Partial(ranges)
+        }
+    }
+}
+
+/// Digits only, where `str::parse` would take a sign too.
+fn number(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Where the bytes of a static file come from.
+pub enum Source {
+    /// Already in memory, as the cache has it
+    Memory(Arc<[u8]>),
+    /// Read from the file as it's sent
+    File(File),
+}
+
+impl Source {
+    /// All of the body, with `status`.
+    pub fn whole(self, status: u16) -> Response {
+        match self {
+            Source::Memory(contents) => Response::new(status).with_body(contents.to_vec()),
+            Source::File(file) => Response::new(status).with_file(file),
+        }
+    }
+
+    /// `206 Partial Content` with `ranges` of the body, which is `len` bytes
+    /// long: the one range as the body, or each range as one part of a
+    /// `multipart/byteranges` body.
+    pub fn partial(self, ranges: &[Range<u64>], len: u64, content_type: &str) -> Response {
+        let content_range =
+            |range: &Range<u64>| format!("bytes {}-{}/{len}", range.start, range.end - 1);
+        if let [range] = ranges {
+            let response = Response::new(206)
+                .with_header("Content-Type", content_type)
+                .with_header("Content-Range", content_range(range));
+            return match self {
+                Source::Memory(contents) => {
+                    response.with_body(&contents[range.start as usize..range.end as usize])
+                }
+                Source::File(mut file) => {
+                    let range = range.clone();
+                    response.with_writer(move |writer| copy_range(&mut file, &range, writer))
+                }
+            };
+        }
+
+        let parts: Vec<(String, Range<u64>)> = ranges
+            .iter()
+            .map(|range| {
+                let head = format!(
+                    "--{BOUNDARY}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+                    content_range(range)
+                );
+                (head, range.clone())
+            })
+            .collect();
+        let end = format!("--{BOUNDARY}--\r\n");
+        let response = Response::new(206).with_header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={BOUNDARY}"),
+        );
+        match self {
+            Source::Memory(contents) => {
+                let mut body = Vec::new();
+                for (head, range) in &parts {
+                    body.extend_from_slice(head.as_bytes());
+                    body.extend_from_slice(&contents[range.start as usize..range.end as usize]);
+                    body.extend_from_slice(b"\r\n");
+                }
+                body.extend_from_slice(end.as_bytes());
+                response.with_body(body)
+            }
+            Source::File(mut file) => response.with_writer(move |writer| {
+                for (head, range) in &parts {
+                    writer.write_all(head.as_bytes())?;
+                    copy_range(&mut file, range, writer)?;
+                    writer.write_all(b"\r\n")?;
+                }
+                writer.write_all(end.as_bytes())
+            }),
+        }
+    }
+}
+
+/// `416 Range Not Satisfiable` for a body `len` bytes long.
+pub fn unsatisfiable(len: u64) -> Response {
+    Response::new(416).with_header("Content-Range", format!("bytes */{len}"))
+}
+
+fn copy_range(file: &mut File, range: &Range<u64>, writer: &mut dyn Write) -> io::Result<()> {
+    file.seek(SeekFrom::Start(range.start))?;
+    let wanted = range.end - range.start;
+    // The file may have shrunk since its length was taken
+    if io::copy(&mut file.take(wanted), writer)? < wanted {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file shrank while being sent",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Body;
+    use std::fs;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request::new("GET", "/", headers)
+    }
+
+    fn spans(pairs: &[(u64, u64)]) -> Vec<Range<u64>> {
+        pairs.iter().map(|&(start, end)| start..end).collect()
+    }
+
+    fn partial(pairs: &[(u64, u64)]) -> Ranges {
+        Ranges::Partial(spans(pairs))
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let parse = |header| Ranges::parse(header, 100);
+        assert_eq!(partial(&[(0, 10)]), parse("bytes=0-9"));
+        assert_eq!(partial(&[(90, 100)]), parse("bytes=90-"));
+        assert_eq!(partial(&[(80, 100)]), parse("bytes=-20"));
+        assert_eq!(partial(&[(0, 100)]), parse("bytes=-500"));
+        assert_eq!(partial(&[(50, 100)]), parse("Bytes=50-999"));
+        assert_eq!(
+            partial(&[(0, 1), (99, 100)]),
+            parse("bytes=0-0, 100-200, -1")
+        );
+        assert_eq!(Ranges::Unsatisfiable, parse("bytes=100-"));
+        assert_eq!(Ranges::Unsatisfiable, parse("bytes=-0"));
+        for ignored in [
+            "items=0-9",
+            "bytes=",
+            "bytes=9-0",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=5",
+        ] {
+            assert_eq!(Ranges::All, parse(ignored), "{ignored}");
+        }
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(Ranges::All, parse(&many));
+    }
+
+    #[test]
+    fn honours_if_range() {
+        let ranges = |headers: &[(&str, &str)]| {
+            Ranges::for_request(
+                &request(headers),
+                100,
+                "\"v1\"",
+                "Sun, 06 Nov 1994 08:49:37 GMT",
+            )
+        };
+        assert_eq!(Ranges::All, ranges(&[]));
+        assert_eq!(
+            partial(&[(0, 10)]),
+            ranges(&[("Range", "bytes=0-9"), ("If-Range", "\"v1\"")])
+        );
+        assert_eq!(
+            partial(&[(0, 10)]),
+            ranges(&[
+                ("Range", "bytes=0-9"),
+                ("If-Range", "Sun, 06 Nov 1994 08:49:37 GMT")
+            ])
+        );
+        assert_eq!(
+            Ranges::All,
+            ranges(&[("Range", "bytes=0-9"), ("If-Range", "\"v0\"")])
+        );
+    }
+
+    #[test]
+    fn serves_single_and_multiple_ranges() {
+        let contents: Arc<[u8]> = b"0123456789".to_vec().into();
+        let single =
+            Source::Memory(Arc::clone(&contents)).partial(&spans(&[(2, 5)]), 10, "text/plain");
+        assert_eq!(206, single.status);
+        assert_eq!(Some("bytes 2-4/10"), single.header("Content-Range"));
+        assert!(matches!(single.body, Body::Full(ref body) if body == b"234"));
+
+        let expected = format!(
+            "--{BOUNDARY}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+            --{BOUNDARY}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+            --{BOUNDARY}--\r\n"
+        );
+        let multiple = Source::Memory(contents).partial(&[0..2, 8..10], 10, "text/plain");
+        assert_eq!(
+            Some(format!("multipart/byteranges; boundary={BOUNDARY}").as_str()),
+            multiple.header("Content-Type")
+        );
+        assert!(matches!(multiple.body, Body::Full(ref body) if body == expected.as_bytes()));
+
+        // The same parts read from the file, unframed
+        let path = std::env::temp_dir().join(format!("range_test_{}", std::process::id()));
+        fs::write(&path, "0123456789").unwrap();
+        let mut multiple =
+            Source::File(File::open(&path).unwrap()).partial(&[0..2, 8..10], 10, "text/plain");
+        let mut out = Vec::new();
+        multiple.write_to(&mut out, false).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with(&expected));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            Some("bytes */10"),
+            unsatisfiable(10).header("Content-Range")
+        );
+    }
+}
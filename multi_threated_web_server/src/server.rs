@@ -0,0 +1,1020 @@
+#[cfg(target_os = "linux")]
+use crate::event_loop;
+use crate::{
+    access_log::{AccessEntry, AccessLog},
+    builder::ServerBuilder,
+    cache::{FileCache, Lookup},
+    compress,
+    config::{Config, IoMode, LogLevel},
+    http::{self, Request, RequestError, Response, Upgrade},
+    middleware::{Middleware, Next},
+    pool::{CancellationToken, Histogram, PoolMonitor, PoolStats},
+    proxy::Proxy,
+    range::{self, Ranges, Source},
+    sse,
+    transport::Transport,
+    websocket, ThreadPool,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufReader},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+/// How often slow handlers check whether their client is still there.
+const CANCEL_CHECK: Duration = Duration::from_millis(50);
+
+/// What handlers can use besides the config, each optional.
+#[derive(Clone, Default)]
+pub struct Services {
+    /// The pool serving requests, whose stats are served at `config.metrics_path`
+    pub pool: Option<PoolMonitor>,
+    /// Forwards the requests under its routes
+    pub proxy: Option<Arc<Proxy>>,
+    /// Keeps pages in memory instead of reading them for every request
+    pub cache: Option<Arc<FileCache>>,
+    /// Runs around every request, first registered outermost
+    pub middleware: Arc<[Box<dyn Middleware>]>,
+    /// WebSocket handlers by path
+    pub websockets: Arc<HashMap<String, websocket::Handler>>,
+    /// Server-sent event channels by the path clients subscribe at
+    pub event_streams: Arc<HashMap<String, sse::Channel>>,
+}
+
+/// A server with its listeners bound and its pool started, see [`ServerBuilder`].
+pub struct Server {
+    pub(crate) config: Arc<Config>,
+    pub(crate) pool: ThreadPool,
+    pub(crate) services: Services,
+    pub(crate) access_log: Arc<Option<AccessLog>>,
+    pub(crate) listeners: Vec<TcpListener>,
+    #[cfg(unix)]
+    pub(crate) unix_listener: Option<UnixListener>,
+    /// Set to have [`run`](Server::run) return
+    pub(crate) stopping: Arc<AtomicBool>,
+}
+
+impl Server {
+    pub fn builder(config: Config) -> ServerBuilder {
+        ServerBuilder::new(config)
+    }
+
+    /// The addresses of the TCP listeners, with the ports picked for any
+    /// bound to port 0.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    /// Serves connections on every listener until the process exits, or the
+    /// server is shut down if it was [spawned](Server::spawn), in the
+    /// configured I/O mode. The Unix socket is always served in blocking mode.
+    pub fn run(&self) {
+        let Server {
+            config,
+            pool,
+            services,
+            access_log,
+            ..
+        } = self;
+        let stopping = &*self.stopping;
+        // One accept loop per listener, all feeding the same pool
+        thread::scope(|s| {
+            for listener in &self.listeners {
+                match config.io {
+                    IoMode::Blocking => {
+                        s.spawn(|| {
+                            let incoming = listener
+                                .incoming()
+                                .take_while(|_| !stopping.load(Ordering::SeqCst));
+                            serve(incoming, pool, config, services, access_log);
+                        });
+                    }
+                    #[cfg(target_os = "linux")]
+                    IoMode::Epoll => {
+                        for _ in 0..config.event_loops {
+                            s.spawn(|| {
+                                if let Err(err) = event_loop::serve_until(
+                                    listener, pool, config, services, access_log, stopping,
+                                ) {
+                                    eprintln!("Event loop failed: {err}");
+                                    process::exit(1);
+                                }
+                            });
+                        }
+                    }
+                    #[cfg(not(target_os = "linux"))]
+                    IoMode::Epoll => unreachable!("only accepted on Linux"),
+                }
+            }
+            #[cfg(unix)]
+            if let Some(listener) = &self.unix_listener {
+                s.spawn(|| {
+                    let incoming = listener
+                        .incoming()
+                        .take_while(|_| !stopping.load(Ordering::SeqCst));
+                    serve(incoming, pool, config, services, access_log);
+                });
+            }
+        });
+    }
+
+    /// Runs the server on a thread of its own, returning a handle to shut it
+    /// down with.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the thread can't be spawned.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let addrs = self.local_addrs();
+        #[cfg(unix)]
+        let unix = self
+            .unix_listener
+            .as_ref()
+            .and_then(|_| self.config.unix.clone());
+        let stopping = Arc::clone(&self.stopping);
+        // Dropping the server when it stops waits for the pool to finish its jobs
+        let thread = thread::Builder::new()
+            .name("server".to_string())
+            .spawn(move || self.run())?;
+        Ok(ServerHandle {
+            addrs,
+            #[cfg(unix)]
+            unix,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A server running in the background, see [`Server::spawn`]. Dropping the
+/// handle shuts the server down.
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+    #[cfg(unix)]
+    unix: Option<PathBuf>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The address of the first TCP listener.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Stops accepting connections and waits for the server to stop. Requests
+    /// already with a worker are finished first in blocking mode, while
+    /// connections an event loop still holds are dropped.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        self.stopping.store(true, Ordering::SeqCst);
+
+        // A blocking accept loop only sees the flag once a connection comes in
+        for addr in &self.addrs {
+            let mut addr = *addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect(addr);
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.unix {
+            let _ = UnixStream::connect(path);
+        }
+
+        // A panicking accept loop has already reported itself
+        let _ = thread.join();
+        #[cfg(unix)]
+        if let Some(path) = &self.unix {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Accepts connections and hands each one to the pool, which serves it on a
+/// worker from start to finish.
+pub fn serve<T>(
+    incoming: impl Iterator<Item = io::Result<T>>,
+    pool: &ThreadPool,
+    config: &Arc<Config>,
+    services: &Services,
+    access_log: &Arc<Option<AccessLog>>,
+) where
+    T: Transport + Send + 'static,
+{
+    for stream in incoming {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                if config.log_level >= LogLevel::Warn {
+                    eprintln!("Failed to accept connection: {err}");
+                }
+                continue;
+            }
+        };
+        let mut queued = Queued {
+            stream: Some(stream),
+            config: Arc::clone(config),
+        };
+        let access_log = Arc::clone(access_log);
+        let services = services.clone();
+        let result = pool.execute(move || {
+            let stream = queued.stream.take().unwrap();
+            let config = &queued.config;
+            match handle_connection(stream, config, &services) {
+                Ok(Some(entry)) => {
+                    if let Some(access_log) = access_log.as_ref() {
+                        access_log.log(&entry);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    if config.log_level >= LogLevel::Warn {
+                        eprintln!("Failed to handle connection: {err}");
+                    }
+                }
+            }
+        });
+        // The rejected job, and the client with it, has already been sent a 503
+        if let Err(err) = result {
+            if config.log_level >= LogLevel::Debug {
+                eprintln!("Turned a connection away: {err}");
+            }
+        }
+    }
+}
+
+/// A connection waiting for a worker. If its job is dropped without running,
+/// because the queue was full or the pool shut down, the client gets a 503
+/// instead of a bare hang-up.
+struct Queued<T: Transport> {
+    stream: Option<T>,
+    config: Arc<Config>,
+}
+
+impl<T: Transport> Drop for Queued<T> {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = reject_connection(stream, &self.config);
+        }
+    }
+}
+
+/// Serves one request, returning what happened for the access log.
+///
+/// Returns `Ok(None)` when the client hung up without sending a request.
+pub fn handle_connection<T: Transport + Send + 'static>(
+    stream: T,
+    config: &Config,
+    services: &Services,
+) -> io::Result<Option<AccessEntry>> {
+    let started = Instant::now();
+    stream.set_write_timeout(config.write_timeout)?;
+    let client = stream.peer_addr();
+
+    let mut reader = BufReader::new(stream);
+    let mut request = match http::read_request(&mut reader, &config.limits()) {
+        Ok(request) => request,
+        Err(RequestError::Closed) => return Ok(None),
+        Err(RequestError::Io(err)) => return Err(err),
+        Err(err) => {
+            // Tell the client why we're hanging up, on a best effort basis since it may be long gone
+            let status = err.status().unwrap_or(400);
+            let _ = bad_request(status).write_to(reader.get_mut(), false);
+            return Ok(Some(access_entry(None, client, status, 0, started)));
+        }
+    };
+
+    request.client = client;
+    let mut response = respond(&mut request, config, services, None)?;
+    let status = response.status;
+
+    // HTTP/1.0 clients can't decode chunked bodies
+    let chunked = request.version != "HTTP/1.0";
+    let bytes = response.write_to(reader.get_mut(), chunked)?;
+    if let Some(upgrade) = response.upgrade {
+        let rest = reader.buffer().to_vec();
+        hand_over(upgrade, Box::new(reader.into_inner()), rest)?;
+    }
+
+    Ok(Some(access_entry(
+        Some(request),
+        client,
+        status,
+        bytes,
+        started,
+    )))
+}
+
+/// Gives a connection that has switched protocols to its new owner, on a
+/// thread of its own as it stays open for as long as the client likes.
+pub(crate) fn hand_over(
+    upgrade: Upgrade,
+    stream: Box<dyn Transport + Send>,
+    rest: Vec<u8>,
+) -> io::Result<()> {
+    thread::Builder::new()
+        .name("upgraded-connection".to_string())
+        .spawn(move || upgrade(stream, rest))
+        .map(drop)
+}
+
+/// The answer to a request head that couldn't be read, before hanging up.
+pub(crate) fn bad_request(status: u16) -> Response {
+    Response::new(status).with_header("Connection", "close")
+}
+
+/// Describes a served request for the access log, `None` for one that
+/// couldn't be read.
+pub(crate) fn access_entry(
+    request: Option<Request>,
+    client: Option<SocketAddr>,
+    status: u16,
+    bytes: u64,
+    started: Instant,
+) -> AccessEntry {
+    let mut entry = AccessEntry {
+        time: SystemTime::now(),
+        client,
+        method: "-".to_string(),
+        path: "-".to_string(),
+        version: "-".to_string(),
+        status,
+        bytes,
+        latency: started.elapsed(),
+        worker: ThreadPool::current_worker_id(),
+        referer: None,
+        user_agent: None,
+    };
+    if let Some(request) = request {
+        entry.referer = request.header("Referer").map(String::from);
+        entry.user_agent = request.header("User-Agent").map(String::from);
+        entry.method = request.method;
+        entry.path = request.path;
+        entry.version = request.version;
+    }
+    entry
+}
+
+/// Turns a client away with `503 Service Unavailable` when there is no room
+/// to queue its connection, without reading its request.
+pub fn reject_connection<T: Transport>(mut stream: T, config: &Config) -> io::Result<()> {
+    stream.set_write_timeout(config.write_timeout)?;
+    unavailable().write_to(&mut stream, false)?;
+    stream.flush()
+}
+
+pub(crate) fn unavailable() -> Response {
+    Response::new(503).with_header("Retry-After", "1")
+}
+
+/// Builds the response to a request that has already been read.
+///
+/// The request goes through the middleware first, and handlers that fail
+/// answer `500 Internal Server Error`. Slow handlers give up early once
+/// `cancel` is cancelled, which callers that can tell the client has gone use
+/// to stop working for nobody.
+///
+/// # Errors
+///
+/// Returns Err() if the handler was cancelled.
+pub fn respond(
+    request: &mut Request,
+    config: &Config,
+    services: &Services,
+    cancel: Option<&CancellationToken>,
+) -> io::Result<Response> {
+    let cancelled = || cancel.is_some_and(CancellationToken::is_cancelled);
+    let handler = |request: &mut Request| match route(request, config, services, cancel) {
+        Ok(response) => response,
+        Err(err) => {
+            if config.log_level >= LogLevel::Warn && !cancelled() {
+                eprintln!(
+                    "Failed to handle {} {}: {err}",
+                    request.method, request.path
+                );
+            }
+            Response::new(500)
+        }
+    };
+    let response = Next::new(&services.middleware, &handler).run(request);
+    if cancelled() {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "client went away",
+        ));
+    }
+    Ok(match config.compress_min_size {
+        Some(min_size) => compress::compress_response(request, response, min_size),
+        None => response,
+    })
+}
+
+fn route(
+    request: &Request,
+    config: &Config,
+    services: &Services,
+    cancel: Option<&CancellationToken>,
+) -> io::Result<Response> {
+    if let (Some(path), Some(pool)) = (&config.metrics_path, &services.pool) {
+        if request.method == "GET" && request.path == *path {
+            return Ok(Response::new(200)
+                .with_header("Content-Type", "text/plain; version=0.0.4")
+                .with_body(prometheus(&pool.stats())));
+        }
+    }
+    let path = request
+        .path
+        .split_once('?')
+        .map_or(request.path.as_str(), |(path, _)| path);
+    if let Some(handler) = services.websockets.get(path) {
+        let handler = Arc::clone(handler);
+        return Ok(websocket::accept(request, move |socket| handler(socket)));
+    }
+    if let Some(channel) = services.event_streams.get(path) {
+        if request.method == "GET" {
+            return Ok(channel.subscribe(request));
+        }
+    }
+    if let Some(response) = services
+        .proxy
+        .as_ref()
+        .and_then(|proxy| proxy.forward(request))
+    {
+        return Ok(response);
+    }
+
+    let (status, filename) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => (200, "200.html"),
+        ("GET", "/sleep") => {
+            pause(Duration::from_secs(5), cancel)?; // loads after 5s
+            (200, "200.html")
+        }
+        _ => (404, "404.html"),
+    };
+
+    let path = config.root.join(filename);
+    let (len, modified, source) = match &services.cache {
+        Some(cache) => {
+            // The validators must be of the version sent, not whatever is on disk now
+            let (contents, modified) = cache.get(&path, Lookup::for_request(request))?;
+            (contents.len() as u64, modified, Source::Memory(contents))
+        }
+        None => {
+            let file = File::open(&path)?;
+            let metadata = file.metadata()?;
+            (metadata.len(), metadata.modified()?, Source::File(file))
+        }
+    };
+    let content_type = "text/html; charset=utf-8";
+    let mut response = if status == 200 {
+        let etag = format!(
+            "\"{len:x}-{:x}\"",
+            modified
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos())
+        );
+        let last_modified = http::http_date(modified);
+        match Ranges::for_request(request, len, &etag, &last_modified) {
+            Ranges::All => source
+                .whole(status)
+                .with_header("Content-Type", content_type),
+            Ranges::Partial(ranges) => source.partial(&ranges, len, content_type),
+            Ranges::Unsatisfiable => range::unsatisfiable(len),
+        }
+        .with_header("Accept-Ranges", "bytes")
+        .with_header("ETag", etag)
+        .with_header("Last-Modified", last_modified)
+    } else {
+        source
+            .whole(status)
+            .with_header("Content-Type", content_type)
+    };
+    if let Some(cache) = &services.cache {
+        response = response.with_header("Cache-Control", cache.cache_control());
+    }
+    Ok(response)
+}
+
+/// Sleeps for `duration`, or until `cancel` is cancelled.
+fn pause(duration: Duration, cancel: Option<&CancellationToken>) -> io::Result<()> {
+    let deadline = Instant::now() + duration;
+    loop {
+        if cancel.is_some_and(CancellationToken::is_cancelled) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "client went away",
+            ));
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(());
+        }
+        thread::sleep(match cancel {
+            Some(_) => left.min(CANCEL_CHECK),
+            None => left,
+        });
+    }
+}
+
+/// Renders pool stats in the Prometheus text exposition format.
+fn prometheus(stats: &PoolStats) -> String {
+    let values = [
+        (
+            "pool_threads",
+            "gauge",
+            "Worker threads running.",
+            stats.threads as u64,
+        ),
+        (
+            "pool_active_workers",
+            "gauge",
+            "Workers running a job.",
+            stats.active as u64,
+        ),
+        (
+            "pool_queued_jobs",
+            "gauge",
+            "Jobs waiting for a worker.",
+            stats.queued as u64,
+        ),
+        (
+            "pool_jobs_completed_total",
+            "counter",
+            "Jobs that ran to completion.",
+            stats.completed,
+        ),
+        (
+            "pool_jobs_panicked_total",
+            "counter",
+            "Jobs that panicked.",
+            stats.panicked,
+        ),
+        (
+            "pool_jobs_rejected_total",
+            "counter",
+            "Jobs turned away by a full queue.",
+            stats.rejected,
+        ),
+        (
+            "pool_jobs_dropped_total",
+            "counter",
+            "Jobs pushed out of a full queue.",
+            stats.dropped,
+        ),
+        (
+            "pool_jobs_cancelled_total",
+            "counter",
+            "Jobs skipped because they were cancelled before starting.",
+            stats.cancelled,
+        ),
+    ];
+    let histograms: [(&str, &str, &Histogram); 2] = [
+        (
+            "pool_queue_wait_seconds",
+            "Time jobs spent waiting for a worker.",
+            &stats.queue_wait,
+        ),
+        (
+            "pool_execution_seconds",
+            "Time jobs spent running.",
+            &stats.execution,
+        ),
+    ];
+
+    // Writing to a String can't fail
+    let mut out = String::new();
+    for (name, kind, help, value) in values {
+        let _ = writeln!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+        );
+    }
+    for (name, help, histogram) in histograms {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        for (bound, count) in &histogram.buckets {
+            let le = bound.as_secs_f64();
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", histogram.count);
+        let _ = writeln!(out, "{name}_sum {}", histogram.sum.as_secs_f64());
+        let _ = writeln!(out, "{name}_count {}", histogram.count);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryStream;
+    use std::{
+        fs,
+        io::prelude::*,
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+    };
+
+    /// Sends `request` over a memory stream and returns everything the handler wrote back.
+    fn exchange(request: &str) -> (String, Option<AccessEntry>) {
+        let (mut client, server) = MemoryStream::pair();
+        client.write_all(request.as_bytes()).unwrap();
+
+        let entry = handle_connection(server, &Config::default(), &Services::default()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        (response, entry)
+    }
+
+    #[test]
+    fn serves_index() {
+        let (response, entry) = exchange("GET / HTTP/1.1\r\nUser-Agent: test\r\n\r\n");
+        let body = fs::read_to_string("200.html").unwrap();
+        let modified = fs::metadata("200.html").unwrap().modified().unwrap();
+        let since = modified.duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        assert_eq!(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\
+                 Accept-Ranges: bytes\r\nETag: \"{len:x}-{since:x}\"\r\n\
+                 Last-Modified: {}\r\n\
+                 Vary: Accept-Encoding\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 {len:x}\r\n{body}\r\n0\r\n\r\n",
+                http::http_date(modified),
+                len = body.len()
+            ),
+            response
+        );
+
+        let entry = entry.unwrap();
+        assert_eq!(
+            ("GET", "/", 200),
+            (&entry.method[..], &entry.path[..], entry.status)
+        );
+        assert_eq!(Some("test"), entry.user_agent.as_deref());
+        assert_eq!(None, entry.client);
+    }
+
+    #[test]
+    fn runs_middleware_around_handlers() {
+        let tag = |request: &mut Request, next: Next<'_>| {
+            let client = request.client.is_some().to_string();
+            next.run(request).with_header("X-Saw-Client", client)
+        };
+        let middleware: Vec<Box<dyn Middleware>> = vec![Box::new(tag)];
+        let services = Services {
+            middleware: middleware.into(),
+            ..Services::default()
+        };
+        // The pages aren't there to be read
+        let config = Config {
+            root: "src".into(),
+            ..Config::default()
+        };
+
+        let (mut client, server) = MemoryStream::pair();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let entry = handle_connection(server, &config, &services).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.contains("\r\nX-Saw-Client: false\r\n"));
+        assert_eq!(500, entry.unwrap().status);
+    }
+
+    #[test]
+    fn unknown_path_is_404() {
+        let (response, entry) = exchange("GET /nope HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert_eq!(404, entry.unwrap().status);
+    }
+
+    #[test]
+    fn compresses_for_clients_that_accept_it() {
+        let (mut client, server) = MemoryStream::pair();
+        client
+            .write_all(b"GET /nope HTTP/1.1\r\nAccept-Encoding: br, gzip\r\n\r\n")
+            .unwrap();
+        handle_connection(server, &Config::default(), &Services::default()).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.contains("\r\nVary: Accept-Encoding\r\n"));
+        assert!(response.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert!(response.contains("\r\nTransfer-Encoding: chunked\r\n"));
+    }
+
+    #[test]
+    fn serves_pool_metrics() {
+        let pool = ThreadPool::build(1).unwrap();
+        pool.execute(|| ()).unwrap();
+        while pool.stats().completed < 1 {
+            thread::yield_now();
+        }
+        let config = Config {
+            metrics_path: Some("/metrics".to_string()),
+            ..Config::default()
+        };
+
+        let (mut client, server) = MemoryStream::pair();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let services = Services {
+            pool: Some(pool.monitor()),
+            ..Services::default()
+        };
+        handle_connection(server, &config, &services).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE pool_threads gauge\npool_threads 1\n"));
+        assert!(response.contains("pool_execution_seconds_bucket{le=\"+Inf\"} 1\n"));
+
+        // Not served unless asked for
+        let (response, _) = exchange("GET /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn serves_cached_pages() {
+        let services = Services {
+            cache: Some(Arc::new(FileCache::new(
+                1024 * 1024,
+                Some(Duration::from_secs(60)),
+            ))),
+            ..Services::default()
+        };
+        let body = fs::read_to_string("200.html").unwrap();
+        for _ in 0..2 {
+            let (mut client, server) = MemoryStream::pair();
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            handle_connection(server, &Config::default(), &services).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.contains("Cache-Control: max-age=60\r\n"));
+            assert!(response.ends_with(&format!("Content-Length: {}\r\n\r\n{body}", body.len())));
+        }
+        assert_eq!(1, services.cache.unwrap().size().0);
+    }
+
+    #[test]
+    fn forwards_proxied_requests() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let route = format!("/api={}", upstream.local_addr().unwrap());
+        let services = Services {
+            proxy: Some(Arc::new(Proxy::new(&[route.parse().unwrap()], None))),
+            ..Services::default()
+        };
+        let echo = thread::spawn(move || {
+            let (stream, _) = upstream.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).unwrap() > 2 {}
+            let mut body = [0; 5];
+            reader.read_exact(&mut body).unwrap();
+            let body = String::from_utf8_lossy(&body);
+            write!(reader.get_mut(), "HTTP/1.1 200 OK\r\n\r\n{body}").unwrap();
+            head
+        });
+
+        let (mut client, server) = MemoryStream::pair();
+        client
+            .write_all(b"POST /api/echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+            .unwrap();
+        let entry = handle_connection(server, &Config::default(), &services).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(
+            response.ends_with("\r\n5\r\nhello\r\n0\r\n\r\n"),
+            "{response}"
+        );
+        assert_eq!(200, entry.unwrap().status);
+
+        let head = echo.join().unwrap();
+        assert!(head.starts_with("POST /api/echo HTTP/1.1\r\n"));
+        assert!(head.contains("Content-Length: 5\r\n"));
+    }
+
+    #[test]
+    fn upgrades_websockets() {
+        let echo: websocket::Handler = Arc::new(|mut socket| {
+            while let Ok(Some(message)) = socket.recv() {
+                socket.send(&message).unwrap();
+            }
+        });
+        let services = Services {
+            websockets: Arc::new(HashMap::from([("/ws".to_string(), echo)])),
+            ..Services::default()
+        };
+
+        let (mut client, server) = MemoryStream::pair();
+        // A frame right behind the handshake, masked with a key of zeros
+        client
+            .write_all(
+                b"GET /ws?room=1 HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n\
+                \x81\x82\0\0\0\0hi",
+            )
+            .unwrap();
+        let entry = handle_connection(server, &Config::default(), &services).unwrap();
+        assert_eq!(101, entry.unwrap().status);
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!head.contains("Content-Length"));
+        let mut frame = [0; 4];
+        client.read_exact(&mut frame).unwrap();
+        assert_eq!(b"\x81\x02hi", &frame);
+
+        client.write_all(b"\x88\x80\0\0\0\0").unwrap();
+        let mut close = Vec::new();
+        client.read_to_end(&mut close).unwrap();
+        assert_eq!(b"\x88\x00", &close[..]);
+
+        let (mut client, server) = MemoryStream::pair();
+        client.write_all(b"GET /ws HTTP/1.1\r\n\r\n").unwrap();
+        let entry = handle_connection(server, &Config::default(), &services).unwrap();
+        assert_eq!(426, entry.unwrap().status);
+    }
+
+    #[test]
+    fn streams_events() {
+        let channel = sse::Channel::new(16);
+        let services = Services {
+            event_streams: Arc::new(HashMap::from([("/events".to_string(), channel.clone())])),
+            ..Services::default()
+        };
+        let (mut client, server) = MemoryStream::pair();
+        client.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+        let entry = handle_connection(server, &Config::default(), &services).unwrap();
+        assert_eq!(200, entry.unwrap().status);
+
+        // Joins on a thread of its own once the head is out
+        while channel.subscribers() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        channel.send(&sse::Event::new("ready").named("status"));
+        drop(channel);
+        drop(services);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!response.contains("Content-Length"));
+        assert!(
+            response.ends_with("\r\n\r\nid: 1\nevent: status\ndata: ready\n\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn serves_ranges_of_pages() {
+        let body = fs::read_to_string("200.html").unwrap();
+        let (response, entry) = exchange("GET / HTTP/1.1\r\nRange: bytes=0-14\r\n\r\n");
+        assert_eq!(206, entry.unwrap().status);
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        let range = format!("Content-Range: bytes 0-14/{}\r\n", body.len());
+        assert!(response.contains(&range));
+        assert!(response.ends_with(&format!("f\r\n{}\r\n0\r\n\r\n", &body[..15])));
+
+        // Cached pages are sliced from memory, and a stale If-Range gets all of it
+        let services = Services {
+            cache: Some(Arc::new(FileCache::new(1024 * 1024, None))),
+            ..Services::default()
+        };
+        let ranged = |headers: &str| {
+            let (mut client, server) = MemoryStream::pair();
+            write!(client, "GET / HTTP/1.1\r\n{headers}\r\n").unwrap();
+            handle_connection(server, &Config::default(), &services).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = ranged("Range: bytes=-5\r\n");
+        assert!(response.contains("Content-Length: 5\r\n"));
+        assert!(response.ends_with(&body[body.len() - 5..]));
+        let response = ranged("Range: bytes=0-0\r\nIf-Range: \"stale\"\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = ranged(&format!("Range: bytes={}-\r\n", body.len()));
+        assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+    }
+
+    #[test]
+    fn client_hangs_up_without_request() {
+        let (client, server) = MemoryStream::pair();
+        drop(client);
+        assert!(
+            handle_connection(server, &Config::default(), &Services::default())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn slow_client_gets_408() {
+        let config = Config {
+            header_timeout: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let (mut client, server) = MemoryStream::pair();
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+        let entry = handle_connection(server, &config, &Services::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(408, entry.status);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn rejected_client_gets_503() {
+        let (mut client, server) = MemoryStream::pair();
+        reject_connection(server, &Config::default()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Retry-After: 1\r\n"));
+    }
+
+    #[test]
+    fn stays_responsive_under_slow_clients() {
+        let config = Arc::new(Config {
+            header_timeout: Some(Duration::from_millis(300)),
+            ..Config::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = ThreadPool::build(2).unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let config = Arc::clone(&config);
+                pool.execute(move || {
+                    let _ = handle_connection(stream, &config, &Services::default());
+                })
+                .unwrap();
+            }
+        });
+
+        // Twice as many connections as workers, none of which ever send a byte
+        let idle: Vec<TcpStream> = (0..4).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        // Each worker waits out two idle clients at most before getting to us
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        for mut stream in idle {
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        }
+    }
+}
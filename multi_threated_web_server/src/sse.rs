@@ -0,0 +1,347 @@
+use crate::{
+    http::{Request, Response},
+    transport::Transport,
+};
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::Write,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+/// Longest one write to a subscriber may take before it's dropped, so a
+/// client that stops reading can't hold up everyone else's events for the
+/// server's whole write timeout.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Locked for as long as the subscriber is being written to, which keeps
+/// concurrent writes from interleaving.
+type Subscriber = Arc<Mutex<Box<dyn Transport + Send>>>;
+
+/// Nothing panics while these locks are held, but a poisoned one mustn't stop
+/// every later event.
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// One server-sent event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    name: Option<String>,
+    data: String,
+}
+
+impl Event {
+    /// An unnamed event, which browsers dispatch as `message`.
+    pub fn new(data: impl Into<String>) -> Event {
+        Event {
+            name: None,
+            data: data.into(),
+        }
+    }
+
+    /// Sets the type browsers dispatch the event as.
+    pub fn named(mut self, name: &str) -> Event {
+        // A line break would end the field early
+        self.name = Some(name.replace(['\r', '\n'], ""));
+        self
+    }
+
+    /// The event in `text/event-stream` form, one `data:` line per line of data.
+    fn encode(&self, id: u64) -> Vec<u8> {
+        let mut out = format!("id: {id}\n");
+        if let Some(name) = &self.name {
+            let _ = writeln!(out, "event: {name}");
+        }
+        for line in self.data.split('\n') {
+            let _ = writeln!(out, "data: {}", line.strip_suffix('\r').unwrap_or(line));
+        }
+        out.push('\n');
+        out.into_bytes()
+    }
+}
+
+struct Shared {
+    subscribers: Vec<Subscriber>,
+    /// The latest events by id, oldest first
+    history: VecDeque<(u64, Vec<u8>)>,
+    keep: usize,
+    next_id: u64,
+}
+
+/// Sends events to every client subscribed to it with a `text/event-stream`
+/// response.
+///
+/// Subscribers' connections are held here rather than by a handler, and are
+/// written to by whoever sends an event, so each one costs a socket instead
+/// of a worker for as long as it stays. Events are numbered from 1 and the
+/// latest are kept, so a client reconnecting with `Last-Event-ID` gets the
+/// ones it missed. A subscriber whose connection fails or stays full for a
+/// second is dropped.
+///
+/// Subscribers are written to outside the channel's lock, so events sent at
+/// the same time from different threads may reach them in either order.
+#[derive(Clone)]
+pub struct Channel {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Channel {
+    /// Keeps the last `history` events for clients that reconnect.
+    pub fn new(history: usize) -> Channel {
+        Channel {
+            shared: Arc::new(Mutex::new(Shared {
+                subscribers: Vec::new(),
+                history: VecDeque::new(),
+                keep: history,
+                next_id: 1,
+            })),
+        }
+    }
+
+    /// Answers a request to subscribe. The client joins once the response
+    /// head is out, after being sent the kept events newer than its
+    /// `Last-Event-ID`.
+    pub fn subscribe(&self, request: &Request) -> Response {
+        let last_id = request
+            .header("Last-Event-ID")
+            .and_then(|id| id.trim().parse::<u64>().ok());
+        let shared = Arc::clone(&self.shared);
+        // The body runs until the connection closes, so it needs no framing
+        Response::new(200)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            .with_header("Connection", "close")
+            .with_upgrade(move |stream, _| {
+                if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_err() {
+                    return;
+                }
+                let subscriber: Subscriber = Arc::new(Mutex::new(stream));
+                // Held while replaying, so events sent meanwhile wait their turn
+                let mut stream = lock(&subscriber);
+                let missed: Vec<u8> = {
+                    let mut shared = lock(&shared);
+                    shared.subscribers.push(Arc::clone(&subscriber));
+                    match last_id {
+                        Some(last_id) => shared
+                            .history
+                            .iter()
+                            .filter(|(id, _)| *id > last_id)
+                            .flat_map(|(_, event)| event.iter().copied())
+                            .collect(),
+                        None => Vec::new(),
+                    }
+                };
+                let replayed = stream.write_all(&missed).and_then(|()| stream.flush());
+                drop(stream);
+                if replayed.is_err() {
+                    lock(&shared)
+                        .subscribers
+                        .retain(|other| !Arc::ptr_eq(other, &subscriber));
+                }
+            })
+    }
+
+    /// Sends `event` to every subscriber, returning the id it was given.
+    pub fn send(&self, event: &Event) -> u64 {
+        let (id, encoded, subscribers) = {
+            let mut shared = lock(&self.shared);
+            let id = shared.next_id;
+            shared.next_id += 1;
+            let encoded = event.encode(id);
+            if shared.keep > 0 {
+                if shared.history.len() == shared.keep {
+                    shared.history.pop_front();
+                }
+                shared.history.push_back((id, encoded.clone()));
+            }
+            (id, encoded, shared.subscribers.clone())
+        };
+        self.broadcast(&subscribers, &encoded);
+        id
+    }
+
+    /// Sends subscribers a comment, which keeps idle connections from being
+    /// timed out along the way and finds the clients that have gone.
+    pub fn heartbeat(&self) {
+        let subscribers = lock(&self.shared).subscribers.clone();
+        self.broadcast(&subscribers, b": heartbeat\n\n");
+    }
+
+    /// The number of clients subscribed.
+    pub fn subscribers(&self) -> usize {
+        lock(&self.shared).subscribers.len()
+    }
+
+    /// Writes `bytes` to each of `subscribers`, then drops those it couldn't
+    /// be written to.
+    fn broadcast(&self, subscribers: &[Subscriber], bytes: &[u8]) {
+        let gone: Vec<&Subscriber> = subscribers
+            .iter()
+            .filter(|subscriber| {
+                let mut stream = lock(subscriber);
+                stream
+                    .write_all(bytes)
+                    .and_then(|()| stream.flush())
+                    .is_err()
+            })
+            .collect();
+        if !gone.is_empty() {
+            lock(&self.shared)
+                .subscribers
+                .retain(|subscriber| !gone.iter().any(|gone| Arc::ptr_eq(gone, subscriber)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryStream;
+    use std::{
+        io::{self, Read},
+        net::SocketAddr,
+        sync::mpsc,
+        thread,
+    };
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request::new("GET", "/events", headers)
+    }
+
+    /// Subscribes a client to `channel` as the server would once the head is out.
+    fn subscribe(channel: &Channel, headers: &[(&str, &str)]) -> MemoryStream {
+        let response = channel.subscribe(&request(headers));
+        assert_eq!(Some("text/event-stream"), response.header("Content-Type"));
+        let (client, server) = MemoryStream::pair();
+        (response.upgrade.unwrap())(Box::new(server), Vec::new());
+        client
+    }
+
+    fn read(client: &mut MemoryStream, expected: &str) {
+        let mut received = vec![0; expected.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(expected, String::from_utf8(received).unwrap());
+    }
+
+    #[test]
+    fn encodes_events() {
+        assert_eq!(
+            b"id: 3\nevent: tick\ndata: one\ndata: two\n\n".to_vec(),
+            Event::new("one\r\ntwo").named("ti\nck").encode(3)
+        );
+        assert_eq!(b"id: 4\ndata: \n\n".to_vec(), Event::new("").encode(4));
+    }
+
+    #[test]
+    fn broadcasts_to_subscribers() {
+        let channel = Channel::new(8);
+        let mut first = subscribe(&channel, &[]);
+        let mut second = subscribe(&channel, &[]);
+        assert_eq!(2, channel.subscribers());
+
+        assert_eq!(1, channel.send(&Event::new("hello")));
+        read(&mut first, "id: 1\ndata: hello\n\n");
+        read(&mut second, "id: 1\ndata: hello\n\n");
+
+        channel.heartbeat();
+        read(&mut first, ": heartbeat\n\n");
+
+        // Gone clients are found the next time they're written to
+        drop(second);
+        channel.heartbeat();
+        assert_eq!(1, channel.subscribers());
+    }
+
+    /// A client that has stopped reading, whose writes hang until released.
+    struct Stalled {
+        release: mpsc::Receiver<()>,
+        write_timeout: Arc<Mutex<Option<Duration>>>,
+    }
+
+    impl Read for Stalled {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for Stalled {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            let _ = self.release.recv();
+            Err(io::ErrorKind::TimedOut.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Stalled {
+        fn peer_addr(&self) -> Option<SocketAddr> {
+            None
+        }
+
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            *self.write_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stalled_subscribers_hold_up_nobody_else() {
+        let channel = Channel::new(8);
+        let (release, stalled) = mpsc::channel();
+        let write_timeout = Arc::new(Mutex::new(None));
+        let response = channel.subscribe(&request(&[]));
+        let stream = Stalled {
+            release: stalled,
+            write_timeout: Arc::clone(&write_timeout),
+        };
+        (response.upgrade.unwrap())(Box::new(stream), Vec::new());
+        assert_eq!(Some(WRITE_TIMEOUT), *write_timeout.lock().unwrap());
+        let mut healthy = subscribe(&channel, &[]);
+
+        let sender = {
+            let channel = channel.clone();
+            thread::spawn(move || channel.send(&Event::new("hi")))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!sender.is_finished());
+
+        // The write that's hanging holds none of the channel's locks
+        let mut late = subscribe(&channel, &[]);
+        assert_eq!(3, channel.subscribers());
+
+        release.send(()).unwrap();
+        assert_eq!(1, sender.join().unwrap());
+        read(&mut healthy, "id: 1\ndata: hi\n\n");
+        assert_eq!(2, channel.subscribers());
+
+        channel.send(&Event::new("again"));
+        read(&mut late, "id: 2\ndata: again\n\n");
+    }
+
+    #[test]
+    fn replays_missed_events() {
+        let channel = Channel::new(2);
+        for data in ["a", "b", "c"] {
+            channel.send(&Event::new(data));
+        }
+
+        // Event 2 is the oldest kept
+        let mut resumed = subscribe(&channel, &[("Last-Event-ID", "1")]);
+        read(&mut resumed, "id: 2\ndata: b\n\nid: 3\ndata: c\n\n");
+        channel.send(&Event::new("d"));
+        read(&mut resumed, "id: 4\ndata: d\n\n");
+
+        // A new client only gets what's sent from now on
+        let mut fresh = subscribe(&channel, &[]);
+        channel.send(&Event::new("e"));
+        read(&mut fresh, "id: 5\ndata: e\n\n");
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/// A byte stream a connection can be served over.
+///
+/// The connection handler only needs to read the request, write the response
+/// and bound how long either may take, so anything duplex can stand in for a socket.
+pub trait Transport: Read + Write {
+    /// The remote address, `None` for transports without one (Unix sockets, memory streams).
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[derive(Default)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One direction of a [`MemoryStream`] pair.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+/// One end of an in-memory duplex stream, see [`MemoryStream::pair`].
+///
+/// Writes never block. Reads block until the other end writes or is dropped,
+/// and honour the read timeout the same way a socket does by failing with
+/// `ErrorKind::WouldBlock`.
+pub struct MemoryStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl MemoryStream {
+    /// Creates two connected ends, whatever is written to one is read from the other.
+    pub fn pair() -> (MemoryStream, MemoryStream) {
+        let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let end = |incoming, outgoing| MemoryStream {
+            incoming,
+            outgoing,
+            read_timeout: Mutex::new(None),
+        };
+        (end(Arc::clone(&a), Arc::clone(&b)), end(b, a))
+    }
+
+    /// Closes one or both directions like `TcpStream::shutdown`, shutting down
+    /// writes lets the other end read to EOF while this end keeps reading.
+    pub fn shutdown(&self, how: Shutdown) {
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.outgoing.close();
+        }
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.incoming.close();
+        }
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self
+            .read_timeout
+            .lock()
+            .unwrap()
+            .map(|timeout| Instant::now() + timeout);
+        let mut state = self.incoming.state.lock().unwrap();
+
+        while state.buf.is_empty() && !state.closed {
+            state = match deadline {
+                None => self.incoming.readable.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    self.incoming
+                        .readable
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+
+        let n = buf.len().min(state.buf.len());
+        for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.buf.extend(buf);
+        self.outgoing.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.shutdown(Shutdown::Both);
+    }
+}
+
+impl Transport for MemoryStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            // Sockets reject a zero timeout too
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        // Writes only append to a buffer, there is nothing to time out
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn memory_stream_round_trip() {
+        let (mut client, mut server) = MemoryStream::pair();
+        client.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Write);
+
+        let mut received = String::new();
+        server.read_to_string(&mut received).unwrap();
+        assert_eq!("ping", received);
+
+        server.write_all(b"pong").unwrap();
+        drop(server);
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert_eq!("pong", received);
+
+        assert_eq!(
+            io::ErrorKind::BrokenPipe,
+            client.write(b"late").unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn memory_stream_read_timeout() {
+        let (client, mut server) = MemoryStream::pair();
+        server
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+
+        let started = Instant::now();
+        let err = server.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        // A write from another thread wakes the reader up before the timeout
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let writer = thread::spawn(move || {
+            let mut client = client;
+            client.write_all(b"late").unwrap();
+            client
+        });
+        let mut buf = [0; 8];
+        let n = server.read(&mut buf).unwrap();
+        assert_eq!(b"late", &buf[..n]);
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,622 @@
+use crate::{
+    http::{self, Request, Response},
+    transport::Transport,
+};
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+/// Appended to the client's key before hashing it for `Sec-WebSocket-Accept`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message taken from a client unless set otherwise, fragments included.
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+/// How long [`WebSocket::close`] waits for the client to answer.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Close status codes.
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const TOO_BIG: u16 = 1009;
+
+/// Runs for each WebSocket connection accepted at its path, see
+/// [`ServerBuilder::websocket`](crate::builder::ServerBuilder::websocket).
+pub type Handler = Arc<dyn Fn(WebSocket) + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Whether the request asks to switch to the WebSocket protocol.
+pub fn is_upgrade(request: &Request) -> bool {
+    request
+        .header("Upgrade")
+        .is_some_and(|protocol| protocol.eq_ignore_ascii_case("websocket"))
+        && request.header("Connection").is_some_and(|options| {
+            options
+                .split(',')
+                .any(|option| option.trim().eq_ignore_ascii_case("upgrade"))
+        })
+}
+
+/// Answers a WebSocket handshake, running `handler` with the connection
+/// once the `101 Switching Protocols` response is out.
+///
+/// Requests that aren't a handshake, or are for a version other than 13,
+/// get `426 Upgrade Required`. A handshake without a proper key gets `400`.
+pub fn accept<F>(request: &Request, handler: F) -> Response
+where
+    F: FnOnce(WebSocket) + Send + 'static,
+{
+    if request.method != "GET" || !is_upgrade(request) {
+        return Response::new(426)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade");
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Response::new(426).with_header("Sec-WebSocket-Version", "13");
+    }
+    // Always 16 random bytes in base64
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if key.len() == 24 => key,
+        _ => return Response::new(400),
+    };
+    Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key))
+        .with_upgrade(move |stream, rest| handler(WebSocket::new(stream, rest)))
+}
+
+fn accept_key(key: &str) -> String {
+    http::base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// The server's end of a WebSocket connection.
+///
+/// [`recv`](WebSocket::recv) answers pings, puts fragmented messages back
+/// together and goes through the close handshake along the way. Frames are
+/// only taken from what has been read once they're whole, so a read timeout
+/// can be used to do other work, such as pushing updates, while waiting for
+/// the client without losing anything.
+pub struct WebSocket {
+    stream: Box<dyn Transport + Send>,
+    /// Read from the stream but not yet taken as a frame
+    buf: Vec<u8>,
+    /// The opcode and payload so far of a fragmented message
+    partial: Option<(u8, Vec<u8>)>,
+    max_message: usize,
+    /// A close frame has been sent, nothing else may follow it
+    closing: bool,
+}
+
+impl WebSocket {
+    /// Wraps a connection that has switched protocols, `rest` being whatever
+    /// the client sent after its handshake.
+    ///
+    /// The connection may sit idle for as long as the client likes, so the
+    /// read timeout left over from reading the handshake is cleared.
+    pub fn new(stream: Box<dyn Transport + Send>, rest: Vec<u8>) -> WebSocket {
+        let _ = stream.set_read_timeout(None);
+        WebSocket {
+            stream,
+            buf: rest,
+            partial: None,
+            max_message: MAX_MESSAGE,
+            closing: false,
+        }
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Bounds how long [`recv`](WebSocket::recv) waits for the client, `None`
+    /// waits forever.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the transport can't set it.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Sets the largest message the client may send, larger ones close the
+    /// connection with status 1009.
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message = max;
+    }
+
+    /// Waits for the next message from the client.
+    ///
+    /// Returns `Ok(None)` once the client has closed the connection, after
+    /// answering its close frame.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if reading fails, with `ErrorKind::WouldBlock` on a read
+    /// timeout after which `recv` can be called again. A client breaking the
+    /// protocol has the connection closed with the matching status code and
+    /// gets `ErrorKind::InvalidData`.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            let Frame {
+                fin,
+                opcode,
+                payload,
+            } = self.read_frame()?;
+            let (opcode, payload) = match opcode {
+                PING => {
+                    if !self.closing {
+                        self.write_frame(PONG, &payload)?;
+                    }
+                    continue;
+                }
+                PONG => continue,
+                CLOSE => return self.closed(&payload).map(|()| None),
+                CONTINUATION => match self.partial.take() {
+                    Some((opcode, mut message)) => {
+                        message.extend(payload);
+                        (opcode, message)
+                    }
+                    None => return Err(self.fail(PROTOCOL_ERROR, "unexpected continuation")),
+                },
+                TEXT | BINARY if self.partial.is_none() => (opcode, payload),
+                TEXT | BINARY => return Err(self.fail(PROTOCOL_ERROR, "expected a continuation")),
+                _ => return Err(self.fail(PROTOCOL_ERROR, "unknown opcode")),
+            };
+            if !fin {
+                self.partial = Some((opcode, payload));
+                continue;
+            }
+            if opcode == BINARY {
+                return Ok(Some(Message::Binary(payload)));
+            }
+            return match String::from_utf8(payload) {
+                Ok(text) => Ok(Some(Message::Text(text))),
+                Err(_) => Err(self.fail(INVALID_DATA, "text is not UTF-8")),
+            };
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Returns Err() if writing fails or the connection is closing.
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.send_text(text),
+            Message::Binary(data) => self.send_binary(data),
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Returns Err() if writing fails or the connection is closing.
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(TEXT, text.as_bytes())
+    }
+
+    /// # Errors
+    ///
+    /// Returns Err() if writing fails or the connection is closing.
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(BINARY, data)
+    }
+
+    /// Pings the client, whose pong [`recv`](WebSocket::recv) skips.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the payload is over 125 bytes, writing fails or the
+    /// connection is closing.
+    pub fn ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > 125 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ping payload over 125 bytes",
+            ));
+        }
+        self.write_frame(PING, payload)
+    }
+
+    /// Closes the connection with status `code`, waiting a few seconds for
+    /// the client to answer. Messages that arrive meanwhile are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns Err() if the reason is over 123 bytes or the close frame can't be written.
+    pub fn close(mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.closing {
+            return Ok(());
+        }
+        if reason.len() > 123 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "close reason over 123 bytes",
+            ));
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.write_frame(CLOSE, &payload)?;
+        self.closing = true;
+
+        // Whatever goes wrong from here, the connection is done with
+        let _ = self.stream.set_read_timeout(Some(CLOSE_TIMEOUT));
+        while let Ok(frame) = self.read_frame() {
+            if frame.opcode == CLOSE {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers the client's close frame with the same status code, if it gave one.
+    fn closed(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() == 1 {
+            return Err(self.fail(PROTOCOL_ERROR, "truncated close status"));
+        }
+        if payload.len() > 2 && std::str::from_utf8(&payload[2..]).is_err() {
+            return Err(self.fail(INVALID_DATA, "close reason is not UTF-8"));
+        }
+        if !self.closing {
+            self.write_frame(CLOSE, &payload[..payload.len().min(2)])?;
+            self.closing = true;
+        }
+        Ok(())
+    }
+
+    /// Closes the connection over a protocol error on a best effort basis,
+    /// returning the error for `recv` to fail with.
+    fn fail(&mut self, code: u16, reason: &str) -> io::Error {
+        if !self.closing {
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.as_bytes());
+            let _ = self.write_frame(CLOSE, &payload);
+            self.closing = true;
+        }
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    fn read_frame(&mut self) -> io::Result<Frame> {
+        self.fill(2)?;
+        let (first, second) = (self.buf[0], self.buf[1]);
+        let (mut header, mut len) = (2, u64::from(second & 0x7F));
+        if len == 126 {
+            self.fill(4)?;
+            len = u16::from_be_bytes([self.buf[2], self.buf[3]]).into();
+            header = 4;
+        } else if len == 127 {
+            self.fill(10)?;
+            len = u64::from_be_bytes(self.buf[2..10].try_into().unwrap());
+            header = 10;
+        }
+
+        let fin = first & 0x80 != 0;
+        let opcode = first & 0x0F;
+        if first & 0x70 != 0 {
+            return Err(self.fail(PROTOCOL_ERROR, "reserved bits set"));
+        }
+        // Clients must mask everything they send
+        if second & 0x80 == 0 {
+            return Err(self.fail(PROTOCOL_ERROR, "unmasked frame"));
+        }
+        if opcode >= CLOSE && (!fin || len > 125) {
+            return Err(self.fail(PROTOCOL_ERROR, "fragmented or oversized control frame"));
+        }
+        let so_far = self
+            .partial
+            .as_ref()
+            .map_or(0, |(_, message)| message.len());
+        if len > self.max_message.saturating_sub(so_far) as u64 {
+            return Err(self.fail(TOO_BIG, "message too big"));
+        }
+
+        let len = len as usize;
+        self.fill(header + 4 + len)?;
+        let mask: [u8; 4] = self.buf[header..header + 4].try_into().unwrap();
+        let mut payload: Vec<u8> = self
+            .buf
+            .drain(..header + 4 + len)
+            .skip(header + 4)
+            .collect();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    /// Reads until at least `len` bytes are buffered.
+    fn fill(&mut self, len: usize) -> io::Result<()> {
+        let mut chunk = [0; 4096];
+        while self.buf.len() < len {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "client hung up",
+                    ))
+                }
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends one unmasked, unfragmented frame.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closing {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "connection is closing",
+            ));
+        }
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+/// SHA-1, which the handshake needs and nothing else should use.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in w.into_iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let next = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryStream;
+    use std::io::Read;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request::new("GET", "/ws", headers)
+    }
+
+    /// A frame as a client would send it, masked.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+        let mut frame = vec![u8::from(fin) << 7 | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend((payload.len() as u16).to_be_bytes());
+        }
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Reads one unmasked frame from the server, returning its opcode and payload.
+    fn read_frame(client: &mut MemoryStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(0x80, head[0] & 0xF0, "unfragmented");
+        assert_eq!(0, head[1] & 0x80, "unmasked");
+        let mut payload = vec![0; usize::from(head[1] & 0x7F)];
+        client.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    fn connect() -> (MemoryStream, WebSocket) {
+        let (client, server) = MemoryStream::pair();
+        (client, WebSocket::new(Box::new(server), Vec::new()))
+    }
+
+    #[test]
+    fn computes_accept_key() {
+        assert_eq!(
+            "a9993e364706816aba3e25717850c26c9cd0d89d",
+            sha1(b"abc")
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        );
+        // The example from RFC 6455
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn answers_handshakes() {
+        let handshake = [
+            ("Upgrade", "websocket"),
+            ("Connection", "keep-alive, Upgrade"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("Sec-WebSocket-Version", "13"),
+        ];
+        let response = accept(&request(&handshake), |_| {});
+        assert_eq!(101, response.status);
+        assert_eq!(
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+            response.header("Sec-WebSocket-Accept")
+        );
+        assert!(response.upgrade.is_some());
+
+        assert_eq!(426, accept(&request(&[]), |_| {}).status);
+        let old = accept(
+            &request(&[handshake[0], handshake[1], handshake[2]]),
+            |_| {},
+        );
+        assert_eq!(
+            (426, Some("13")),
+            (old.status, old.header("Sec-WebSocket-Version"))
+        );
+        let keyless = accept(
+            &request(&[handshake[0], handshake[1], handshake[3]]),
+            |_| {},
+        );
+        assert_eq!(400, keyless.status);
+        assert!(keyless.upgrade.is_none());
+    }
+
+    #[test]
+    fn exchanges_messages() {
+        let (mut client, server) = MemoryStream::pair();
+        // Part of the first frame came in with the handshake
+        let first = frame(true, TEXT, b"hi");
+        let mut ws = WebSocket::new(Box::new(server), first[..3].to_vec());
+        client.write_all(&first[3..]).unwrap();
+        client.write_all(&frame(false, TEXT, b"Hel")).unwrap();
+        client
+            .write_all(&frame(true, PING, b"are you there"))
+            .unwrap();
+        client.write_all(&frame(true, CONTINUATION, b"lo")).unwrap();
+        client.write_all(&frame(true, BINARY, &[0; 300])).unwrap();
+
+        assert_eq!(Some(Message::Text("hi".to_string())), ws.recv().unwrap());
+        assert_eq!(Some(Message::Text("Hello".to_string())), ws.recv().unwrap());
+        assert_eq!((PONG, b"are you there".to_vec()), read_frame(&mut client));
+        assert_eq!(Some(Message::Binary(vec![0; 300])), ws.recv().unwrap());
+
+        ws.send_text("pushed").unwrap();
+        assert_eq!((TEXT, b"pushed".to_vec()), read_frame(&mut client));
+
+        // A timeout leaves a half read frame to be finished later
+        ws.set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let late = frame(true, BINARY, b"late");
+        client.write_all(&late[..4]).unwrap();
+        assert_eq!(io::ErrorKind::WouldBlock, ws.recv().unwrap_err().kind());
+        client.write_all(&late[4..]).unwrap();
+        assert_eq!(Some(Message::Binary(b"late".to_vec())), ws.recv().unwrap());
+
+        // The client closing is echoed back
+        let mut close = 1001u16.to_be_bytes().to_vec();
+        close.extend(b"bye");
+        client.write_all(&frame(true, CLOSE, &close)).unwrap();
+        assert_eq!(None, ws.recv().unwrap());
+        assert_eq!(
+            (CLOSE, 1001u16.to_be_bytes().to_vec()),
+            read_frame(&mut client)
+        );
+        assert!(ws.send_text("too late").is_err());
+    }
+
+    #[test]
+    fn closes_on_request() {
+        let (mut client, ws) = connect();
+        client
+            .write_all(&frame(true, CLOSE, &[0x03, 0xE8]))
+            .unwrap();
+        ws.close(1000, "done").unwrap();
+        let mut expected = 1000u16.to_be_bytes().to_vec();
+        expected.extend(b"done");
+        assert_eq!((CLOSE, expected), read_frame(&mut client));
+    }
+
+    #[test]
+    fn closes_on_protocol_errors() {
+        let status = |bytes: &[u8], max: usize| {
+            let (mut client, mut ws) = connect();
+            ws.set_max_message_size(max);
+            client.write_all(bytes).unwrap();
+            assert_eq!(io::ErrorKind::InvalidData, ws.recv().unwrap_err().kind());
+            let (opcode, payload) = read_frame(&mut client);
+            assert_eq!(CLOSE, opcode);
+            u16::from_be_bytes([payload[0], payload[1]])
+        };
+
+        let mut unmasked = frame(true, TEXT, b"");
+        unmasked[1] &= 0x7F;
+        assert_eq!(PROTOCOL_ERROR, status(&unmasked[..2], 64));
+        assert_eq!(PROTOCOL_ERROR, status(&frame(true, CONTINUATION, b"x"), 64));
+        assert_eq!(PROTOCOL_ERROR, status(&frame(false, PING, b""), 64));
+        assert_eq!(INVALID_DATA, status(&frame(true, TEXT, &[0xC3, 0x28]), 64));
+
+        let mut fragments = frame(false, BINARY, &[0; 40]);
+        fragments.extend(frame(true, CONTINUATION, &[0; 40]));
+        assert_eq!(TOO_BIG, status(&fragments, 64));
+    }
+}
@@ -0,0 +1,201 @@
+use multi_threaded_web_server::{
+    client::Client,
+    config::{AccessLogTarget, Config, IoMode, LogLevel},
+    server::{Server, ServerHandle},
+};
+use std::{
+    fs,
+    net::TcpStream,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn page(name: &str) -> Vec<u8> {
+    fs::read(root().join(name)).unwrap()
+}
+
+/// A quiet server on a free port, with four workers.
+fn start(io: IoMode) -> ServerHandle {
+    let config = Config {
+        port: 0,
+        workers: Some(4),
+        io,
+        root: root(),
+        access_log: AccessLogTarget::Off,
+        log_level: LogLevel::Error,
+        ..Config::default()
+    };
+    Server::builder(config).build().unwrap().spawn().unwrap()
+}
+
+fn serves_pages(io: IoMode) {
+    let server = start(io);
+    let client = Client::new(server.local_addr());
+
+    let response = client.get("/").unwrap();
+    assert_eq!(200, response.status);
+    assert_eq!(page("200.html"), response.body);
+
+    let response = client.get("/missing").unwrap();
+    assert_eq!(404, response.status);
+    assert_eq!(page("404.html"), response.body);
+
+    // Only GET is routed
+    let response = client.request("POST", "/", &[], b"hello").unwrap();
+    assert_eq!(404, response.status);
+}
+
+fn sleeps_concurrently(io: IoMode) {
+    let server = start(io);
+    let client = Client::new(server.local_addr());
+
+    // Four workers take four sleeps at once, rather than one after another
+    let started = Instant::now();
+    let sleepers: Vec<_> = (0..4)
+        .map(|_| {
+            let client = client.clone();
+            thread::spawn(move || client.get("/sleep").unwrap())
+        })
+        .collect();
+    for sleeper in sleepers {
+        let response = sleeper.join().unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(page("200.html"), response.body);
+    }
+    let took = started.elapsed();
+    assert!(took >= Duration::from_secs(5), "{took:?}");
+    assert!(took < Duration::from_secs(8), "{took:?}");
+}
+
+fn answers_while_sleeping(io: IoMode) {
+    let server = start(io);
+    let client = Client::new(server.local_addr());
+
+    let sleeper = {
+        let client = client.clone();
+        thread::spawn(move || client.get("/sleep").unwrap())
+    };
+    thread::sleep(Duration::from_millis(200));
+    let started = Instant::now();
+    assert_eq!(200, client.get("/").unwrap().status);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(!sleeper.is_finished());
+    assert_eq!(200, sleeper.join().unwrap().status);
+}
+
+fn shuts_down(io: IoMode) {
+    let server = start(io);
+    let addr = server.local_addr();
+    assert_eq!(200, Client::new(addr).get("/").unwrap().status);
+
+    server.shutdown();
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn serves_pages_blocking() {
+    serves_pages(IoMode::Blocking);
+}
+
+#[test]
+fn sleeps_concurrently_blocking() {
+    sleeps_concurrently(IoMode::Blocking);
+}
+
+#[test]
+fn answers_while_sleeping_blocking() {
+    answers_while_sleeping(IoMode::Blocking);
+}
+
+#[test]
+fn shuts_down_blocking() {
+    shuts_down(IoMode::Blocking);
+}
+
+#[cfg(target_os = "linux")]
+mod epoll {
+    use super::*;
+
+    #[test]
+    fn serves_pages() {
+        super::serves_pages(IoMode::Epoll);
+    }
+
+    #[test]
+    fn sleeps_concurrently() {
+        super::sleeps_concurrently(IoMode::Epoll);
+    }
+
+    #[test]
+    fn answers_while_sleeping() {
+        super::answers_while_sleeping(IoMode::Epoll);
+    }
+
+    #[test]
+    fn shuts_down() {
+        super::shuts_down(IoMode::Epoll);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn only_replaces_stale_sockets() {
+    use multi_threaded_web_server::builder::BuildError;
+    use std::os::unix::net::UnixListener;
+
+    let path = std::env::temp_dir().join(format!("server_test_{}.sock", std::process::id()));
+    let config = Config {
+        port: 0,
+        unix: Some(path.clone()),
+        access_log: AccessLogTarget::Off,
+        log_level: LogLevel::Error,
+        ..Config::default()
+    };
+
+    // Say a typo pointed it at a file that isn't a socket
+    fs::write(&path, "keep me").unwrap();
+    let result = Server::builder(config.clone()).build();
+    assert!(matches!(result, Err(BuildError::Bind { .. })));
+    assert_eq!("keep me", fs::read_to_string(&path).unwrap());
+    fs::remove_file(&path).unwrap();
+
+    // One left behind by an earlier run is bound over
+    drop(UnixListener::bind(&path).unwrap());
+    let server = Server::builder(config).build().unwrap().spawn().unwrap();
+    server.shutdown();
+    assert!(!path.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn leaves_sockets_in_use_alone() {
+    use multi_threaded_web_server::builder::BuildError;
+    use std::os::unix::net::UnixStream;
+
+    let path = std::env::temp_dir().join(format!("server_test_{}_busy.sock", std::process::id()));
+    let config = Config {
+        port: 0,
+        unix: Some(path.clone()),
+        access_log: AccessLogTarget::Off,
+        log_level: LogLevel::Error,
+        ..Config::default()
+    };
+    let first = Server::builder(config.clone())
+        .build()
+        .unwrap()
+        .spawn()
+        .unwrap();
+
+    // A second instance mustn't take the path over from the first
+    let second = Server::builder(config).build();
+    assert!(matches!(second, Err(BuildError::Bind { .. })));
+    assert!(UnixStream::connect(&path).is_ok());
+
+    first.shutdown();
+    assert!(!path.exists());
+}